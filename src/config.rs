@@ -0,0 +1,801 @@
+use std::env;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::lockfile::LockFile;
+use crate::wildmatch::wildmatch;
+
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    System,
+    Global,
+    Local,
+    File,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::System => "system",
+            Scope::Global => "global",
+            Scope::Local => "local",
+            Scope::File => "file",
+        }
+    }
+}
+
+/// A single variable together with the section it belongs to.
+#[derive(Debug, Clone)]
+pub struct ConfigEntry {
+    /// Lower-cased section name.
+    pub section: String,
+    /// Subsection names are case sensitive.
+    pub subsection: Option<String>,
+    /// Lower-cased variable name.
+    pub name: String,
+    /// `None` for a variable without `=`, which means boolean true.
+    pub value: Option<String>,
+    pub scope: Scope,
+    pub file: PathBuf,
+}
+
+impl ConfigEntry {
+    pub fn key(&self) -> String {
+        match &self.subsection {
+            Some(subsection) => format!("{}.{subsection}.{}", self.section, self.name),
+            None => format!("{}.{}", self.section, self.name),
+        }
+    }
+
+    fn matches(&self, key: &Key) -> bool {
+        self.section == key.section
+            && self.subsection.as_deref() == key.subsection.as_deref()
+            && self.name == key.name
+    }
+}
+
+/// A parsed `section[.subsection].name` key.
+struct Key {
+    section: String,
+    subsection: Option<String>,
+    name: String,
+}
+
+impl Key {
+    fn parse(key: &str) -> anyhow::Result<Self> {
+        let (section, rest) = key
+            .split_once('.')
+            .ok_or(anyhow::anyhow!("key does not contain a section: {key}"))?;
+        let (subsection, name) = match rest.rsplit_once('.') {
+            Some((subsection, name)) => (Some(subsection.to_owned()), name),
+            None => (None, rest),
+        };
+
+        let valid =
+            |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !valid(section) || !valid(name) || !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            anyhow::bail!("invalid key: {key}");
+        }
+
+        Ok(Self {
+            section: section.to_lowercase(),
+            subsection,
+            name: name.to_lowercase(),
+        })
+    }
+}
+
+/// Configuration merged from the system, global and repository config files,
+/// later entries override earlier ones.
+#[derive(Default)]
+pub struct Config {
+    entries: Vec<ConfigEntry>,
+}
+
+impl Config {
+    /// Loads all the scopes, `git_dir` is needed for the repository config
+    /// and for `includeIf` conditions.
+    pub fn load(git_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = Config::default();
+        if let Some(path) = system_config_path() {
+            config.read_file(&path, Scope::System, git_dir, 0)?;
+        }
+        for path in global_config_paths() {
+            config.read_file(&path, Scope::Global, git_dir, 0)?;
+        }
+        if let Some(git_dir) = git_dir {
+            config.read_file(&git_dir.join("config"), Scope::Local, Some(git_dir), 0)?;
+        }
+
+        Ok(config)
+    }
+
+    /// Loads only the given files (and the files they include).
+    pub fn load_files(
+        paths: &[PathBuf],
+        scope: Scope,
+        git_dir: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let mut config = Config::default();
+        for path in paths {
+            config.read_file(path, scope, git_dir, 0)?;
+        }
+        Ok(config)
+    }
+
+    fn read_file(
+        &mut self,
+        path: &Path,
+        scope: Scope,
+        git_dir: Option<&Path>,
+        depth: usize,
+    ) -> anyhow::Result<()> {
+        if !path.is_file() {
+            return Ok(());
+        }
+        if depth > MAX_INCLUDE_DEPTH {
+            anyhow::bail!("exceeded maximum include depth at {}", path.display());
+        }
+
+        let content = fs::read_to_string(path)?;
+        let parsed = parse(&content)
+            .map_err(|err| anyhow::anyhow!("bad config file {}: {err}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        for item in parsed.entries {
+            let entry = ConfigEntry {
+                section: item.section,
+                subsection: item.subsection,
+                name: item.name,
+                value: item.value,
+                scope,
+                file: path.to_owned(),
+            };
+
+            let include = match (entry.section.as_str(), &entry.subsection) {
+                ("include", None) => entry.name == "path",
+                ("includeif", Some(condition)) => {
+                    entry.name == "path" && include_condition(condition, base, git_dir)?
+                }
+                _ => false,
+            };
+            let included = entry.value.clone().filter(|_| include);
+            self.entries.push(entry);
+
+            // included entries take effect as if they were written in place of the include
+            if let Some(included) = included {
+                let included = expand_path(&included, base)?;
+                self.read_file(&included, scope, git_dir, depth + 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[ConfigEntry] {
+        &self.entries
+    }
+
+    /// Returns the last value of a `section[.subsection].name` key,
+    /// a variable without a value is reported as `true`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_all(key)
+            .pop()
+            .map(|e| e.value.as_deref().unwrap_or("true"))
+    }
+
+    /// Returns the last value of a key as a boolean, see `parse_bool`.
+    pub fn get_bool(&self, key: &str) -> anyhow::Result<Option<bool>> {
+        self.get(key)
+            .map(|value| {
+                parse_bool(value).ok_or(anyhow::anyhow!(
+                    "bad boolean config value '{value}' for '{key}'"
+                ))
+            })
+            .transpose()
+    }
+
+    /// All the entries of a key, in the order they were read.
+    pub fn get_all(&self, key: &str) -> Vec<&ConfigEntry> {
+        let Ok(key) = Key::parse(key) else {
+            return Vec::new();
+        };
+        self.entries.iter().filter(|e| e.matches(&key)).collect()
+    }
+}
+
+/// Parses a boolean like git, ignoring case: `true`, `yes`, `on` and non-zero
+/// numbers are true, `false`, `no`, `off`, `0` and an empty value are false.
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" => Some(true),
+        "false" | "no" | "off" | "" => Some(false),
+        number => number.parse::<i64>().ok().map(|n| n != 0),
+    }
+}
+
+pub fn system_config_path() -> Option<PathBuf> {
+    if env::var_os("GIT_CONFIG_NOSYSTEM").is_some() {
+        return None;
+    }
+    Some(
+        env::var_os("GIT_CONFIG_SYSTEM")
+            .map(PathBuf::from)
+            .unwrap_or(PathBuf::from("/etc/gitconfig")),
+    )
+}
+
+/// Global config files in the order they are read, the last one is the one written to.
+pub fn global_config_paths() -> Vec<PathBuf> {
+    if let Some(path) = env::var_os("GIT_CONFIG_GLOBAL") {
+        return vec![PathBuf::from(path)];
+    }
+
+    let mut paths = Vec::new();
+    let home = env::var_os("HOME").map(PathBuf::from);
+    match (env::var_os("XDG_CONFIG_HOME"), &home) {
+        (Some(xdg), _) => paths.push(Path::new(&xdg).join("git/config")),
+        (None, Some(home)) => paths.push(home.join(".config/git/config")),
+        _ => {}
+    }
+    if let Some(home) = home {
+        paths.push(home.join(".gitconfig"));
+    }
+    paths
+}
+
+/// Expands `~/` and makes relative paths relative to the including file.
+pub fn expand_path(path: &str, base: &Path) -> anyhow::Result<PathBuf> {
+    if let Some(rest) = path.strip_prefix("~/") {
+        let home = env::var_os("HOME").ok_or(anyhow::anyhow!("HOME is not set"))?;
+        Ok(Path::new(&home).join(rest))
+    } else {
+        Ok(base.join(path))
+    }
+}
+
+/// Evaluates the condition of an `includeIf` section: `gitdir:`, `gitdir/i:` and `onbranch:`.
+fn include_condition(condition: &str, base: &Path, git_dir: Option<&Path>) -> anyhow::Result<bool> {
+    let Some(git_dir) = git_dir else {
+        return Ok(false);
+    };
+
+    let (pattern, ignore_case) = if let Some(pattern) = condition.strip_prefix("gitdir:") {
+        (pattern, false)
+    } else if let Some(pattern) = condition.strip_prefix("gitdir/i:") {
+        (pattern, true)
+    } else if let Some(pattern) = condition.strip_prefix("onbranch:") {
+        let head = fs::read_to_string(git_dir.join("HEAD")).unwrap_or_default();
+        let Some(branch) = head.trim_end().strip_prefix("ref: refs/heads/") else {
+            return Ok(false);
+        };
+        let mut pattern = pattern.to_owned();
+        if pattern.ends_with('/') {
+            pattern.push_str("**");
+        }
+        return Ok(wildmatch(pattern.as_bytes(), branch.as_bytes(), false));
+    } else {
+        return Ok(false);
+    };
+
+    let mut pattern = if pattern.starts_with("~/") {
+        expand_path(pattern, base)?.to_string_lossy().into_owned()
+    } else if let Some(relative) = pattern.strip_prefix("./") {
+        base.join(relative).to_string_lossy().into_owned()
+    } else if pattern.starts_with('/') {
+        pattern.to_owned()
+    } else {
+        format!("**/{pattern}")
+    };
+    if pattern.ends_with('/') {
+        pattern.push_str("**");
+    }
+
+    let git_dir = git_dir.to_string_lossy();
+    Ok(wildmatch(
+        pattern.as_bytes(),
+        git_dir.as_bytes(),
+        ignore_case,
+    ))
+}
+
+struct ParsedEntry {
+    section: String,
+    subsection: Option<String>,
+    name: String,
+    value: Option<String>,
+    lines: Range<usize>,
+    /// The section header when the variable follows it on the same line.
+    header: Option<String>,
+}
+
+struct ParsedSection {
+    section: String,
+    subsection: Option<String>,
+    header_line: usize,
+    /// Last line of the section that belongs to it (the header or the last variable).
+    last_line: usize,
+}
+
+struct ParsedFile {
+    entries: Vec<ParsedEntry>,
+    sections: Vec<ParsedSection>,
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl Cursor<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == b'\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.next() {
+            if c == b'\n' {
+                break;
+            }
+        }
+    }
+
+    fn skip_blanks(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r')) {
+            self.next();
+        }
+    }
+}
+
+/// Parses the git config dialect of INI files.
+fn parse(content: &str) -> anyhow::Result<ParsedFile> {
+    let mut cursor = Cursor {
+        data: content.as_bytes(),
+        pos: 0,
+        line: 0,
+    };
+    let mut result = ParsedFile {
+        entries: Vec::new(),
+        sections: Vec::new(),
+    };
+
+    loop {
+        match cursor.peek() {
+            None => break,
+            Some(b' ' | b'\t' | b'\r' | b'\n') => {
+                cursor.next();
+            }
+            Some(b'#' | b';') => cursor.skip_line(),
+            Some(b'[') => {
+                let line = cursor.line;
+                let (section, subsection) = parse_section_header(&mut cursor)?;
+                result.sections.push(ParsedSection {
+                    section,
+                    subsection,
+                    header_line: line,
+                    last_line: line,
+                });
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let section = result.sections.last_mut().ok_or(anyhow::anyhow!(
+                    "variable outside of a section at line {}",
+                    cursor.line + 1
+                ))?;
+                let start = cursor.line;
+                let header = (section.header_line == start).then(|| {
+                    let line_start = content[..cursor.pos].rfind('\n').map_or(0, |i| i + 1);
+                    content[line_start..cursor.pos].trim_end().to_owned()
+                });
+                let (name, value) = parse_variable(&mut cursor)?;
+                // the variable ends with the consumed newline, or with the end of the file
+                let end = if cursor.data.get(cursor.pos.wrapping_sub(1)) == Some(&b'\n') {
+                    cursor.line
+                } else {
+                    cursor.line + 1
+                };
+                section.last_line = end - 1;
+                result.entries.push(ParsedEntry {
+                    section: section.section.clone(),
+                    subsection: section.subsection.clone(),
+                    name,
+                    value,
+                    lines: start..end,
+                    header,
+                });
+            }
+            Some(_) => anyhow::bail!("bad config line {}", cursor.line + 1),
+        }
+    }
+
+    Ok(result)
+}
+
+fn parse_section_header(cursor: &mut Cursor) -> anyhow::Result<(String, Option<String>)> {
+    let line = cursor.line + 1;
+    cursor.next();
+
+    let mut name = String::new();
+    while let Some(c) = cursor.peek() {
+        if c.is_ascii_alphanumeric() || c == b'-' || c == b'.' {
+            name.push(c.to_ascii_lowercase() as char);
+            cursor.next();
+        } else {
+            break;
+        }
+    }
+
+    match cursor.next() {
+        Some(b']') => {
+            // deprecated [section.subsection] syntax
+            Ok(match name.split_once('.') {
+                Some((section, subsection)) => (section.to_owned(), Some(subsection.to_owned())),
+                None => (name, None),
+            })
+        }
+        Some(b' ' | b'\t') => {
+            cursor.skip_blanks();
+            if cursor.next() != Some(b'"') {
+                anyhow::bail!("bad section header at line {line}");
+            }
+            let mut subsection = Vec::new();
+            loop {
+                match cursor.next() {
+                    Some(b'"') => break,
+                    Some(b'\\') => subsection.push(
+                        cursor
+                            .next()
+                            .ok_or(anyhow::anyhow!("bad section header at line {line}"))?,
+                    ),
+                    Some(b'\n') | None => anyhow::bail!("bad section header at line {line}"),
+                    Some(c) => subsection.push(c),
+                }
+            }
+            if cursor.next() != Some(b']') {
+                anyhow::bail!("bad section header at line {line}");
+            }
+            Ok((name, Some(String::from_utf8(subsection)?)))
+        }
+        _ => anyhow::bail!("bad section header at line {line}"),
+    }
+}
+
+fn parse_variable(cursor: &mut Cursor) -> anyhow::Result<(String, Option<String>)> {
+    let line = cursor.line + 1;
+    let mut name = String::new();
+    while let Some(c) = cursor.peek() {
+        if c.is_ascii_alphanumeric() || c == b'-' {
+            name.push(c.to_ascii_lowercase() as char);
+            cursor.next();
+        } else {
+            break;
+        }
+    }
+
+    cursor.skip_blanks();
+    match cursor.peek() {
+        Some(b'=') => {
+            cursor.next();
+            Ok((name, Some(parse_value(cursor)?)))
+        }
+        None | Some(b'\n') => {
+            cursor.next();
+            Ok((name, None))
+        }
+        Some(b'#' | b';') => {
+            cursor.skip_line();
+            Ok((name, None))
+        }
+        Some(_) => anyhow::bail!("bad config line {line}"),
+    }
+}
+
+fn parse_value(cursor: &mut Cursor) -> anyhow::Result<String> {
+    let line = cursor.line + 1;
+    let mut value = Vec::new();
+    // whitespace is kept only if it is followed by something else than the end of line
+    let mut pending = Vec::new();
+    let mut quoted = false;
+
+    cursor.skip_blanks();
+    while let Some(c) = cursor.next() {
+        match c {
+            b'\n' => {
+                if quoted {
+                    anyhow::bail!("unterminated quoted value at line {line}");
+                }
+                break;
+            }
+            b'\\' => {
+                let escaped = match cursor.next() {
+                    Some(b'\n') => continue,
+                    Some(b'n') => b'\n',
+                    Some(b't') => b'\t',
+                    Some(b'b') => 0x08,
+                    Some(b'"') => b'"',
+                    Some(b'\\') => b'\\',
+                    _ => anyhow::bail!("invalid escape sequence at line {line}"),
+                };
+                value.append(&mut pending);
+                value.push(escaped);
+            }
+            b'"' => {
+                value.append(&mut pending);
+                quoted = !quoted;
+            }
+            b'#' | b';' if !quoted => {
+                cursor.skip_line();
+                break;
+            }
+            b' ' | b'\t' | b'\r' if !quoted => pending.push(c),
+            _ => {
+                value.append(&mut pending);
+                value.push(c);
+            }
+        }
+    }
+    if quoted && cursor.peek().is_none() {
+        anyhow::bail!("unterminated quoted value at line {line}");
+    }
+
+    Ok(String::from_utf8(value)?)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    /// Set a single value, fails if the key has multiple values.
+    Set,
+    /// Add a new value to a multi-valued key.
+    Add,
+    /// Replace all values with a single one.
+    ReplaceAll,
+    /// Remove a single value, fails if the key has multiple values.
+    Unset,
+    UnsetAll,
+}
+
+/// Modifies a config file while keeping the rest of its formatting intact.
+pub fn edit_file(path: &Path, key: &str, value: Option<&str>, edit: Edit) -> anyhow::Result<()> {
+    let parsed_key = Key::parse(key)?;
+    let content = if path.exists() {
+        fs::read_to_string(path)?
+    } else {
+        String::new()
+    };
+    let parsed = parse(&content)
+        .map_err(|err| anyhow::anyhow!("bad config file {}: {err}", path.display()))?;
+
+    let mut lines: Vec<String> = content.split_inclusive('\n').map(str::to_owned).collect();
+    if lines.last().is_some_and(|l| !l.ends_with('\n')) {
+        lines.last_mut().unwrap().push('\n');
+    }
+
+    let matching = parsed
+        .entries
+        .iter()
+        .filter(|e| {
+            e.section == parsed_key.section
+                && e.subsection == parsed_key.subsection
+                && e.name == parsed_key.name
+        })
+        .collect::<Vec<_>>();
+
+    if matching.len() > 1 && matches!(edit, Edit::Set | Edit::Unset) {
+        anyhow::bail!("{key} has multiple values");
+    }
+    if matching.is_empty() && matches!(edit, Edit::Unset | Edit::UnsetAll) {
+        anyhow::bail!("no such key: {key}");
+    }
+
+    let new_line = value.map(|value| format!("\t{} = {}\n", parsed_key.name, quote(value)));
+    // a header sharing its line with a replaced variable is kept on its own line
+    let replace = |entry: &ParsedEntry, line: Option<String>| {
+        let text = match (&entry.header, line) {
+            (Some(header), line) => Some(format!("{header}\n{}", line.unwrap_or_default())),
+            (None, line) => line,
+        };
+        (entry.lines.clone(), text)
+    };
+    // lines are replaced from the end so that the earlier line numbers stay valid
+    let mut replacements: Vec<(Range<usize>, Option<String>)> = Vec::new();
+    match edit {
+        Edit::Set | Edit::ReplaceAll if !matching.is_empty() => {
+            for (i, entry) in matching.iter().enumerate() {
+                let line = if i == 0 { new_line.clone() } else { None };
+                replacements.push(replace(entry, line));
+            }
+        }
+        Edit::Unset | Edit::UnsetAll => {
+            for entry in &matching {
+                replacements.push(replace(entry, None));
+            }
+
+            // sections left with nothing but blank lines are removed too
+            for (i, section) in parsed.sections.iter().enumerate() {
+                let end = parsed
+                    .sections
+                    .get(i + 1)
+                    .map_or(lines.len(), |next| next.header_line);
+                let body = section.header_line + 1..end;
+                let removed = |line: usize| {
+                    matching
+                        .iter()
+                        .any(|e| e.header.is_none() && e.lines.contains(&line))
+                };
+                let emptied = body.clone().any(removed)
+                    && body
+                        .clone()
+                        .all(|l| removed(l) || lines[l].trim().is_empty())
+                    && !parsed
+                        .entries
+                        .iter()
+                        .any(|e| e.lines.start == section.header_line);
+                if emptied {
+                    replacements.push((section.header_line..section.header_line + 1, None));
+                }
+            }
+        }
+        _ => {
+            let section =
+                parsed.sections.iter().rev().find(|s| {
+                    s.section == parsed_key.section && s.subsection == parsed_key.subsection
+                });
+            let line = new_line.ok_or(anyhow::anyhow!("missing value for {key}"))?;
+            match section {
+                Some(section) => {
+                    let at = section.last_line + 1;
+                    replacements.push((at..at, Some(line)));
+                }
+                None => {
+                    let header = match &parsed_key.subsection {
+                        Some(subsection) => format!(
+                            "[{} \"{}\"]\n",
+                            parsed_key.section,
+                            subsection.replace('\\', "\\\\").replace('"', "\\\"")
+                        ),
+                        None => format!("[{}]\n", parsed_key.section),
+                    };
+                    let at = lines.len();
+                    replacements.push((at..at, Some(header + &line)));
+                }
+            }
+        }
+    }
+
+    replacements.sort_by_key(|(range, _)| range.start);
+    for (range, line) in replacements.into_iter().rev() {
+        lines.splice(range, line);
+    }
+
+    let mut lock = LockFile::acquire(path)?;
+    lock.write_all(lines.concat().as_bytes())?;
+    lock.commit()
+}
+
+/// Quotes and escapes a value so that it is read back unchanged.
+fn quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    let needs_quotes = value.starts_with([' ', '\t'])
+        || value.ends_with([' ', '\t'])
+        || value.contains(['#', ';']);
+    if needs_quotes {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(content: &str) -> Vec<(String, Option<String>)> {
+        parse(content)
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|e| {
+                let key = match e.subsection {
+                    Some(subsection) => format!("{}.{subsection}.{}", e.section, e.name),
+                    None => format!("{}.{}", e.section, e.name),
+                };
+                (key, e.value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sections_and_values() {
+        let content = "# comment\n\
+            [Core]\n\
+            \tBare = false ; trailing comment\n\
+            \tflag\n\
+            [remote \"Origin\"] url = a b  \n\
+            [branch.main]\n\
+            \tmessage = \" padded \" \\\"q\\\" a\\tb\\n\n\
+            \tlong = one \\\n\
+            two\n";
+        let entries = values(content);
+        let expected = [
+            ("core.bare", Some("false")),
+            ("core.flag", None),
+            ("remote.Origin.url", Some("a b")),
+            ("branch.main.message", Some(" padded  \"q\" a\tb\n")),
+            ("branch.main.long", Some("one two")),
+        ];
+        assert_eq!(entries.len(), expected.len());
+        for ((key, value), (expected_key, expected_value)) in entries.iter().zip(expected) {
+            assert_eq!(key, expected_key);
+            assert_eq!(value.as_deref(), expected_value);
+        }
+    }
+
+    #[test]
+    fn invalid_files() {
+        for content in [
+            "name = value\n",
+            "[core\n",
+            "[remote \"x]\n",
+            "[core]\nbad name = 1\n",
+            "[core]\nx = \"open\n",
+            "[core]\nx = a\\q\n",
+        ] {
+            assert!(parse(content).is_err(), "{content:?}");
+        }
+    }
+
+    #[test]
+    fn booleans() {
+        for value in ["true", "YES", "On", "1", "-2"] {
+            assert_eq!(parse_bool(value), Some(true), "{value}");
+        }
+        for value in ["false", "No", "OFF", "0", ""] {
+            assert_eq!(parse_bool(value), Some(false), "{value}");
+        }
+        assert_eq!(parse_bool("maybe"), None);
+    }
+
+    #[test]
+    fn keys() {
+        let key = Key::parse("Remote.Origin.URL").unwrap();
+        assert_eq!(key.section, "remote");
+        assert_eq!(key.subsection.as_deref(), Some("Origin"));
+        assert_eq!(key.name, "url");
+        assert_eq!(
+            Key::parse("a.b.c.d").unwrap().subsection.as_deref(),
+            Some("b.c")
+        );
+        for key in ["core", "core.", ".name", "core.1name", "co_re.name"] {
+            assert!(Key::parse(key).is_err(), "{key}");
+        }
+    }
+
+    #[test]
+    fn quoting_round_trip() {
+        for value in [
+            "plain",
+            " lead",
+            "a # b",
+            "tab\tand \"quote\"\\",
+            "line\nbreak",
+        ] {
+            let content = format!("[a]\nb = {}\n", quote(value));
+            assert_eq!(values(&content)[0].1.as_deref(), Some(value));
+        }
+    }
+}
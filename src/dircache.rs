@@ -0,0 +1,784 @@
+use sha1::{Digest, Sha1};
+use std::fs::{self, Metadata};
+use std::io::{BufRead, Read};
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::lockfile::LockFile;
+use crate::object::ShaHash;
+use crate::pack::parse_delta_offset;
+
+const SIGNATURE: &[u8; 4] = b"DIRC";
+const CACHE_TREE_SIGNATURE: &[u8; 4] = b"TREE";
+const RESOLVE_UNDO_SIGNATURE: &[u8; 4] = b"REUC";
+
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+
+/// A single staged file in `.git/index`.
+#[derive(Debug, Clone, Default)]
+pub struct IndexEntry {
+    pub ctime_sec: u32,
+    pub ctime_nsec: u32,
+    pub mtime_sec: u32,
+    pub mtime_nsec: u32,
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub hash: ShaHash,
+    pub flags: u16,
+    /// Flags only present in version 3 and later (skip-worktree, intent-to-add).
+    pub extended_flags: u16,
+    pub path: Vec<u8>,
+}
+
+impl IndexEntry {
+    /// Creates an entry with stat data taken from `metadata` (as returned by `lstat`).
+    pub fn from_metadata(path: Vec<u8>, mode: u32, hash: ShaHash, metadata: &Metadata) -> Self {
+        Self {
+            ctime_sec: metadata.ctime() as u32,
+            ctime_nsec: metadata.ctime_nsec() as u32,
+            mtime_sec: metadata.mtime() as u32,
+            mtime_nsec: metadata.mtime_nsec() as u32,
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            mode,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+            hash,
+            flags: path.len().min(FLAG_NAME_MASK as usize) as u16,
+            extended_flags: 0,
+            path,
+        }
+    }
+
+    /// Returns true if the stat data recorded in the entry match the file,
+    /// meaning the content does not have to be hashed again.
+    pub fn is_stat_clean(&self, metadata: &Metadata) -> bool {
+        self.mtime_sec == metadata.mtime() as u32
+            && self.mtime_nsec == metadata.mtime_nsec() as u32
+            && self.ctime_sec == metadata.ctime() as u32
+            && self.ctime_nsec == metadata.ctime_nsec() as u32
+            && self.ino == metadata.ino() as u32
+            && self.uid == metadata.uid()
+            && self.gid == metadata.gid()
+            && self.size == metadata.size() as u32
+    }
+
+    /// Merge stage: 0 for regular entries, 1-3 for conflicts.
+    pub fn stage(&self) -> u16 {
+        (self.flags & FLAG_STAGE_MASK) >> 12
+    }
+
+    fn read(input: &mut &[u8], version: u32, previous: &[u8]) -> anyhow::Result<Self> {
+        let mut values = [0u32; 10];
+        for value in values.iter_mut() {
+            *value = read_u32(input)?;
+        }
+        let mut hash = ShaHash::default();
+        input.read_exact(&mut hash)?;
+        let flags = read_u16(input)?;
+        let extended_flags = if flags & FLAG_EXTENDED != 0 {
+            if version < 3 {
+                anyhow::bail!("extended index entry flags in index version {version}");
+            }
+            read_u16(input)?
+        } else {
+            0
+        };
+        let fixed_size = if flags & FLAG_EXTENDED != 0 { 64 } else { 62 };
+
+        let path = if version == 4 {
+            let strip = parse_delta_offset(input)? as usize;
+            let keep = previous
+                .len()
+                .checked_sub(strip)
+                .ok_or(anyhow::anyhow!("invalid path prefix compression"))?;
+            let mut path = previous[..keep].to_vec();
+            input.read_until(b'\0', &mut path)?;
+            if path.pop() != Some(b'\0') {
+                anyhow::bail!("unterminated index entry path");
+            }
+            path
+        } else {
+            let mut path = Vec::new();
+            input.read_until(b'\0', &mut path)?;
+            if path.pop() != Some(b'\0') {
+                anyhow::bail!("unterminated index entry path");
+            }
+            // skip the rest of the NUL padding
+            let padding = 8 - (fixed_size + path.len()) % 8 - 1;
+            *input = input
+                .get(padding..)
+                .ok_or(anyhow::anyhow!("truncated index entry padding"))?;
+            path
+        };
+
+        let [ctime_sec, ctime_nsec, mtime_sec, mtime_nsec, dev, ino, mode, uid, gid, size] = values;
+        Ok(Self {
+            ctime_sec,
+            ctime_nsec,
+            mtime_sec,
+            mtime_nsec,
+            dev,
+            ino,
+            mode,
+            uid,
+            gid,
+            size,
+            hash,
+            flags,
+            extended_flags,
+            path,
+        })
+    }
+
+    fn serialize(&self, content: &mut Vec<u8>, version: u32, previous: &[u8]) {
+        let start = content.len();
+        for value in [
+            self.ctime_sec,
+            self.ctime_nsec,
+            self.mtime_sec,
+            self.mtime_nsec,
+            self.dev,
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.size,
+        ] {
+            content.extend(value.to_be_bytes());
+        }
+        content.extend(self.hash);
+
+        let mut flags = (self.flags & !(FLAG_EXTENDED | FLAG_NAME_MASK))
+            | self.path.len().min(FLAG_NAME_MASK as usize) as u16;
+        if self.extended_flags != 0 {
+            flags |= FLAG_EXTENDED;
+        }
+        content.extend(flags.to_be_bytes());
+        if self.extended_flags != 0 {
+            content.extend(self.extended_flags.to_be_bytes());
+        }
+
+        if version == 4 {
+            let common = previous
+                .iter()
+                .zip(&self.path)
+                .take_while(|(a, b)| a == b)
+                .count();
+            encode_varint(content, (previous.len() - common) as u64);
+            content.extend(&self.path[common..]);
+            content.push(b'\0');
+        } else {
+            content.extend(&self.path);
+            // entries are NUL-padded to a multiple of 8 bytes, at least one NUL is present
+            let padding = 8 - (content.len() - start) % 8;
+            content.extend(std::iter::repeat_n(0u8, padding));
+        }
+    }
+}
+
+/// Node of the `TREE` extension caching tree hashes of index directories.
+#[derive(Debug, Clone, Default)]
+pub struct CacheTree {
+    pub name: Vec<u8>,
+    /// Number of index entries covered by the tree, -1 when the tree is invalidated.
+    pub entry_count: i32,
+    pub hash: Option<ShaHash>,
+    pub children: Vec<CacheTree>,
+}
+
+impl CacheTree {
+    fn read(input: &mut &[u8]) -> anyhow::Result<Self> {
+        let mut name = Vec::new();
+        input.read_until(b'\0', &mut name)?;
+        name.pop();
+
+        let mut counts = Vec::new();
+        input.read_until(b'\n', &mut counts)?;
+        counts.pop();
+        let counts = String::from_utf8(counts)?;
+        let (entry_count, subtrees) = counts
+            .split_once(' ')
+            .ok_or(anyhow::anyhow!("malformed cache tree entry: {counts}"))?;
+        let entry_count = entry_count.parse::<i32>()?;
+        let subtrees = subtrees.parse::<usize>()?;
+
+        let hash = if entry_count >= 0 {
+            let mut hash = ShaHash::default();
+            input.read_exact(&mut hash)?;
+            Some(hash)
+        } else {
+            None
+        };
+
+        let children = (0..subtrees)
+            .map(|_| CacheTree::read(input))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            name,
+            entry_count,
+            hash,
+            children,
+        })
+    }
+
+    /// Marks the trees on the way to `path` as invalid.
+    fn invalidate(&mut self, path: &[u8]) {
+        self.entry_count = -1;
+        self.hash = None;
+        if let Some(pos) = path.iter().position(|&c| c == b'/') {
+            let (dir, rest) = (&path[..pos], &path[pos + 1..]);
+            if let Some(child) = self.children.iter_mut().find(|c| c.name == dir) {
+                child.invalidate(rest);
+            }
+        }
+    }
+
+    fn serialize(&self, content: &mut Vec<u8>) {
+        content.extend(&self.name);
+        content.push(b'\0');
+        content.extend(format!("{} {}\n", self.entry_count, self.children.len()).as_bytes());
+        if let Some(hash) = &self.hash {
+            content.extend(hash);
+        }
+        for child in &self.children {
+            child.serialize(content);
+        }
+    }
+}
+
+/// Entry of the `REUC` extension remembering the conflicting stages of a resolved path.
+#[derive(Debug, Clone)]
+pub struct ResolveUndo {
+    pub path: Vec<u8>,
+    pub modes: [u32; 3],
+    pub hashes: [Option<ShaHash>; 3],
+}
+
+impl ResolveUndo {
+    fn read(input: &mut &[u8]) -> anyhow::Result<Self> {
+        let mut path = Vec::new();
+        input.read_until(b'\0', &mut path)?;
+        path.pop();
+
+        let mut modes = [0u32; 3];
+        for mode in modes.iter_mut() {
+            let mut value = Vec::new();
+            input.read_until(b'\0', &mut value)?;
+            value.pop();
+            *mode = u32::from_str_radix(std::str::from_utf8(&value)?, 8)?;
+        }
+
+        let mut hashes = [None; 3];
+        for (mode, hash) in modes.iter().zip(hashes.iter_mut()) {
+            if *mode != 0 {
+                let mut value = ShaHash::default();
+                input.read_exact(&mut value)?;
+                *hash = Some(value);
+            }
+        }
+
+        Ok(Self {
+            path,
+            modes,
+            hashes,
+        })
+    }
+
+    fn serialize(&self, content: &mut Vec<u8>) {
+        content.extend(&self.path);
+        content.push(b'\0');
+        for mode in self.modes {
+            content.extend(format!("{mode:o}").as_bytes());
+            content.push(b'\0');
+        }
+        for hash in self.hashes.iter().flatten() {
+            content.extend(hash);
+        }
+    }
+}
+
+/// The staging area stored in `.git/index`.
+pub struct Index {
+    pub version: u32,
+    pub entries: Vec<IndexEntry>,
+    pub cache_tree: Option<CacheTree>,
+    pub resolve_undo: Vec<ResolveUndo>,
+}
+
+impl Index {
+    pub fn new(mut entries: Vec<IndexEntry>) -> Self {
+        entries.sort_by(|a, b| (&a.path, a.stage()).cmp(&(&b.path, b.stage())));
+        Self {
+            version: 2,
+            entries,
+            cache_tree: None,
+            resolve_undo: Vec::new(),
+        }
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(path)?;
+        if data.len() < 32 {
+            anyhow::bail!("index file too short: {}", path.display());
+        }
+        let (content, checksum) = data.split_at(data.len() - 20);
+        if Sha1::digest(content).as_slice() != checksum {
+            anyhow::bail!("index file checksum mismatch: {}", path.display());
+        }
+
+        let mut input = content;
+        let mut signature = [0u8; 4];
+        input.read_exact(&mut signature)?;
+        if &signature != SIGNATURE {
+            anyhow::bail!("invalid index signature: {}", path.display());
+        }
+        let version = read_u32(&mut input)?;
+        if !(2..=4).contains(&version) {
+            anyhow::bail!("unsupported index version: {version}");
+        }
+
+        let count = read_u32(&mut input)?;
+        let mut entries: Vec<IndexEntry> = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let previous = entries.last().map_or(&[][..], |e| &e.path);
+            let entry = IndexEntry::read(&mut input, version, previous)?;
+            entries.push(entry);
+        }
+
+        let mut cache_tree = None;
+        let mut resolve_undo = Vec::new();
+        while !input.is_empty() {
+            let mut signature = [0u8; 4];
+            input.read_exact(&mut signature)?;
+            let size = read_u32(&mut input)? as usize;
+            let mut extension = input
+                .get(..size)
+                .ok_or(anyhow::anyhow!("truncated index extension"))?;
+            input = &input[size..];
+
+            match &signature {
+                CACHE_TREE_SIGNATURE => cache_tree = Some(CacheTree::read(&mut extension)?),
+                RESOLVE_UNDO_SIGNATURE => {
+                    while !extension.is_empty() {
+                        resolve_undo.push(ResolveUndo::read(&mut extension)?);
+                    }
+                }
+                // optional extensions start with an uppercase letter and can be ignored
+                [b'A'..=b'Z', ..] => {}
+                _ => anyhow::bail!(
+                    "unsupported index extension: {}",
+                    String::from_utf8_lossy(&signature)
+                ),
+            }
+        }
+
+        Ok(Self {
+            version,
+            entries,
+            cache_tree,
+            resolve_undo,
+        })
+    }
+
+    /// The positions of all merge stages of a path, found by binary search
+    /// since entries are sorted by path and stage.
+    fn stages(&self, path: &[u8]) -> Range<usize> {
+        let start = self.entries.partition_point(|e| e.path.as_slice() < path);
+        let count = self.entries[start..]
+            .iter()
+            .take_while(|e| e.path == path)
+            .count();
+        start..start + count
+    }
+
+    /// The entry of a path with the lowest merge stage.
+    pub fn find(&self, path: &[u8]) -> Option<&IndexEntry> {
+        self.entries[self.stages(path)].first()
+    }
+
+    /// Whether there are entries below the directory `dir`.
+    pub fn contains_dir(&self, dir: &[u8]) -> bool {
+        let mut prefix = dir.to_vec();
+        prefix.push(b'/');
+        let pos = self.entries.partition_point(|e| e.path < prefix);
+        self.entries
+            .get(pos)
+            .is_some_and(|e| e.path.starts_with(&prefix))
+    }
+
+    /// Inserts or replaces the entry (all its merge stages). Entries that conflict
+    /// with the path, a file replaced by a directory or vice versa, are dropped.
+    pub fn add(&mut self, entry: IndexEntry) {
+        let path = entry.path.clone();
+
+        // entries below the path sort right after `path/`
+        let mut dir = path.clone();
+        dir.push(b'/');
+        let start = self.entries.partition_point(|e| e.path < dir);
+        let count = self.entries[start..]
+            .iter()
+            .take_while(|e| e.path.starts_with(&dir))
+            .count();
+        self.entries.drain(start..start + count);
+
+        // files at one of the leading directories of the path
+        for (i, _) in path.iter().enumerate().filter(|(_, &c)| c == b'/') {
+            let stages = self.stages(&path[..i]);
+            self.entries.drain(stages);
+        }
+
+        let stages = self.stages(&path);
+        self.entries.drain(stages);
+        let pos = self
+            .entries
+            .partition_point(|e| (&e.path, e.stage()) < (&entry.path, entry.stage()));
+        self.entries.insert(pos, entry);
+        self.invalidate(&path);
+    }
+
+    /// Removes all merge stages of the path, returns true if anything was removed.
+    pub fn remove(&mut self, path: &[u8]) -> bool {
+        let stages = self.stages(path);
+        let removed = !stages.is_empty();
+        self.entries.drain(stages);
+        self.invalidate(path);
+        removed
+    }
+
+    fn invalidate(&mut self, path: &[u8]) {
+        if let Some(cache_tree) = &mut self.cache_tree {
+            cache_tree.invalidate(path);
+        }
+    }
+
+    /// Reads the index or returns an empty one if it does not exist yet.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if path.exists() {
+            Self::read(path)
+        } else {
+            Ok(Self::new(Vec::new()))
+        }
+    }
+
+    /// Writes the index through a `.lock` file that is renamed over the target.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let version = if self.entries.iter().any(|e| e.extended_flags != 0) {
+            self.version.max(3)
+        } else {
+            self.version
+        };
+
+        let mut content = Vec::new();
+        content.extend(SIGNATURE);
+        content.extend(version.to_be_bytes());
+        content.extend((self.entries.len() as u32).to_be_bytes());
+        let mut previous: &[u8] = &[];
+        for entry in &self.entries {
+            entry.serialize(&mut content, version, previous);
+            previous = &entry.path;
+        }
+
+        if let Some(cache_tree) = &self.cache_tree {
+            let mut extension = Vec::new();
+            cache_tree.serialize(&mut extension);
+            write_extension(&mut content, CACHE_TREE_SIGNATURE, &extension);
+        }
+        if !self.resolve_undo.is_empty() {
+            let mut extension = Vec::new();
+            for entry in &self.resolve_undo {
+                entry.serialize(&mut extension);
+            }
+            write_extension(&mut content, RESOLVE_UNDO_SIGNATURE, &extension);
+        }
+
+        let checksum = Sha1::digest(&content);
+        content.extend(checksum);
+
+        let mut lock = LockFile::acquire(path)?;
+        lock.write_all(&content)?;
+        lock.commit()
+    }
+}
+
+/// Returns true if `dir` is a leading directory of `path`.
+pub fn is_parent_path(dir: &[u8], path: &[u8]) -> bool {
+    dir.is_empty() || (path.starts_with(dir) && path.get(dir.len()) == Some(&b'/'))
+}
+
+fn write_extension(content: &mut Vec<u8>, signature: &[u8; 4], extension: &[u8]) {
+    content.extend(signature);
+    content.extend((extension.len() as u32).to_be_bytes());
+    content.extend(extension);
+}
+
+fn read_u32(input: &mut &[u8]) -> anyhow::Result<u32> {
+    let mut buffer = [0u8; 4];
+    input.read_exact(&mut buffer)?;
+    Ok(u32::from_be_bytes(buffer))
+}
+
+fn read_u16(input: &mut &[u8]) -> anyhow::Result<u16> {
+    let mut buffer = [0u8; 2];
+    input.read_exact(&mut buffer)?;
+    Ok(u16::from_be_bytes(buffer))
+}
+
+/// Inverse of `parse_delta_offset`, used for the v4 path prefix compression.
+fn encode_varint(content: &mut Vec<u8>, mut value: u64) {
+    let mut buffer = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        buffer.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    content.extend(buffer.iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn entry(path: &str, stage: u16) -> IndexEntry {
+        IndexEntry {
+            mode: 0o100644,
+            flags: stage << 12,
+            path: path.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn paths(index: &Index) -> Vec<(&str, u16)> {
+        index
+            .entries
+            .iter()
+            .map(|e| (std::str::from_utf8(&e.path).unwrap(), e.stage()))
+            .collect()
+    }
+
+    #[test]
+    fn find_and_remove_stages() {
+        let index = Index::new(vec![
+            entry("b", 3),
+            entry("a", 0),
+            entry("b", 1),
+            entry("c", 0),
+        ]);
+        assert_eq!(index.find(b"b").unwrap().stage(), 1);
+        assert!(index.find(b"bb").is_none());
+
+        let mut index = index;
+        assert!(index.remove(b"b"));
+        assert!(!index.remove(b"b"));
+        assert_eq!(paths(&index), [("a", 0), ("c", 0)]);
+    }
+
+    #[test]
+    fn add_replaces_conflicting_paths() {
+        let mut index = Index::new(vec![
+            entry("a", 0),
+            entry("a-b", 0),
+            entry("a/b", 0),
+            entry("a/c/d", 0),
+            entry("a0", 0),
+            entry("x", 2),
+        ]);
+
+        // a directory replaced by a file
+        index.add(entry("a", 0));
+        assert_eq!(paths(&index), [("a", 0), ("a-b", 0), ("a0", 0), ("x", 2)]);
+
+        // a file replaced by a directory, resolving a conflict
+        index.add(entry("a/b", 0));
+        index.add(entry("x", 0));
+        assert_eq!(paths(&index), [("a-b", 0), ("a/b", 0), ("a0", 0), ("x", 0)]);
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 127, 128, 255, 16511, 16512, u32::MAX as u64] {
+            let mut content = Vec::new();
+            encode_varint(&mut content, value);
+            assert_eq!(parse_delta_offset(&mut &content[..]).unwrap(), value);
+        }
+    }
+
+    fn stat_entry(path: &str, extended_flags: u16) -> IndexEntry {
+        IndexEntry {
+            ctime_sec: 1,
+            ctime_nsec: 2,
+            mtime_sec: 3,
+            mtime_nsec: 4,
+            dev: 5,
+            ino: 6,
+            uid: 7,
+            gid: 8,
+            size: 9,
+            hash: [path.len() as u8; 20],
+            flags: path.len() as u16,
+            extended_flags,
+            ..entry(path, 0)
+        }
+    }
+
+    /// Writes the index, reads it back and checks that writing it again gives the same file.
+    fn round_trip(index: &Index) -> (Index, Vec<u8>) {
+        let dir = TempDir::new();
+        let file = dir.path().join("index");
+        index.write(&file).unwrap();
+        let data = fs::read(&file).unwrap();
+        let read = Index::read(&file).unwrap();
+        read.write(&file).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), data);
+        (read, data)
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        for version in [2, 3, 4] {
+            let mut index = Index::new(vec![
+                stat_entry("dir/a", 0),
+                stat_entry("dir/b", 0),
+                stat_entry("file-with-a-longer-name", 0),
+            ]);
+            index.version = version;
+            let (read, data) = round_trip(&index);
+            assert_eq!(read.version, version);
+            assert_eq!(&data[4..8], &version.to_be_bytes());
+            assert_eq!(
+                format!("{:?}", read.entries),
+                format!("{:?}", index.entries)
+            );
+        }
+    }
+
+    #[test]
+    fn extended_flags_round_trip() {
+        let skip_worktree = 0x4000;
+        let mut index = Index::new(vec![stat_entry("a", skip_worktree), stat_entry("b", 0)]);
+        // extended flags force version 3
+        let (read, data) = round_trip(&index);
+        assert_eq!(read.version, 3);
+        assert_eq!(read.entries[0].extended_flags, skip_worktree);
+        assert_ne!(read.entries[0].flags & FLAG_EXTENDED, 0);
+        assert_eq!(read.entries[1].extended_flags, 0);
+        assert_eq!(data.len(), 12 + 72 + 64 + 20);
+
+        // but version 2 cannot represent them
+        index.entries[0].flags |= FLAG_EXTENDED;
+        let mut content = Vec::new();
+        content.extend(SIGNATURE);
+        content.extend(2u32.to_be_bytes());
+        content.extend(1u32.to_be_bytes());
+        index.entries[0].serialize(&mut content, 3, &[]);
+        let checksum = Sha1::digest(&content);
+        content.extend(checksum);
+        let dir = TempDir::new();
+        fs::write(dir.path().join("index"), content).unwrap();
+        assert!(Index::read(&dir.path().join("index")).is_err());
+    }
+
+    #[test]
+    fn prefix_compressed_paths() {
+        let mut index = Index::new(vec![
+            stat_entry("dir/a", 0),
+            stat_entry("dir/b", 0),
+            stat_entry("file", 0),
+        ]);
+        index.version = 4;
+        let (read, data) = round_trip(&index);
+        assert_eq!(paths(&read), [("dir/a", 0), ("dir/b", 0), ("file", 0)]);
+
+        // no padding, each path is stored as the length to strip and the new suffix
+        assert_eq!(data.len(), 12 + (62 + 7) + (62 + 3) + (62 + 6) + 20);
+        let entry = 12 + 69 + 62;
+        assert_eq!(&data[entry..entry + 3], b"\x01b\0");
+        let entry = entry + 3 + 62;
+        assert_eq!(&data[entry..entry + 6], b"\x05file\0");
+    }
+
+    fn index_file(extensions: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut content = Vec::new();
+        content.extend(SIGNATURE);
+        content.extend(2u32.to_be_bytes());
+        content.extend(0u32.to_be_bytes());
+        for (signature, extension) in extensions {
+            write_extension(&mut content, signature, extension);
+        }
+        let checksum = Sha1::digest(&content);
+        content.extend(checksum);
+        content
+    }
+
+    #[test]
+    fn extensions() {
+        let mut cache_tree = b"\x003 2\n".to_vec();
+        cache_tree.extend([1; 20]);
+        cache_tree.extend(b"dir\x002 0\n");
+        cache_tree.extend([2; 20]);
+        cache_tree.extend(b"stale\x00-1 0\n");
+        let mut resolve_undo = b"path\x00100644\x000\x00100755\x00".to_vec();
+        resolve_undo.extend([3; 20]);
+        resolve_undo.extend([4; 20]);
+        resolve_undo.extend(b"other\x000\x00100644\x000\x00");
+        resolve_undo.extend([5; 20]);
+        let data = index_file(&[
+            (CACHE_TREE_SIGNATURE, &cache_tree),
+            (RESOLVE_UNDO_SIGNATURE, &resolve_undo),
+        ]);
+
+        let dir = TempDir::new();
+        let file = dir.path().join("index");
+        fs::write(&file, &data).unwrap();
+        let index = Index::read(&file).unwrap();
+
+        let root = index.cache_tree.as_ref().unwrap();
+        assert_eq!((root.name.as_slice(), root.entry_count), (&b""[..], 3));
+        assert_eq!(root.hash, Some([1; 20]));
+        let [dir_tree, stale] = &root.children[..] else {
+            panic!("expected two subtrees");
+        };
+        assert_eq!(
+            (dir_tree.name.as_slice(), dir_tree.entry_count),
+            (&b"dir"[..], 2)
+        );
+        assert_eq!(dir_tree.hash, Some([2; 20]));
+        assert_eq!((stale.entry_count, stale.hash), (-1, None));
+
+        let [path, other] = &index.resolve_undo[..] else {
+            panic!("expected two resolve-undo entries");
+        };
+        assert_eq!(path.path, b"path");
+        assert_eq!(path.modes, [0o100644, 0, 0o100755]);
+        assert_eq!(path.hashes, [Some([3; 20]), None, Some([4; 20])]);
+        assert_eq!(other.modes, [0, 0o100644, 0]);
+        assert_eq!(other.hashes, [None, Some([5; 20]), None]);
+
+        let (_, written) = round_trip(&index);
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn unknown_extensions() {
+        let dir = TempDir::new();
+        let file = dir.path().join("index");
+        fs::write(&file, index_file(&[(b"UNTR", b"anything")])).unwrap();
+        let index = Index::read(&file).unwrap();
+        assert!(index.cache_tree.is_none() && index.resolve_undo.is_empty());
+
+        // lowercase signatures mark extensions required to understand the index
+        fs::write(&file, index_file(&[(b"link", b"anything")])).unwrap();
+        assert!(Index::read(&file).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, Offset, TimeZone};
+use std::env;
+
+use crate::config::Config;
+use crate::object::Signature;
+
+#[derive(Clone, Copy)]
+pub enum Role {
+    Author,
+    Committer,
+}
+
+impl Role {
+    fn env_prefix(&self) -> &'static str {
+        match self {
+            Role::Author => "GIT_AUTHOR",
+            Role::Committer => "GIT_COMMITTER",
+        }
+    }
+
+    fn config_section(&self) -> &'static str {
+        match self {
+            Role::Author => "author",
+            Role::Committer => "committer",
+        }
+    }
+}
+
+/// Resolves the identity from `GIT_{AUTHOR,COMMITTER}_{NAME,EMAIL,DATE}`,
+/// then `author.*`/`committer.*` and `user.*` config values.
+pub fn signature(config: &Config, role: Role) -> anyhow::Result<Signature> {
+    let prefix = role.env_prefix();
+    let section = role.config_section();
+    let lookup = |field: &str| {
+        env::var(format!("{prefix}_{}", field.to_uppercase()))
+            .ok()
+            .or_else(|| config.get(&format!("{section}.{field}")).map(str::to_owned))
+            .or_else(|| config.get(&format!("user.{field}")).map(str::to_owned))
+            .map(|value| without_crud(&value))
+            .filter(|value| !value.is_empty())
+    };
+
+    let email = lookup("email").or_else(|| env::var("EMAIL").ok().map(|e| without_crud(&e)));
+    let (Some(name), Some(email)) = (lookup("name"), email) else {
+        anyhow::bail!(
+            "{} identity unknown, please tell me who you are:\n\n  \
+             git config --global user.email \"you@example.com\"\n  \
+             git config --global user.name \"Your Name\"",
+            match role {
+                Role::Author => "Author",
+                Role::Committer => "Committer",
+            }
+        );
+    };
+
+    let (timestamp, timezone) = match env::var(format!("{prefix}_DATE")) {
+        Ok(date) => parse_date(&date)?,
+        Err(_) => {
+            let now = Local::now();
+            (now.timestamp(), format_offset(now.offset().fix()))
+        }
+    };
+
+    Ok(Signature {
+        name,
+        email,
+        timestamp,
+        timezone,
+    })
+}
+
+/// Cleans a name or email like git's `strbuf_addstr_without_crud`: `<`, `>` and
+/// newlines would break the signature line, they are removed, and leading and
+/// trailing whitespace and punctuation are trimmed.
+fn without_crud(value: &str) -> String {
+    let is_crud = |c: char| c <= ' ' || ".,:;<>\"\\'".contains(c);
+    value
+        .trim_matches(is_crud)
+        .chars()
+        .filter(|c| !matches!(c, '<' | '>' | '\n'))
+        .collect()
+}
+
+/// Formats an offset the way git stores it, e.g. `+0200`.
+pub fn format_offset(offset: FixedOffset) -> String {
+    let seconds = offset.local_minus_utc();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    format!("{sign}{:02}{:02}", minutes / 60, minutes % 60)
+}
+
+/// Parses the date formats accepted by git: the internal `<timestamp> <tz>` format
+/// (optionally prefixed with `@`), RFC 2822 and ISO 8601.
+pub fn parse_date(date: &str) -> anyhow::Result<(i64, String)> {
+    let date = date.trim();
+    if let Some((timestamp, timezone)) = date.trim_start_matches('@').split_once(' ') {
+        if let (Ok(timestamp), Ok(_)) = (timestamp.parse::<i64>(), timezone.parse::<i32>()) {
+            return Ok((timestamp, timezone.to_owned()));
+        }
+    }
+    if let Some(timestamp) = date.strip_prefix('@') {
+        return Ok((timestamp.parse()?, "+0000".to_owned()));
+    }
+
+    let parsed = DateTime::parse_from_rfc2822(date)
+        .or_else(|_| DateTime::parse_from_rfc3339(date))
+        .or_else(|_| DateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S %z"))
+        .or_else(|_| DateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%z"));
+    if let Ok(parsed) = parsed {
+        return Ok((parsed.timestamp(), format_offset(*parsed.offset())));
+    }
+
+    // dates without a timezone are in the local one
+    let naive = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S"))
+        .map_err(|_| anyhow::anyhow!("invalid date format: {date}"))?;
+    let local = Local
+        .from_local_datetime(&naive)
+        .single()
+        .ok_or(anyhow::anyhow!("ambiguous local date: {date}"))?;
+    Ok((local.timestamp(), format_offset(local.offset().fix())))
+}
+
+/// Date formats used when showing commits.
+#[derive(Clone, Copy)]
+pub enum DateFormat {
+    /// `Tue Nov 14 23:13:20 2023 +0100`
+    Default,
+    /// `2023-11-14 23:13:20 +0100`
+    Iso,
+    /// `2023-11-14T23:13:20+01:00`
+    IsoStrict,
+    /// `Tue, 14 Nov 2023 23:13:20 +0100`
+    Rfc2822,
+}
+
+/// Formats a timestamp in the timezone it was recorded with.
+pub fn format_date(timestamp: i64, timezone: &str, format: DateFormat) -> String {
+    let offset = timezone
+        .parse::<i32>()
+        .ok()
+        .and_then(|tz| {
+            FixedOffset::east_opt(tz.signum() * (tz.abs() / 100 * 3600 + tz.abs() % 100 * 60))
+        })
+        .unwrap_or(FixedOffset::east_opt(0).expect("zero offset is valid"));
+    let Some(date) = DateTime::from_timestamp(timestamp, 0) else {
+        return timestamp.to_string();
+    };
+    let date = date.with_timezone(&offset);
+    let pattern = match format {
+        DateFormat::Default => "%a %b %-d %H:%M:%S %Y %z",
+        DateFormat::Iso => "%Y-%m-%d %H:%M:%S %z",
+        DateFormat::IsoStrict => "%Y-%m-%dT%H:%M:%S%:z",
+        DateFormat::Rfc2822 => "%a, %-d %b %Y %H:%M:%S %z",
+    };
+    date.format(pattern).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crud_is_removed() {
+        assert_eq!(without_crud("  Jane Doe. "), "Jane Doe");
+        assert_eq!(without_crud("<jane@example.com>"), "jane@example.com");
+        assert_eq!(without_crud("Jane <x>\nDoe"), "Jane xDoe");
+        assert_eq!(without_crud("\"'.;"), "");
+    }
+}
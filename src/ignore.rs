@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::repository::Repository;
+use crate::wildmatch::wildmatch;
+
+/// A line of an ignore file.
+struct Pattern {
+    pattern: Vec<u8>,
+    /// `!pattern` re-includes paths excluded by an earlier pattern.
+    negated: bool,
+    /// `pattern/` only matches directories.
+    dir_only: bool,
+    /// Patterns with a `/` before the end match the path relative to the ignore
+    /// file's directory, the others match the basename at any depth.
+    anchored: bool,
+}
+
+impl Pattern {
+    fn parse(line: &[u8]) -> Option<Self> {
+        let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() || line[0] == b'#' {
+            return None;
+        }
+        // trailing spaces are ignored unless escaped with a backslash
+        while line.ends_with(b" ") && !line.ends_with(b"\\ ") {
+            line = &line[..line.len() - 1];
+        }
+
+        let negated = line.starts_with(b"!");
+        if negated {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with(b"/");
+        if dir_only {
+            line = &line[..line.len() - 1];
+        }
+        let anchored = line.contains(&b'/');
+        let line = line.strip_prefix(b"/").unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            pattern: line.to_vec(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    /// `path` is relative to the directory of the ignore file.
+    fn matches(&self, path: &[u8], is_dir: bool, ignore_case: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            return wildmatch(&self.pattern, path, ignore_case);
+        }
+        let basename = match path.iter().rposition(|&c| c == b'/') {
+            Some(slash) => &path[slash + 1..],
+            None => path,
+        };
+        wildmatch(&self.pattern, basename, ignore_case)
+    }
+}
+
+fn read_patterns(path: &Path) -> anyhow::Result<Vec<Pattern>> {
+    match fs::read(path) {
+        Ok(content) => Ok(content
+            .split(|&c| c == b'\n')
+            .filter_map(Pattern::parse)
+            .collect()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => anyhow::bail!("cannot read {}: {err}", path.display()),
+    }
+}
+
+/// The `core.excludesFile` default, `$XDG_CONFIG_HOME/git/ignore`.
+fn default_excludes_file() -> Option<PathBuf> {
+    match (env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+        (Some(xdg), _) => Some(Path::new(&xdg).join("git/ignore")),
+        (None, Some(home)) => Some(Path::new(&home).join(".config/git/ignore")),
+        _ => None,
+    }
+}
+
+/// Decides which worktree paths are ignored using the `.gitignore` files of their
+/// directories, `.git/info/exclude` and `core.excludesFile`, in this precedence.
+pub struct IgnoreRules {
+    work_tree: PathBuf,
+    ignore_case: bool,
+    /// `info/exclude` followed by `core.excludesFile`.
+    excludes: Vec<Vec<Pattern>>,
+    /// `.gitignore` patterns by worktree-relative directory, read when first needed.
+    directories: HashMap<Vec<u8>, Vec<Pattern>>,
+}
+
+impl IgnoreRules {
+    pub fn load(repo: &Repository) -> anyhow::Result<Self> {
+        let work_tree = repo.work_tree()?;
+        // git runs from the top of the worktree, relative paths are resolved from there
+        let excludes_file = match repo.config.get("core.excludesFile") {
+            Some(path) => Some(config::expand_path(path, work_tree)?),
+            None => default_excludes_file(),
+        };
+        let mut excludes = vec![read_patterns(&repo.git_dir.join("info/exclude"))?];
+        if let Some(path) = excludes_file {
+            excludes.push(read_patterns(&path)?);
+        }
+
+        Ok(Self {
+            work_tree: work_tree.to_owned(),
+            ignore_case: repo.config.get_bool("core.ignoreCase")?.unwrap_or(false),
+            excludes,
+            directories: HashMap::new(),
+        })
+    }
+
+    fn directory(&mut self, dir: &[u8]) -> anyhow::Result<&[Pattern]> {
+        if !self.directories.contains_key(dir) {
+            let mut path = dir.to_vec();
+            if !path.is_empty() {
+                path.push(b'/');
+            }
+            path.extend(b".gitignore");
+            let path = self.work_tree.join(OsStr::from_bytes(&path));
+            self.directories.insert(dir.to_vec(), read_patterns(&path)?);
+        }
+        Ok(&self.directories[dir])
+    }
+
+    /// Whether a worktree-relative path is ignored, also when one of its parent
+    /// directories is: files below an ignored directory cannot be re-included.
+    pub fn is_ignored(&mut self, path: &[u8], is_dir: bool) -> anyhow::Result<bool> {
+        for (i, _) in path.iter().enumerate().filter(|(_, &c)| c == b'/') {
+            if self.is_excluded(&path[..i], true)? {
+                return Ok(true);
+            }
+        }
+        self.is_excluded(path, is_dir)
+    }
+
+    /// Checks the patterns that apply to a path, the last matching pattern of the
+    /// most specific file decides.
+    fn is_excluded(&mut self, path: &[u8], is_dir: bool) -> anyhow::Result<bool> {
+        let ignore_case = self.ignore_case;
+        let mut dir = path;
+        while let Some(slash) = dir.iter().rposition(|&c| c == b'/') {
+            dir = &dir[..slash];
+            let relative = &path[slash + 1..];
+            let patterns = self.directory(dir)?;
+            if let Some(p) = patterns
+                .iter()
+                .rev()
+                .find(|p| p.matches(relative, is_dir, ignore_case))
+            {
+                return Ok(!p.negated);
+            }
+        }
+
+        self.directory(&[])?;
+        let levels = std::iter::once(&self.directories[&[][..]]).chain(&self.excludes);
+        for patterns in levels {
+            if let Some(p) = patterns
+                .iter()
+                .rev()
+                .find(|p| p.matches(path, is_dir, ignore_case))
+            {
+                return Ok(!p.negated);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn pattern(line: &str) -> Pattern {
+        Pattern::parse(line.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn parse_lines() {
+        assert!(Pattern::parse(b"").is_none());
+        assert!(Pattern::parse(b"# comment").is_none());
+        assert!(Pattern::parse(b"/").is_none());
+
+        let p = pattern("!/build/  ");
+        assert!(p.negated && p.dir_only && p.anchored);
+        assert_eq!(p.pattern, b"build");
+        assert_eq!(pattern("a\\ ").pattern, b"a\\ ");
+        assert!(!pattern("*.o").anchored);
+        assert!(pattern("doc/*.txt").anchored);
+    }
+
+    #[test]
+    fn pattern_matches() {
+        // basename patterns match at any depth
+        assert!(pattern("*.o").matches(b"a/b/c.o", false, false));
+        // anchored patterns match the whole relative path
+        assert!(pattern("/a.o").matches(b"a.o", false, false));
+        assert!(!pattern("/a.o").matches(b"x/a.o", false, false));
+        assert!(pattern("doc/*.txt").matches(b"doc/a.txt", false, false));
+        assert!(!pattern("doc/*.txt").matches(b"doc/x/a.txt", false, false));
+        assert!(pattern("doc/**/*.txt").matches(b"doc/x/a.txt", false, false));
+        assert!(pattern("**/tmp").matches(b"a/tmp", true, false));
+        // directory-only patterns
+        assert!(pattern("build/").matches(b"build", true, false));
+        assert!(!pattern("build/").matches(b"build", false, false));
+        assert!(pattern("*.O").matches(b"a.o", false, true));
+    }
+
+    #[test]
+    fn rules_precedence() {
+        let dir = TempDir::new();
+        let work_tree = dir.path().to_owned();
+        fs::create_dir_all(work_tree.join("sub")).unwrap();
+        fs::write(
+            work_tree.join(".gitignore"),
+            "*.log\nbuild/\n!build/keep.txt\n/top\n",
+        )
+        .unwrap();
+        fs::write(work_tree.join("sub/.gitignore"), "!keep.log\n").unwrap();
+
+        let mut rules = IgnoreRules {
+            work_tree: work_tree.clone(),
+            ignore_case: false,
+            excludes: vec![vec![pattern("*.tmp"), pattern("!top.tmp")]],
+            directories: HashMap::new(),
+        };
+        let mut ignored = |path: &str, is_dir| rules.is_ignored(path.as_bytes(), is_dir).unwrap();
+
+        assert!(ignored("a.log", false));
+        assert!(ignored("sub/a.log", false));
+        // a deeper .gitignore overrides the top-level one
+        assert!(!ignored("sub/keep.log", false));
+        assert!(ignored("keep.log", false));
+        // files below an ignored directory cannot be re-included
+        assert!(ignored("build/keep.txt", false));
+        assert!(ignored("top", false));
+        assert!(!ignored("sub/top", false));
+        // info/exclude comes after the .gitignore files
+        assert!(ignored("a.tmp", false));
+        assert!(!ignored("top.tmp", false));
+        assert!(!ignored("a.txt", false));
+    }
+
+    #[test]
+    fn relative_excludes_file() {
+        let dir = TempDir::new();
+        let repo = Repository::init(dir.path()).unwrap();
+        let work_tree = repo.work_tree().unwrap();
+        fs::write(
+            repo.git_dir.join("config"),
+            "[core]\n\texcludesFile = ignores\n",
+        )
+        .unwrap();
+        fs::write(work_tree.join("ignores"), "*.x\n").unwrap();
+        fs::create_dir(repo.git_dir.join("info")).unwrap();
+        fs::write(repo.git_dir.join("info/exclude"), "*.y\n").unwrap();
+
+        // reopened to read the config written above, the current directory of the
+        // tests is not the worktree
+        let repo = Repository::open(repo.git_dir.clone(), repo.work_tree.clone()).unwrap();
+        let mut rules = IgnoreRules::load(&repo).unwrap();
+        assert!(rules.is_ignored(b"sub/a.x", false).unwrap());
+        assert!(rules.is_ignored(b"a.y", false).unwrap());
+        assert!(!rules.is_ignored(b"a.z", false).unwrap());
+    }
+}
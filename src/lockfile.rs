@@ -0,0 +1,53 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Exclusive `<path>.lock` file, renamed over `path` on commit and removed if dropped before.
+pub struct LockFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: Option<File>,
+}
+
+impl LockFile {
+    pub fn acquire(path: &Path) -> anyhow::Result<Self> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = File::create_new(&lock_path)
+            .map_err(|err| anyhow::anyhow!("unable to create '{}': {err}", lock_path.display()))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            lock_path,
+            file: Some(file),
+        })
+    }
+
+    pub fn write_all(&mut self, content: &[u8]) -> anyhow::Result<()> {
+        self.file
+            .as_mut()
+            .expect("lock file is open until committed")
+            .write_all(content)?;
+        Ok(())
+    }
+
+    /// Replaces the target with the written content.
+    pub fn commit(mut self) -> anyhow::Result<()> {
+        drop(self.file.take());
+        fs::rename(&self.lock_path, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
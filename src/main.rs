@@ -1,12 +1,43 @@
 use clap::{Parser, Subcommand};
-use std::{path::PathBuf, str};
+use std::{env, path::PathBuf, process, str};
 
+use object::ObjectKind;
+use repository::Repository;
+
+mod config;
+mod dircache;
+mod ident;
+mod ignore;
+mod lockfile;
 mod object;
+mod odb;
+mod pack;
+mod pktline;
+mod pretty;
+mod refs;
+mod repository;
+mod revision;
+mod revwalk;
 mod subcommand;
+#[cfg(test)]
+mod testutil;
+mod wildmatch;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    /// Run as if git was started in the given path
+    #[arg(short = 'C', value_name = "PATH")]
+    directory: Vec<PathBuf>,
+
+    /// Path to the repository (the .git directory)
+    #[arg(long, value_name = "PATH")]
+    git_dir: Option<PathBuf>,
+
+    /// Path to the working tree
+    #[arg(long, value_name = "PATH")]
+    work_tree: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -17,12 +48,65 @@ enum Commands {
     Init,
     /// Read a blob git object
     CatFile {
-        /// Pretty-print
-        #[arg(short)]
+        /// Object type, or the object when one of -p, -t, -s or -e is given
+        #[arg(value_name = "TYPE", required_unless_present = "batch_mode")]
+        kind: Option<String>,
+
+        /// Object to print as the given type
+        object: Option<String>,
+
+        /// Pretty-print the content based on its type
+        #[arg(short, group = "mode")]
         pretty: bool,
 
-        /// Hash
-        hash: String,
+        /// Show the object type
+        #[arg(short = 't', group = "mode")]
+        show_type: bool,
+
+        /// Show the object size
+        #[arg(short = 's', group = "mode")]
+        size: bool,
+
+        /// Exit with zero status if the object exists, print nothing
+        #[arg(short = 'e', group = "mode")]
+        exists: bool,
+
+        /// Print the information and content of the objects named on stdin
+        #[arg(
+            long,
+            value_name = "FORMAT",
+            num_args = 0..=1,
+            require_equals = true,
+            group = "batch_mode",
+            conflicts_with = "mode"
+        )]
+        batch: Option<Option<String>>,
+
+        /// Print the information of the objects named on stdin
+        #[arg(
+            long,
+            value_name = "FORMAT",
+            num_args = 0..=1,
+            require_equals = true,
+            group = "batch_mode",
+            conflicts_with = "mode"
+        )]
+        batch_check: Option<Option<String>>,
+
+        /// Read `info <object>`, `contents <object>` and `flush` commands from stdin
+        #[arg(
+            long,
+            value_name = "FORMAT",
+            num_args = 0..=1,
+            require_equals = true,
+            group = "batch_mode",
+            conflicts_with = "mode"
+        )]
+        batch_command: Option<Option<String>>,
+
+        /// Do not flush the output after every object
+        #[arg(long, requires = "batch_mode")]
+        buffer: bool,
     },
     /// Write a blob git object
     HashObject {
@@ -35,12 +119,100 @@ enum Commands {
     },
     /// Inspect a tree object
     LsTree {
-        /// Hash
-        hash: String,
+        /// Tree or commit-ish whose tree is listed
+        tree: String,
+
+        /// Show only the entries matching these paths, relative to the root of the tree
+        paths: Vec<String>,
+
+        /// Recurse into subtrees
+        #[arg(short)]
+        recursive: bool,
+
+        /// Show tree entries even when recursing into them
+        #[arg(short = 't')]
+        show_trees: bool,
+
+        /// Show only tree entries
+        #[arg(short = 'd')]
+        trees_only: bool,
+
+        /// Show the size of blobs
+        #[arg(short, long)]
+        long: bool,
+
+        /// Terminate entries with NUL instead of newline
+        #[arg(short = 'z')]
+        null_terminated: bool,
 
         /// Print only names
-        #[arg(long)]
+        #[arg(long, alias = "name-status")]
         name_only: bool,
+
+        /// Abbreviate object names to the shortest unique prefix of at least N digits
+        #[arg(
+            long,
+            value_name = "N",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "7"
+        )]
+        abbrev: Option<usize>,
+    },
+    /// Add file contents to the index
+    Add {
+        /// Files or directories to add
+        paths: Vec<PathBuf>,
+
+        /// Update only files that are already tracked
+        #[arg(short, long)]
+        update: bool,
+
+        /// Add, modify and remove index entries to match the working tree
+        #[arg(short = 'A', long, conflicts_with = "update")]
+        all: bool,
+
+        /// Leave the entries of files removed from the working tree in the index
+        #[arg(long, alias = "no-all", conflicts_with_all = ["update", "all"])]
+        ignore_removal: bool,
+
+        /// Also add ignored files
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Remove files from the working tree and from the index
+    Rm {
+        /// Files or directories to remove
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Only remove from the index
+        #[arg(long)]
+        cached: bool,
+
+        /// Allow recursive removal of directories
+        #[arg(short)]
+        recursive: bool,
+
+        /// Override the up-to-date check
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Show the working tree status
+    Status {
+        /// Give the output in the short format
+        #[arg(short, long)]
+        short: bool,
+
+        /// Give the output in a machine-readable format (v1 or v2)
+        #[arg(long, value_name = "VERSION", num_args = 0..=1, default_missing_value = "v1")]
+        porcelain: Option<String>,
+    },
+    /// Show files in the index
+    LsFiles {
+        /// Show mode, hash and stage of the entries
+        #[arg(short, long)]
+        stage: bool,
     },
     /// Write tree object
     WriteTree,
@@ -49,13 +221,238 @@ enum Commands {
         /// Hash
         tree_object: String,
 
-        /// Parent commit hash
+        /// Parent commit hash, can be repeated for merges or omitted for a root commit
+        #[arg(short)]
+        parent: Vec<String>,
+
+        /// Commit message paragraph
         #[arg(short)]
-        parent: String,
+        message: Vec<String>,
 
-        /// Commit message
+        /// Read the commit message from a file, `-` for stdin
+        #[arg(short = 'F', value_name = "FILE")]
+        file: Vec<PathBuf>,
+    },
+    /// Record changes to the repository
+    Commit {
+        /// Commit message paragraph
+        #[arg(short, long)]
+        message: Vec<String>,
+
+        /// Read the commit message from a file, `-` for stdin
+        #[arg(short = 'F', long, value_name = "FILE", conflicts_with = "message")]
+        file: Vec<PathBuf>,
+
+        /// Replace the tip of the current branch
+        #[arg(long)]
+        amend: bool,
+
+        /// Allow a commit with the same tree as its parent
+        #[arg(long)]
+        allow_empty: bool,
+    },
+    /// Show commit logs
+    Log {
+        /// Commits to start from, HEAD by default
+        revisions: Vec<String>,
+
+        /// Limit the number of commits to output
+        #[arg(short = 'n', long, value_name = "NUMBER")]
+        max_count: Option<usize>,
+
+        /// Do not show parents before all of their children are shown
+        #[arg(long)]
+        topo_order: bool,
+
+        /// Follow only the first parent of merge commits
+        #[arg(long)]
+        first_parent: bool,
+
+        /// Shorthand for --format=oneline
+        #[arg(long)]
+        oneline: bool,
+
+        /// Pretty-print format: oneline, short, medium, full, fuller or format:<string>
+        #[arg(long, alias = "pretty", value_name = "FORMAT")]
+        format: Option<String>,
+    },
+    /// Pick out and massage revision parameters
+    RevParse {
+        /// Revisions, ranges (A..B, A...B) and exclusions (^A)
+        args: Vec<String>,
+
+        /// Require exactly one revision that names an existing object
+        #[arg(long)]
+        verify: bool,
+
+        /// Fail silently with --verify
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Abbreviate object names to a unique prefix of at least N digits
+        #[arg(
+            long,
+            value_name = "N",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "7"
+        )]
+        short: Option<usize>,
+
+        /// Print the short name of the ref a revision names
+        #[arg(long)]
+        abbrev_ref: bool,
+
+        /// Print the full name of the ref a revision names
+        #[arg(long)]
+        symbolic_full_name: bool,
+
+        /// Print the path of the git directory
+        #[arg(long)]
+        git_dir: bool,
+
+        /// Print the path of the top-level directory of the working tree
+        #[arg(long)]
+        show_toplevel: bool,
+    },
+    /// Update the object name stored in a ref
+    UpdateRef {
+        /// Ref to update
+        reference: String,
+
+        /// New object name (the expected old value with -d)
+        new_value: Option<String>,
+
+        /// Expected current object name, empty or zeros if the ref must not exist
+        old_value: Option<String>,
+
+        /// Delete the ref
         #[arg(short)]
-        message: String,
+        delete: bool,
+
+        /// Update the symbolic ref itself instead of the ref it points to
+        #[arg(long)]
+        no_deref: bool,
+    },
+    /// Read, modify and delete symbolic refs
+    SymbolicRef {
+        /// Symbolic ref name
+        name: String,
+
+        /// Ref to point to
+        target: Option<String>,
+
+        /// Delete the symbolic ref
+        #[arg(short, long)]
+        delete: bool,
+
+        /// Do not report refs that are not symbolic
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Shorten the printed ref name
+        #[arg(long)]
+        short: bool,
+    },
+    /// List references in the repository
+    ShowRef {
+        /// Show only refs matching the patterns (full name or trailing components)
+        patterns: Vec<String>,
+
+        /// Show the HEAD reference
+        #[arg(long)]
+        head: bool,
+
+        /// Show only branches
+        #[arg(long)]
+        heads: bool,
+
+        /// Show only tags
+        #[arg(long)]
+        tags: bool,
+
+        /// Also show the objects annotated tags point to
+        #[arg(short, long)]
+        dereference: bool,
+
+        /// Show only the object names, abbreviated to the given length
+        #[arg(
+            short = 's',
+            long,
+            value_name = "N",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "40"
+        )]
+        hash: Option<usize>,
+
+        /// Require exact ref names
+        #[arg(long)]
+        verify: bool,
+
+        /// Do not print anything, only report whether a ref matched
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Get and set repository or global options
+    Config {
+        /// Variable name (section.name or section.subsection.name)
+        name: Option<String>,
+
+        /// Value to set
+        value: Option<String>,
+
+        /// Use the global config file
+        #[arg(long, group = "scope")]
+        global: bool,
+
+        /// Use the system config file
+        #[arg(long, group = "scope")]
+        system: bool,
+
+        /// Use the repository config file
+        #[arg(long, group = "scope")]
+        local: bool,
+
+        /// Use the given config file
+        #[arg(short, long, group = "scope", value_name = "FILE")]
+        file: Option<PathBuf>,
+
+        /// Get the last value of a variable
+        #[arg(long, group = "action")]
+        get: bool,
+
+        /// Get all values of a multi-valued variable
+        #[arg(long, group = "action")]
+        get_all: bool,
+
+        /// List all variables
+        #[arg(short, long, group = "action")]
+        list: bool,
+
+        /// Add a new line without altering existing values
+        #[arg(long, group = "action")]
+        add: bool,
+
+        /// Replace all the lines of a multi-valued variable
+        #[arg(long, group = "action")]
+        replace_all: bool,
+
+        /// Remove a variable
+        #[arg(long, group = "action")]
+        unset: bool,
+
+        /// Remove all the lines of a multi-valued variable
+        #[arg(long, group = "action")]
+        unset_all: bool,
+
+        /// Show the file each variable comes from when listing
+        #[arg(long)]
+        show_origin: bool,
+
+        /// Show the scope of each variable when listing
+        #[arg(long)]
+        show_scope: bool,
     },
     /// Clone a repository with HTTP protocol
     Clone {
@@ -74,40 +471,249 @@ enum Commands {
 fn main() {
     let cli = Cli::parse();
 
+    for dir in &cli.directory {
+        if let Err(err) = env::set_current_dir(dir) {
+            eprintln!("fatal: cannot change to '{}': {err}", dir.display());
+            process::exit(128);
+        }
+    }
+    // the options behave like the environment variables, pass them to the discovery that way
+    if let Some(git_dir) = &cli.git_dir {
+        env::set_var("GIT_DIR", git_dir);
+    }
+    if let Some(work_tree) = &cli.work_tree {
+        env::set_var("GIT_WORK_TREE", work_tree);
+    }
+
     match cli.command {
         Commands::Init => {
             if let Err(err) = subcommand::init() {
-                eprintln!("Git init failed with: {err}");
+                fail("init", err);
             }
         }
-        Commands::CatFile { pretty, hash } => match (pretty, hash) {
-            (false, _) => eprintln!("--pretty-print option is expected for cat-file subcommand"),
-            (true, hash) => {
-                if let Err(err) = subcommand::cat_file(&hash) {
-                    eprintln!("git cat-file failed with: {err}");
-                }
-            }
-        },
         Commands::HashObject { path, write } => {
-            let hash = subcommand::hash_object(&path, write);
+            // a repository is needed only for storing the object
+            let hash = write
+                .then(Repository::discover)
+                .transpose()
+                .and_then(|repo| subcommand::hash_object(repo.as_ref(), &path));
             if let Err(err) = hash {
-                eprintln!("git hash-object failed with: {err}");
+                fail("hash-object", err);
             } else {
                 println!("{}", hash.unwrap());
             }
         }
-        Commands::LsTree { name_only, hash } => match (name_only, hash) {
-            (false, _) => eprintln!("--name-only option is expected for ls-tree subcommand"),
-            (true, hash) => {
-                if let Err(err) = subcommand::ls_tree(&hash) {
-                    eprintln!("git ls-tree failed with: {err}");
-                }
+        Commands::Clone { url, dir, verbose } => {
+            let hash = subcommand::clone(&url, &dir, verbose);
+            if let Err(err) = hash {
+                fail("clone", err);
+            }
+        }
+        Commands::Config {
+            name,
+            value,
+            global,
+            system,
+            local,
+            file,
+            get,
+            get_all,
+            list,
+            add,
+            replace_all,
+            unset,
+            unset_all,
+            show_origin,
+            show_scope,
+        } => {
+            use config::{Edit, Scope};
+            use subcommand::ConfigAction;
+
+            let scope = [
+                (global, Scope::Global),
+                (system, Scope::System),
+                (local, Scope::Local),
+            ]
+            .into_iter()
+            .find_map(|(flag, scope)| flag.then_some(scope));
+            let action = match (get, get_all, list, add, replace_all, unset, unset_all) {
+                (true, ..) => ConfigAction::Get,
+                (_, true, ..) => ConfigAction::GetAll,
+                (_, _, true, ..) => ConfigAction::List {
+                    show_origin,
+                    show_scope,
+                },
+                (_, _, _, true, ..) => ConfigAction::Edit(Edit::Add),
+                (_, _, _, _, true, ..) => ConfigAction::Edit(Edit::ReplaceAll),
+                (_, _, _, _, _, true, _) => ConfigAction::Edit(Edit::Unset),
+                (_, _, _, _, _, _, true) => ConfigAction::Edit(Edit::UnsetAll),
+                _ if value.is_some() => ConfigAction::Edit(Edit::Set),
+                _ => ConfigAction::Get,
+            };
+            // the global and system files can be used outside of a repository
+            let repo = Repository::discover().ok();
+            match subcommand::config(
+                repo.as_ref(),
+                scope,
+                file.as_deref(),
+                action,
+                name.as_deref(),
+                value.as_deref(),
+            ) {
+                Ok(true) => {}
+                // a missing key is reported only through the exit status
+                Ok(false) => process::exit(1),
+                Err(err) => fail("config", err),
+            }
+        }
+        command => match Repository::discover() {
+            Ok(repo) => run(&repo, command),
+            Err(err) => {
+                eprintln!("fatal: {err}");
+                process::exit(128);
             }
         },
+    }
+}
+
+/// Reports a failed command and exits with the status git uses for fatal errors.
+fn fail(command: &str, err: anyhow::Error) -> ! {
+    eprintln!("git {command} failed with: {err}");
+    process::exit(128)
+}
+
+/// Runs the commands that operate on an existing repository.
+fn run(repo: &Repository, command: Commands) {
+    match command {
+        Commands::CatFile {
+            kind,
+            object,
+            pretty,
+            show_type,
+            size,
+            exists,
+            batch,
+            batch_check,
+            batch_command,
+            buffer,
+        } => {
+            let batch = match (batch, batch_check, batch_command) {
+                (Some(format), ..) => Some((subcommand::CatFileBatch::Contents, format)),
+                (_, Some(format), _) => Some((subcommand::CatFileBatch::Check, format)),
+                (.., Some(format)) => Some((subcommand::CatFileBatch::Command, format)),
+                _ => None,
+            };
+            if let Some((batch, format)) = batch {
+                if let Err(err) = subcommand::cat_file_batch(repo, batch, format.as_deref(), buffer)
+                {
+                    fail("cat-file", err);
+                }
+                return;
+            }
+
+            let kind = kind.expect("required without a batch mode");
+            let mode = match (pretty, show_type, size, exists) {
+                (true, ..) => Some(subcommand::CatFileMode::Pretty),
+                (_, true, ..) => Some(subcommand::CatFileMode::Type),
+                (_, _, true, _) => Some(subcommand::CatFileMode::Size),
+                (.., true) => Some(subcommand::CatFileMode::Exists),
+                _ => None,
+            };
+            let result = match (mode, object) {
+                (Some(mode), None) => subcommand::cat_file(repo, &kind, mode),
+                (None, Some(object)) => match ObjectKind::parse(kind.as_bytes()) {
+                    Ok(kind) => {
+                        subcommand::cat_file(repo, &object, subcommand::CatFileMode::Typed(kind))
+                    }
+                    Err(err) => Err(err),
+                },
+                (Some(_), Some(_)) => Err(anyhow::anyhow!("only one object is expected")),
+                (None, None) => Err(anyhow::anyhow!(
+                    "one of -p, -t, -s, -e or an object type is expected"
+                )),
+            };
+            match result {
+                Ok(true) => {}
+                // -e reports a missing object only through the exit status
+                Ok(false) => process::exit(1),
+                Err(err) => fail("cat-file", err),
+            }
+        }
+        Commands::LsTree {
+            tree,
+            paths,
+            recursive,
+            show_trees,
+            trees_only,
+            long,
+            null_terminated,
+            name_only,
+            abbrev,
+        } => {
+            let options = subcommand::LsTreeOptions {
+                recursive,
+                show_trees,
+                trees_only,
+                long,
+                null_terminated,
+                name_only,
+                abbrev,
+            };
+            if let Err(err) = subcommand::ls_tree(repo, &tree, &paths, &options) {
+                fail("ls-tree", err);
+            }
+        }
+        Commands::Add {
+            paths,
+            update,
+            all,
+            ignore_removal,
+            force,
+        } => {
+            // removals are staged by default, -A only extends that to the whole tree
+            let all = match (all, ignore_removal) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            };
+            if let Err(err) = subcommand::add(repo, &paths, update, all, force) {
+                fail("add", err);
+            }
+        }
+        Commands::Rm {
+            paths,
+            cached,
+            recursive,
+            force,
+        } => {
+            if let Err(err) = subcommand::rm(repo, &paths, cached, recursive, force) {
+                fail("rm", err);
+            }
+        }
+        Commands::Status { short, porcelain } => {
+            let format = match (short, porcelain.as_deref()) {
+                (_, Some("v1")) => subcommand::StatusFormat::Short,
+                (_, Some("v2")) => subcommand::StatusFormat::PorcelainV2,
+                (_, Some(version)) => fail(
+                    "status",
+                    anyhow::anyhow!("unsupported porcelain format: {version}"),
+                ),
+                (true, None) => subcommand::StatusFormat::Short,
+                (false, None) => subcommand::StatusFormat::Long,
+            };
+            if let Err(err) = subcommand::status(repo, format) {
+                fail("status", err);
+            }
+        }
+        Commands::LsFiles { stage } => {
+            if let Err(err) = subcommand::ls_files(repo, stage) {
+                fail("ls-files", err);
+            }
+        }
         Commands::WriteTree => {
-            let hash = subcommand::write_tree();
+            let hash = subcommand::write_tree(repo);
             if let Err(err) = hash {
-                eprintln!("git write-tree failed with: {err}");
+                fail("write-tree", err);
             } else {
                 println!("{}", hash.unwrap());
             }
@@ -116,19 +722,141 @@ fn main() {
             tree_object,
             parent,
             message,
+            file,
         } => {
-            let hash = subcommand::commit_tree(&tree_object, &parent, &message);
+            let hash = subcommand::commit_tree(repo, &tree_object, &parent, &message, &file);
             if let Err(err) = hash {
-                eprintln!("git commit-tree failed with: {err}");
+                fail("commit-tree", err);
             } else {
                 println!("{}", hash.unwrap());
             }
         }
-        Commands::Clone { url, dir, verbose } => {
-            let hash = subcommand::clone(&url, &dir, verbose);
-            if let Err(err) = hash {
-                eprintln!("git clone failed with: {err}");
+        Commands::Commit {
+            message,
+            file,
+            amend,
+            allow_empty,
+        } => {
+            if let Err(err) = subcommand::commit(repo, &message, &file, amend, allow_empty) {
+                fail("commit", err);
+            }
+        }
+        Commands::Log {
+            revisions,
+            max_count,
+            topo_order,
+            first_parent,
+            oneline,
+            format,
+        } => {
+            let options = revwalk::WalkOptions {
+                topo_order,
+                first_parent,
+                max_count,
+            };
+            let format = match (format, oneline) {
+                (Some(format), _) => pretty::Format::parse(&format),
+                (None, true) => Ok(pretty::Format::Oneline { abbrev: true }),
+                (None, false) => Ok(pretty::Format::Medium),
+            };
+            if let Err(err) =
+                format.and_then(|format| subcommand::log(repo, &revisions, options, &format))
+            {
+                fail("log", err);
             }
         }
+        Commands::RevParse {
+            args,
+            verify,
+            quiet,
+            short,
+            abbrev_ref,
+            symbolic_full_name,
+            git_dir,
+            show_toplevel,
+        } => {
+            let options = subcommand::RevParseOptions {
+                verify,
+                quiet,
+                short,
+                abbrev_ref,
+                symbolic_full_name,
+                git_dir,
+                show_toplevel,
+            };
+            match subcommand::rev_parse(repo, &args, &options) {
+                Ok(true) => {}
+                Ok(false) => process::exit(1),
+                Err(err) => fail("rev-parse", err),
+            }
+        }
+        Commands::UpdateRef {
+            reference,
+            new_value,
+            old_value,
+            delete,
+            no_deref,
+        } => {
+            // with -d the only value is the expected old one
+            let (new_value, old_value) = match delete {
+                true => (None, new_value),
+                false => (new_value, old_value),
+            };
+            if let Err(err) = subcommand::update_ref(
+                repo,
+                &reference,
+                new_value.as_deref(),
+                old_value.as_deref(),
+                delete,
+                no_deref,
+            ) {
+                fail("update-ref", err);
+            }
+        }
+        Commands::SymbolicRef {
+            name,
+            target,
+            delete,
+            quiet,
+            short,
+        } => {
+            if let Err(err) =
+                subcommand::symbolic_ref(repo, &name, target.as_deref(), delete, quiet, short)
+            {
+                fail("symbolic-ref", err);
+            }
+        }
+        Commands::ShowRef {
+            patterns,
+            head,
+            heads,
+            tags,
+            dereference,
+            hash,
+            verify,
+            quiet,
+        } => {
+            let options = subcommand::ShowRefOptions {
+                head,
+                heads,
+                tags,
+                dereference,
+                hash,
+                verify,
+                quiet,
+            };
+            match subcommand::show_ref(repo, &patterns, &options) {
+                Ok(true) => {}
+                // like git, report a missing ref only through the exit status
+                Ok(false) => process::exit(1),
+                Err(err) => fail("show-ref", err),
+            }
+        }
+        Commands::Init
+        | Commands::HashObject { .. }
+        | Commands::Config { .. }
+        | Commands::Clone { .. } => {
+            unreachable!("handled without a repository")
+        }
     }
 }
@@ -1,87 +1,615 @@
-use std::io::BufRead;
-
-use itertools::Itertools;
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{BufRead, Read};
+use std::str;
 
 pub type ShaHash = [u8; 20];
 
-pub struct BlobObject {
-    pub _size: usize,
-    pub content: String,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Blob,
+    Tree,
+    Commit,
+    Tag,
 }
 
-impl BlobObject {
-    pub fn read(input: &mut impl BufRead) -> anyhow::Result<Self> {
-        let mut prefix = [0u8; 5];
-        let _ = input.read_exact(&mut prefix);
-        if &prefix != b"blob " {
-            anyhow::bail!("Unexpected blob object start");
+impl ObjectKind {
+    pub fn parse(name: &[u8]) -> anyhow::Result<Self> {
+        match name {
+            b"blob" => Ok(ObjectKind::Blob),
+            b"tree" => Ok(ObjectKind::Tree),
+            b"commit" => Ok(ObjectKind::Commit),
+            b"tag" => Ok(ObjectKind::Tag),
+            _ => anyhow::bail!("Unknown object type: {}", String::from_utf8_lossy(name)),
         }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ObjectKind::Blob => "blob",
+            ObjectKind::Tree => "tree",
+            ObjectKind::Commit => "commit",
+            ObjectKind::Tag => "tag",
+        }
+    }
+}
+
+impl fmt::Display for ObjectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
-        let mut size = Vec::new();
-        input.read_until(b'\0', &mut size)?;
-        size.pop();
+/// Computes the object name of the given content.
+pub fn compute_hash(kind: ObjectKind, content: &[u8]) -> ShaHash {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{kind} {}\0", content.len()));
+    hasher.update(content);
+    hasher.finalize().into()
+}
 
-        let mut content = String::new();
-        input.read_to_string(&mut content)?;
+/// Reads the `<type> <size>\0` header of a loose object.
+pub fn read_header(input: &mut impl BufRead) -> anyhow::Result<(ObjectKind, usize)> {
+    let mut kind = Vec::new();
+    input.read_until(b' ', &mut kind)?;
+    if kind.pop() != Some(b' ') {
+        anyhow::bail!("Unexpected object start: {:?}", kind);
+    }
+    let kind = ObjectKind::parse(&kind)?;
 
-        let size = String::from_utf8(size)?.parse::<usize>()?;
-        if content.len() != size {
-            anyhow::bail!(format!(
-                "Blob content size {size}: does not match the actual content: {}",
-                content.len()
-            ))
+    let mut size = Vec::new();
+    input.read_until(b'\0', &mut size)?;
+    if size.pop() != Some(b'\0') {
+        anyhow::bail!("Missing NUL after object size");
+    }
+    let size = String::from_utf8(size)?.parse::<usize>()?;
+
+    Ok((kind, size))
+}
+
+pub enum Object {
+    Blob(BlobObject),
+    Tree(TreeObject),
+    Commit(CommitObject),
+    Tag(TagObject),
+}
+
+impl Object {
+    pub fn parse(kind: ObjectKind, content: &[u8]) -> anyhow::Result<Self> {
+        Ok(match kind {
+            ObjectKind::Blob => Object::Blob(BlobObject::parse(content)?),
+            ObjectKind::Tree => Object::Tree(TreeObject::parse(content)?),
+            ObjectKind::Commit => Object::Commit(CommitObject::parse(content)?),
+            ObjectKind::Tag => Object::Tag(TagObject::parse(content)?),
+        })
+    }
+
+    pub fn kind(&self) -> ObjectKind {
+        match self {
+            Object::Blob(_) => ObjectKind::Blob,
+            Object::Tree(_) => ObjectKind::Tree,
+            Object::Commit(_) => ObjectKind::Commit,
+            Object::Tag(_) => ObjectKind::Tag,
         }
+    }
+
+    /// Serializes the object content (without the loose object header).
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            Object::Blob(blob) => blob.serialize(),
+            Object::Tree(tree) => tree.serialize(),
+            Object::Commit(commit) => commit.serialize(),
+            Object::Tag(tag) => tag.serialize(),
+        }
+    }
+}
+
+pub struct BlobObject {
+    pub content: Vec<u8>,
+}
+
+impl BlobObject {
+    pub fn parse(content: &[u8]) -> anyhow::Result<Self> {
         Ok(Self {
-            _size: size,
-            content,
+            content: content.to_vec(),
         })
     }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        self.content.clone()
+    }
 }
 
 pub struct TreeItem {
-    pub _mode: String,
-    pub name: String,
-    pub _hash: ShaHash,
+    pub mode: u32,
+    /// Raw file name, which is not necessarily UTF-8.
+    pub name: Vec<u8>,
+    pub hash: ShaHash,
+}
+
+impl TreeItem {
+    /// The type of the object an entry points to, as implied by its mode.
+    pub fn kind(&self) -> ObjectKind {
+        match self.mode & 0o170000 {
+            0o040000 => ObjectKind::Tree,
+            0o160000 => ObjectKind::Commit,
+            _ => ObjectKind::Blob,
+        }
+    }
+
+    /// The mode git records for the entry: file permissions are reduced to `644`
+    /// or `755` and directories are written as `40000`.
+    fn canonical_mode(&self) -> u32 {
+        match self.mode & 0o170000 {
+            0o040000 => 0o40000,
+            0o120000 => 0o120000,
+            0o160000 => 0o160000,
+            _ if self.mode & 0o100 != 0 => 0o100755,
+            _ => 0o100644,
+        }
+    }
+
+    /// Rejects names that cannot be checked out safely, like git's `verify_path`:
+    /// empty names, `.`, `..`, `.git` in any case and names containing `/` or NUL.
+    pub fn verify_name(&self) -> anyhow::Result<()> {
+        let name = &self.name;
+        if name.is_empty()
+            || name == b"."
+            || name == b".."
+            || name.eq_ignore_ascii_case(b".git")
+            || name.contains(&b'/')
+            || name.contains(&b'\0')
+        {
+            anyhow::bail!(
+                "invalid tree entry name: '{}'",
+                String::from_utf8_lossy(name)
+            );
+        }
+        Ok(())
+    }
+
+    /// Entries are sorted by name as if trees had a trailing `/`.
+    fn sort_key(&self) -> Vec<u8> {
+        let mut key = self.name.clone();
+        if self.kind() == ObjectKind::Tree {
+            key.push(b'/');
+        }
+        key
+    }
 }
 
 pub struct TreeObject {
-    pub _size: usize,
     pub items: Vec<TreeItem>,
 }
 
 impl TreeObject {
-    pub fn read(input: &mut impl BufRead) -> anyhow::Result<Self> {
-        let mut prefix = [0u8; 5];
-        let _ = input.read_exact(&mut prefix);
-        if &prefix != b"tree " {
-            anyhow::bail!("Unexpected blob object start: {:?}", prefix);
-        }
-
-        let mut size = Vec::new();
-        input.read_until(b'\0', &mut size)?;
-        size.pop();
-        let size = String::from_utf8(size)?.parse::<usize>()?;
-
+    pub fn parse(mut content: &[u8]) -> anyhow::Result<Self> {
         let mut items = Vec::new();
         loop {
             let mut line = Vec::new();
-            let n = input.read_until(b'\0', &mut line)?;
+            let n = content.read_until(b'\0', &mut line)?;
             if n == 0 {
                 break;
             }
 
             line.pop();
-            let line = String::from_utf8(line)?;
-            let parts = line.split(' ').collect_vec();
+            let Some(space) = line.iter().position(|&c| c == b' ') else {
+                anyhow::bail!("Malformed tree entry: {}", String::from_utf8_lossy(&line));
+            };
+            let mode = str::from_utf8(&line[..space])
+                .ok()
+                .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+                .ok_or(anyhow::anyhow!(
+                    "Malformed tree entry mode: {}",
+                    String::from_utf8_lossy(&line)
+                ))?;
             let mut hash = ShaHash::default();
-            input.read_exact(&mut hash)?;
+            content.read_exact(&mut hash)?;
             items.push(TreeItem {
-                _mode: parts[0].to_owned(),
-                name: parts[1].to_owned(),
-                _hash: hash,
+                mode,
+                name: line[space + 1..].to_vec(),
+                hash,
             });
         }
 
-        Ok(Self { _size: size, items })
+        Ok(Self { items })
+    }
+
+    /// Serializes the entries in git's canonical order and mode format, so that
+    /// the same entries always give the same hash as with git.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut items = self.items.iter().collect::<Vec<_>>();
+        items.sort_by_cached_key(|item| item.sort_key());
+
+        let mut content = Vec::new();
+        for item in items {
+            content.extend(format!("{:o} ", item.canonical_mode()).as_bytes());
+            content.extend(&item.name);
+            content.extend(b"\0");
+            content.extend(&item.hash);
+        }
+        content
+    }
+
+    /// Checks that the entries have distinct names that are safe to check out.
+    /// Trees read from elsewhere get only this check, git accepts legacy trees
+    /// with unusual modes or order as well.
+    pub fn verify_entries(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+        for item in &self.items {
+            item.verify_name()?;
+            if !names.insert(&item.name) {
+                anyhow::bail!(
+                    "duplicate tree entry: '{}'",
+                    String::from_utf8_lossy(&item.name)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that tree content is canonical: it must serialize back to the same
+    /// bytes after parsing and its entries must pass `verify_entries`.
+    pub fn verify(content: &[u8]) -> anyhow::Result<()> {
+        let tree = Self::parse(content)?;
+        tree.verify_entries()?;
+        if tree.serialize() != content {
+            anyhow::bail!("tree entries are not in canonical order or mode format");
+        }
+        Ok(())
+    }
+}
+
+/// Identity line used by commits and tags: `Name <email> <timestamp> <timezone>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    pub timestamp: i64,
+    pub timezone: String,
+}
+
+impl Signature {
+    pub fn parse(line: &str) -> anyhow::Result<Self> {
+        let (name, rest) = line
+            .split_once('<')
+            .ok_or(anyhow::anyhow!("missing email in signature: {line}"))?;
+        let (email, rest) = rest
+            .split_once('>')
+            .ok_or(anyhow::anyhow!("unterminated email in signature: {line}"))?;
+        let (timestamp, timezone) = rest
+            .trim()
+            .split_once(' ')
+            .ok_or(anyhow::anyhow!("missing date in signature: {line}"))?;
+
+        Ok(Self {
+            name: name.trim_end().to_owned(),
+            email: email.to_owned(),
+            timestamp: timestamp.parse()?,
+            timezone: timezone.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} <{}> {} {}",
+            self.name, self.email, self.timestamp, self.timezone
+        )
+    }
+}
+
+/// A raw header of a commit or a tag.
+pub type Header = (Vec<u8>, Vec<u8>);
+
+/// Splits the header part of a commit or a tag into raw key-value pairs in their
+/// original order and returns the message that follows the first empty line.
+/// Continuation lines (starting with a space) are joined with a newline.
+fn parse_headers(content: &[u8]) -> anyhow::Result<(Vec<Header>, &[u8])> {
+    let (headers, message) = match content.windows(2).position(|w| w == b"\n\n") {
+        Some(pos) => (&content[..pos], &content[pos + 2..]),
+        None => (content.strip_suffix(b"\n").unwrap_or(content), &[][..]),
+    };
+
+    let mut result: Vec<Header> = Vec::new();
+    for line in headers.split(|&c| c == b'\n') {
+        if let Some(continuation) = line.strip_prefix(b" ") {
+            let (_, value) = result
+                .last_mut()
+                .ok_or(anyhow::anyhow!("continuation line without a header"))?;
+            value.push(b'\n');
+            value.extend_from_slice(continuation);
+        } else {
+            let (key, value) = match line.iter().position(|&c| c == b' ') {
+                Some(space) => (&line[..space], &line[space + 1..]),
+                None => (line, &[][..]),
+            };
+            result.push((key.to_vec(), value.to_vec()));
+        }
+    }
+
+    Ok((result, message))
+}
+
+fn write_header(content: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    content.extend_from_slice(key);
+    content.push(b' ');
+    for line in value.split_inclusive(|&c| c == b'\n') {
+        content.extend_from_slice(line);
+        if line.ends_with(b"\n") {
+            content.push(b' ');
+        }
+    }
+    content.push(b'\n');
+}
+
+fn header_str<'a>(key: &str, value: &'a [u8]) -> anyhow::Result<&'a str> {
+    str::from_utf8(value).with_context(|| anyhow::anyhow!("invalid {key} header"))
+}
+
+pub fn parse_hash(value: &str) -> anyhow::Result<ShaHash> {
+    let mut hash = ShaHash::default();
+    hex::decode_to_slice(value, &mut hash)
+        .with_context(|| anyhow::anyhow!("invalid object hash: {value}"))?;
+    Ok(hash)
+}
+
+pub struct CommitObject {
+    pub tree: ShaHash,
+    pub parents: Vec<ShaHash>,
+    pub author: Signature,
+    pub committer: Signature,
+    /// Every header (including `encoding`, `mergetag` and `gpgsig`) as raw bytes in
+    /// its original order, the fields above are parsed from these.
+    pub headers: Vec<Header>,
+    pub message: Vec<u8>,
+}
+
+impl CommitObject {
+    pub fn new(
+        tree: ShaHash,
+        parents: Vec<ShaHash>,
+        author: Signature,
+        committer: Signature,
+        message: String,
+    ) -> Self {
+        let mut headers = vec![(b"tree".to_vec(), hex::encode(tree).into_bytes())];
+        for parent in &parents {
+            headers.push((b"parent".to_vec(), hex::encode(parent).into_bytes()));
+        }
+        headers.push((b"author".to_vec(), author.to_string().into_bytes()));
+        headers.push((b"committer".to_vec(), committer.to_string().into_bytes()));
+        Self {
+            tree,
+            parents,
+            author,
+            committer,
+            headers,
+            message: message.into_bytes(),
+        }
+    }
+
+    pub fn parse(content: &[u8]) -> anyhow::Result<Self> {
+        let (headers, message) = parse_headers(content)?;
+
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        for (key, value) in &headers {
+            match key.as_slice() {
+                b"tree" => tree = Some(parse_hash(header_str("tree", value)?)?),
+                b"parent" => parents.push(parse_hash(header_str("parent", value)?)?),
+                // names may be in the commit's `encoding`
+                b"author" => author = Some(Signature::parse(&String::from_utf8_lossy(value))?),
+                b"committer" => {
+                    committer = Some(Signature::parse(&String::from_utf8_lossy(value))?)
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            tree: tree.ok_or(anyhow::anyhow!("commit without a tree"))?,
+            parents,
+            author: author.ok_or(anyhow::anyhow!("commit without an author"))?,
+            committer: committer.ok_or(anyhow::anyhow!("commit without a committer"))?,
+            headers,
+            message: message.to_vec(),
+        })
+    }
+
+    /// Writes the headers and the message back verbatim.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut content = Vec::new();
+        for (key, value) in &self.headers {
+            write_header(&mut content, key, value);
+        }
+        content.push(b'\n');
+        content.extend_from_slice(&self.message);
+        content
+    }
+}
+
+pub struct TagObject {
+    pub object: ShaHash,
+    /// Every header as raw bytes in its original order, like `CommitObject::headers`.
+    pub headers: Vec<Header>,
+    pub message: Vec<u8>,
+}
+
+impl TagObject {
+    pub fn parse(content: &[u8]) -> anyhow::Result<Self> {
+        let (headers, message) = parse_headers(content)?;
+
+        let mut object = None;
+        let mut kind = None;
+        let mut tag = false;
+        for (key, value) in &headers {
+            match key.as_slice() {
+                b"object" => object = Some(parse_hash(header_str("object", value)?)?),
+                b"type" => kind = Some(ObjectKind::parse(value)?),
+                b"tag" => tag = true,
+                b"tagger" => {
+                    Signature::parse(&String::from_utf8_lossy(value))?;
+                }
+                _ => {}
+            }
+        }
+        kind.ok_or(anyhow::anyhow!("tag without a type"))?;
+        if !tag {
+            anyhow::bail!("tag without a name");
+        }
+
+        Ok(Self {
+            object: object.ok_or(anyhow::anyhow!("tag without an object"))?,
+            headers,
+            message: message.to_vec(),
+        })
+    }
+
+    /// Writes the headers and the message back verbatim.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut content = Vec::new();
+        for (key, value) in &self.headers {
+            write_header(&mut content, key, value);
+        }
+        content.push(b'\n');
+        content.extend_from_slice(&self.message);
+        content
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsafe_tree_entry_names() {
+        let item = |name: &[u8]| TreeItem {
+            mode: 0o100644,
+            name: name.to_vec(),
+            hash: ShaHash::default(),
+        };
+        for name in [&b""[..], b".", b"..", b".git", b".GiT", b"a/b", b"a\0b"] {
+            assert!(item(name).verify_name().is_err(), "{name:?}");
+        }
+        for name in [&b".gitignore"[..], b"...", b"git", b"\xff"] {
+            item(name).verify_name().unwrap();
+        }
+    }
+
+    #[test]
+    fn canonical_tree_order() {
+        let hash = |hex: &str| parse_hash(hex).unwrap();
+        // a file sorts before a directory with the same name followed by `/`
+        let tree = TreeObject {
+            items: vec![
+                TreeItem {
+                    mode: 0o40000,
+                    name: b"foo".to_vec(),
+                    hash: hash("65264ea34144797275c83285a111a0c6fe7d8398"),
+                },
+                TreeItem {
+                    mode: 0o100644,
+                    name: b"foo.txt".to_vec(),
+                    hash: hash("78981922613b2afb6025042ff6bd878ac1994e85"),
+                },
+            ],
+        };
+        let content = tree.serialize();
+        assert_eq!(
+            compute_hash(ObjectKind::Tree, &content),
+            hash("696ea965378ddbe8402069ae5991be599427f813")
+        );
+        TreeObject::verify(&content).unwrap();
+
+        let mut reversed = TreeObject::parse(&content).unwrap();
+        reversed.items.reverse();
+        let mut unsorted = Vec::new();
+        for item in &reversed.items {
+            unsorted.extend(format!("{:o} ", item.mode).as_bytes());
+            unsorted.extend(&item.name);
+            unsorted.push(0);
+            unsorted.extend(item.hash);
+        }
+        assert!(TreeObject::verify(&unsorted).is_err());
+        TreeObject::parse(&unsorted)
+            .unwrap()
+            .verify_entries()
+            .unwrap();
+    }
+
+    #[test]
+    fn legacy_trees_pass_entry_checks() {
+        let tree = |entries: &[(&str, &str)]| {
+            let mut content = Vec::new();
+            for (mode, name) in entries {
+                content.extend(format!("{mode} {name}\0").as_bytes());
+                content.extend([0x11; 20]);
+            }
+            content
+        };
+        let legacy = tree(&[("100664", "b"), ("040000", "a")]);
+        TreeObject::parse(&legacy)
+            .unwrap()
+            .verify_entries()
+            .unwrap();
+        assert!(TreeObject::verify(&legacy).is_err());
+
+        // a duplicate name fails either way
+        let duplicate = tree(&[("100644", "a"), ("100644", "a")]);
+        let parsed = TreeObject::parse(&duplicate).unwrap();
+        assert!(parsed.verify_entries().is_err());
+    }
+
+    #[test]
+    fn tag_headers_round_trip() {
+        let content = b"object 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            type tree\n\
+            tag v1\n\
+            tagger J\xf6rg <j@x> 1700000000 +0100\n\
+            x-extra one\n two\n\
+            \n\
+            Gr\xfc\xdfe\n";
+        let tag = TagObject::parse(content).unwrap();
+        assert_eq!(
+            tag.object,
+            parse_hash("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap()
+        );
+        assert_eq!(tag.headers[4], (b"x-extra".to_vec(), b"one\ntwo".to_vec()));
+        assert_eq!(tag.message, b"Gr\xfc\xdfe\n");
+        assert_eq!(tag.serialize(), content);
+    }
+
+    #[test]
+    fn commit_headers_round_trip() {
+        let content = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            author J\xf6rg <j@x> 1700000000 +0100\n\
+            committer J\xf6rg <j@x> 1700000000 +0100\n\
+            encoding ISO-8859-1\n\
+            gpgsig -----BEGIN PGP SIGNATURE-----\n \n abc\n -----END PGP SIGNATURE-----\n\
+            x-extra value\n\
+            \n\
+            Gr\xfc\xdfe\n";
+        let commit = CommitObject::parse(content).unwrap();
+        assert_eq!(commit.author.email, "j@x");
+        assert_eq!(
+            commit.headers[3],
+            (b"encoding".to_vec(), b"ISO-8859-1".to_vec())
+        );
+        assert_eq!(
+            commit.headers[4].1,
+            b"-----BEGIN PGP SIGNATURE-----\n\nabc\n-----END PGP SIGNATURE-----"
+        );
+        assert_eq!(commit.serialize(), content);
+        assert_eq!(
+            compute_hash(ObjectKind::Commit, &commit.serialize()),
+            parse_hash("c64f917c00b261f55a3eaec23b2fd74fccddd1c2").unwrap()
+        );
     }
 }
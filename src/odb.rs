@@ -0,0 +1,326 @@
+use anyhow::Context;
+use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use sha1::{Digest, Sha1};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::object::{read_header, Object, ObjectKind, ShaHash, TreeObject};
+use crate::pack::{apply_delta, EntryKind, Pack, MAX_PREALLOCATION};
+
+/// Longest delta chain that is resolved, far beyond what git produces.
+const MAX_DELTA_DEPTH: usize = 10_000;
+
+/// Object storage consisting of loose objects and packs in `.git/objects`.
+pub struct ObjectDatabase {
+    objects_dir: PathBuf,
+    packs: Vec<Pack>,
+}
+
+impl ObjectDatabase {
+    pub fn open(objects_dir: &Path) -> anyhow::Result<Self> {
+        let mut packs = Vec::new();
+        let pack_dir = objects_dir.join("pack");
+        if pack_dir.is_dir() {
+            let mut indices = fs::read_dir(&pack_dir)?
+                .map(|res| res.map(|e| e.path()))
+                .collect::<Result<Vec<_>, io::Error>>()?;
+            indices.retain(|path| path.extension().is_some_and(|ext| ext == "idx"));
+            indices.sort();
+            for index in indices {
+                packs
+                    .push(Pack::open(&index).with_context(|| {
+                        anyhow::anyhow!("cannot open pack {}", index.display())
+                    })?);
+            }
+        }
+
+        Ok(Self {
+            objects_dir: objects_dir.to_owned(),
+            packs,
+        })
+    }
+
+    fn loose_path(&self, hash: &ShaHash) -> PathBuf {
+        let hash = hex::encode(hash);
+        self.objects_dir.join(&hash[0..2]).join(&hash[2..])
+    }
+
+    pub fn contains(&self, hash: &ShaHash) -> bool {
+        self.loose_path(hash).exists() || self.packs.iter().any(|p| p.index.lookup(hash).is_some())
+    }
+
+    /// Finds the objects whose hex name starts with `prefix`, which has at least two digits.
+    pub fn find_prefix(&self, prefix: &str) -> anyhow::Result<Vec<ShaHash>> {
+        let prefix = prefix.to_ascii_lowercase();
+        let mut found = Vec::new();
+
+        let dir = self.objects_dir.join(&prefix[..2]);
+        if dir.is_dir() {
+            for entry in fs::read_dir(dir)? {
+                let name = format!("{}{}", &prefix[..2], entry?.file_name().to_string_lossy());
+                let mut hash = ShaHash::default();
+                if name.starts_with(&prefix) && hex::decode_to_slice(&name, &mut hash).is_ok() {
+                    found.push(hash);
+                }
+            }
+        }
+        for pack in &self.packs {
+            found.extend(pack.index.find_prefix(&prefix));
+        }
+
+        found.sort();
+        found.dedup();
+        Ok(found)
+    }
+
+    /// Shortest unique hex prefix of an object name, at least `min_len` digits long.
+    pub fn abbreviate(&self, hash: &ShaHash, min_len: usize) -> anyhow::Result<String> {
+        let hex = hex::encode(hash);
+        for len in min_len.clamp(4, hex.len())..hex.len() {
+            if self.find_prefix(&hex[..len])?.len() <= 1 {
+                return Ok(hex[..len].to_owned());
+            }
+        }
+        Ok(hex)
+    }
+
+    /// Opens an object for streaming, loose objects are not buffered in memory.
+    pub fn open_stream(
+        &self,
+        hash: &ShaHash,
+    ) -> anyhow::Result<(ObjectKind, usize, Box<dyn BufRead>)> {
+        let path = self.loose_path(hash);
+        if path.exists() {
+            let mut reader = BufReader::new(ZlibDecoder::new(BufReader::new(File::open(path)?)));
+            let (kind, size) = read_header(&mut reader)?;
+            return Ok((kind, size, Box::new(reader)));
+        }
+
+        let (kind, content) = self.read_raw(hash)?;
+        Ok((kind, content.len(), Box::new(Cursor::new(content))))
+    }
+
+    /// Reads the object type and its content, looking at loose objects first and packs then.
+    pub fn read_raw(&self, hash: &ShaHash) -> anyhow::Result<(ObjectKind, Vec<u8>)> {
+        self.read_raw_at_depth(hash, 0)
+    }
+
+    /// `depth` counts the deltas already waiting for this object as their base.
+    fn read_raw_at_depth(
+        &self,
+        hash: &ShaHash,
+        depth: usize,
+    ) -> anyhow::Result<(ObjectKind, Vec<u8>)> {
+        let path = self.loose_path(hash);
+        if path.exists() {
+            let mut reader = BufReader::new(ZlibDecoder::new(BufReader::new(File::open(path)?)));
+            let (kind, size) = read_header(&mut reader)?;
+            let mut content = Vec::with_capacity(size.min(MAX_PREALLOCATION));
+            reader.read_to_end(&mut content)?;
+            if content.len() != size {
+                anyhow::bail!(
+                    "Object content size {size}: does not match the actual content: {}",
+                    content.len()
+                );
+            }
+            return Ok((kind, content));
+        }
+
+        for pack in &self.packs {
+            if let Some(offset) = pack.index.lookup(hash) {
+                return self
+                    .read_packed(pack, offset, depth)
+                    .with_context(|| anyhow::anyhow!("cannot read {}", pack.path.display()));
+            }
+        }
+
+        anyhow::bail!("object not found: {}", hex::encode(hash))
+    }
+
+    /// Reads a packed object and resolves its delta chain, which may continue
+    /// in another pack or with a loose object.
+    fn read_packed(
+        &self,
+        pack: &Pack,
+        offset: u64,
+        depth: usize,
+    ) -> anyhow::Result<(ObjectKind, Vec<u8>)> {
+        let mut deltas = Vec::new();
+        let mut offset = offset;
+        let (kind, mut content) = loop {
+            // a chain this long is a cycle of REF_DELTA entries
+            if depth + deltas.len() > MAX_DELTA_DEPTH {
+                anyhow::bail!("delta chain at offset {offset} is longer than {MAX_DELTA_DEPTH}");
+            }
+            let entry = pack.read_entry(offset)?;
+            match entry.kind {
+                EntryKind::Base(kind) => break (kind, entry.data),
+                EntryKind::OfsDelta { base_offset } => {
+                    deltas.push(entry.data);
+                    offset = base_offset;
+                }
+                EntryKind::RefDelta { base } => {
+                    deltas.push(entry.data);
+                    match pack.index.lookup(&base) {
+                        Some(base_offset) => offset = base_offset,
+                        None => break self.read_raw_at_depth(&base, depth + deltas.len())?,
+                    }
+                }
+            }
+        };
+
+        for delta in deltas.iter().rev() {
+            content = apply_delta(&content, delta)?;
+        }
+        Ok((kind, content))
+    }
+
+    pub fn read(&self, hash: &ShaHash) -> anyhow::Result<Object> {
+        let (kind, content) = self.read_raw(hash)?;
+        Object::parse(kind, &content)
+    }
+
+    pub fn write(&self, kind: ObjectKind, content: &[u8]) -> anyhow::Result<ShaHash> {
+        self.write_stream(kind, content.len() as u64, &mut &content[..])
+    }
+
+    /// Stores a tree after checking that it round-trips to the same canonical content.
+    pub fn write_tree(&self, tree: &TreeObject) -> anyhow::Result<ShaHash> {
+        let content = tree.serialize();
+        TreeObject::verify(&content)?;
+        self.write(ObjectKind::Tree, &content)
+    }
+
+    /// Stores an object of the given size as a loose object. The content is streamed
+    /// into a temporary file that is renamed once the hash is known.
+    pub fn write_stream(
+        &self,
+        kind: ObjectKind,
+        size: u64,
+        reader: &mut dyn Read,
+    ) -> anyhow::Result<ShaHash> {
+        let temporary = self
+            .objects_dir
+            .join(format!("tmp_obj_{}", std::process::id()));
+        let mut encoder = ZlibEncoder::new(
+            BufWriter::new(File::create(&temporary)?),
+            Compression::fast(),
+        );
+        let mut hasher = Sha1::new();
+
+        let header = format!("{kind} {size}\0");
+        hasher.update(&header);
+        encoder.write_all(header.as_bytes())?;
+
+        let mut written = 0u64;
+        let mut buffer = [0u8; 8192];
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            encoder.write_all(&buffer[..n])?;
+            written += n as u64;
+        }
+        encoder.finish()?.flush()?;
+
+        if written != size {
+            fs::remove_file(&temporary)?;
+            anyhow::bail!("Object size {size}: does not match the actual content: {written}");
+        }
+
+        let hash: ShaHash = hasher.finalize().into();
+        if self.contains(&hash) {
+            fs::remove_file(&temporary)?;
+            return Ok(hash);
+        }
+        let object_path = self.loose_path(&hash);
+        if let Some(folder) = object_path.parent() {
+            if !folder.exists() {
+                fs::create_dir(folder)?;
+            }
+        }
+        fs::rename(&temporary, &object_path)?;
+
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// Writes a pack whose entries are REF_DELTAs against the given bases, named
+    /// with the given hashes in the index.
+    fn write_ref_delta_pack(objects_dir: &Path, entries: &[(ShaHash, ShaHash)]) {
+        let delta = b"\x01\x01\x01x";
+        let mut pack = b"PACK\0\0\0\x02".to_vec();
+        pack.extend((entries.len() as u32).to_be_bytes());
+        let mut offsets = Vec::new();
+        for (_, base) in entries {
+            offsets.push(pack.len() as u32);
+            pack.push((7 << 4) | delta.len() as u8);
+            pack.extend(base);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(delta).unwrap();
+            pack.extend(encoder.finish().unwrap());
+        }
+
+        let mut sorted = entries.iter().zip(offsets).collect::<Vec<_>>();
+        sorted.sort_by_key(|((name, _), _)| *name);
+        let mut idx = b"\xfftOc\0\0\0\x02".to_vec();
+        for byte in 0..=255u8 {
+            let count = sorted
+                .iter()
+                .filter(|((name, _), _)| name[0] <= byte)
+                .count();
+            idx.extend((count as u32).to_be_bytes());
+        }
+        for ((name, _), _) in &sorted {
+            idx.extend(name);
+        }
+        idx.extend(vec![0; sorted.len() * 4]);
+        for (_, offset) in &sorted {
+            idx.extend(offset.to_be_bytes());
+        }
+        idx.extend([0; 40]);
+
+        let pack_dir = objects_dir.join("pack");
+        fs::create_dir_all(&pack_dir).unwrap();
+        fs::write(pack_dir.join("pack-test.pack"), pack).unwrap();
+        fs::write(pack_dir.join("pack-test.idx"), idx).unwrap();
+    }
+
+    #[test]
+    fn ref_delta_cycle() {
+        let dir = TempDir::new();
+        let (a, b) = ([0xaa; 20], [0xbb; 20]);
+        write_ref_delta_pack(dir.path(), &[(a, b), (b, a)]);
+
+        let db = ObjectDatabase::open(dir.path()).unwrap();
+        assert!(db.contains(&a));
+        let err = db.read_raw(&a).unwrap_err();
+        assert!(format!("{err:#}").contains("delta chain"), "{err:#}");
+    }
+
+    #[test]
+    fn loose_objects() {
+        let dir = TempDir::new();
+        let db = ObjectDatabase::open(dir.path()).unwrap();
+        let hash = db.write(ObjectKind::Blob, b"hello\n").unwrap();
+        assert_eq!(
+            hex::encode(hash),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+        assert_eq!(
+            db.read_raw(&hash).unwrap(),
+            (ObjectKind::Blob, b"hello\n".to_vec())
+        );
+        assert_eq!(db.find_prefix("ce01").unwrap(), [hash]);
+    }
+}
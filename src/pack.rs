@@ -0,0 +1,413 @@
+use flate2::bufread::ZlibDecoder;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::object::{ObjectKind, ShaHash};
+
+const IDX_MAGIC: &[u8; 4] = b"\xfftOc";
+const PACK_MAGIC: &[u8; 4] = b"PACK";
+/// Sizes read from object headers are not trusted further than this when
+/// reserving memory, the actual length is checked once the data is read.
+pub const MAX_PREALLOCATION: usize = 1 << 20;
+
+pub struct ObjectSize(pub usize);
+
+impl ObjectSize {
+    pub fn try_parse(reader: &mut dyn Read) -> anyhow::Result<ObjectSize> {
+        let mut size = 0usize;
+        let mut bitcount = 0usize;
+
+        loop {
+            let mut v = [0u8; 1];
+            reader.read_exact(&mut v)?;
+            let tmp = (v[0] & 0b0111_1111) as usize;
+            size |= tmp
+                .checked_shl(bitcount as u32)
+                .filter(|shifted| shifted >> bitcount == tmp)
+                .ok_or(anyhow::anyhow!(
+                    "object size does not fit in {bitcount} bits"
+                ))?;
+            bitcount += 7;
+
+            if v[0] >> 7 == 0 {
+                break;
+            }
+        }
+
+        Ok(ObjectSize(size))
+    }
+}
+
+pub struct ObjectSizeType {
+    pub size: ObjectSize,
+    pub object_type: u8,
+}
+
+impl ObjectSizeType {
+    pub fn try_parse(reader: &mut dyn Read) -> anyhow::Result<ObjectSizeType> {
+        let mut size = ObjectSize::try_parse(reader)?;
+        let object_type = ((size.0 >> 4) & 0b111) as u8;
+
+        // we need to preserve lowest 4 bits before we remove bits 5,6 and 7 by shifting
+        let lower = size.0 & 0b1111;
+        size.0 >>= 7;
+        size.0 <<= 4;
+        size.0 += lower;
+
+        Ok(ObjectSizeType { size, object_type })
+    }
+}
+
+/// Reader keeping track of the position in a pack stream that cannot be seeked.
+pub struct CountingReader<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R: BufRead> CountingReader<R> {
+    pub fn new(inner: R, offset: u64) -> Self {
+        Self { inner, offset }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<R: BufRead> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.offset += amt as u64;
+    }
+}
+
+/// Parses the negative offset of an OFS_DELTA entry relative to the entry start.
+pub fn parse_delta_offset(reader: &mut dyn Read) -> anyhow::Result<u64> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    let mut offset = (byte[0] & 0b0111_1111) as u64;
+    while byte[0] >> 7 != 0 {
+        reader.read_exact(&mut byte)?;
+        offset = offset
+            .checked_add(1)
+            .filter(|offset| offset.leading_zeros() >= 7)
+            .ok_or(anyhow::anyhow!("variable length integer overflows 64 bits"))?;
+        offset = (offset << 7) | (byte[0] & 0b0111_1111) as u64;
+    }
+
+    Ok(offset)
+}
+
+/// Parses the offset of an OFS_DELTA entry and returns the offset of its base,
+/// which must be an earlier entry.
+pub fn parse_base_offset(reader: &mut dyn Read, entry_offset: u64) -> anyhow::Result<u64> {
+    match parse_delta_offset(reader)? {
+        0 => anyhow::bail!("OFS_DELTA at {entry_offset} is its own base"),
+        offset if offset >= entry_offset => {
+            anyhow::bail!("OFS_DELTA at {entry_offset} points before the first entry")
+        }
+        offset => Ok(entry_offset - offset),
+    }
+}
+
+#[derive(Debug)]
+enum CopyCommand {
+    FromReference { offset: usize, size: usize },
+    Direct { data: Vec<u8> },
+}
+
+impl CopyCommand {
+    fn try_parse(reader: &mut dyn Read) -> anyhow::Result<CopyCommand> {
+        let mut header = [0u8; 1];
+        reader.read_exact(&mut header)?;
+        let header = header[0];
+
+        match header >> 7 {
+            0 => {
+                let size = header & 0b0111_1111;
+                if size == 0 {
+                    anyhow::bail!("reserved delta instruction 0");
+                }
+                let mut data = vec![0u8; size as usize];
+                reader.read_exact(&mut data)?;
+                Ok(CopyCommand::Direct { data })
+            }
+            1 => {
+                let mut buffer = [0u8; 1];
+                let mut offset = 0;
+                let mut size = 0;
+
+                for i in 0..4 {
+                    if header & (1u8 << i) != 0 {
+                        reader.read_exact(&mut buffer)?;
+                        offset += (buffer[0] as usize) << (8 * i);
+                    }
+                }
+
+                for i in 0..3 {
+                    if header & (1u8 << (i + 4)) != 0 {
+                        reader.read_exact(&mut buffer)?;
+                        size += (buffer[0] as usize) << (8 * i);
+                    }
+                }
+
+                if size == 0 {
+                    size = 0x10000;
+                }
+
+                Ok(CopyCommand::FromReference { offset, size })
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Reconstructs an object from its `base` and a delta produced against it.
+pub fn apply_delta(base: &[u8], mut delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let base_size = ObjectSize::try_parse(&mut delta)?.0;
+    if base_size != base.len() {
+        anyhow::bail!(
+            "Delta base size {base_size}: does not match the actual base: {}",
+            base.len()
+        );
+    }
+    let final_size = ObjectSize::try_parse(&mut delta)?.0;
+
+    let mut result = Vec::with_capacity(final_size.min(MAX_PREALLOCATION));
+    while !delta.is_empty() {
+        match CopyCommand::try_parse(&mut delta)? {
+            CopyCommand::FromReference { offset, size } => {
+                let data = base
+                    .get(offset..offset + size)
+                    .ok_or(anyhow::anyhow!("delta copies data outside of the base"))?;
+                result.extend_from_slice(data);
+            }
+            CopyCommand::Direct { data } => result.extend(data),
+        }
+    }
+
+    if result.len() != final_size {
+        anyhow::bail!(
+            "Delta result size {final_size}: does not match the actual content: {}",
+            result.len()
+        );
+    }
+    Ok(result)
+}
+
+/// Type of a packed object, delta entries point to their base.
+pub enum EntryKind {
+    Base(ObjectKind),
+    OfsDelta { base_offset: u64 },
+    RefDelta { base: ShaHash },
+}
+
+pub struct PackEntry {
+    pub kind: EntryKind,
+    /// Object content for base entries, delta instructions otherwise.
+    pub data: Vec<u8>,
+}
+
+pub fn object_kind_from_pack_type(object_type: u8) -> anyhow::Result<ObjectKind> {
+    Ok(match object_type {
+        1 => ObjectKind::Commit,
+        2 => ObjectKind::Tree,
+        3 => ObjectKind::Blob,
+        4 => ObjectKind::Tag,
+        _ => anyhow::bail!("invalid pack object type: {object_type}"),
+    })
+}
+
+/// Version 2 pack index (`.idx`) mapping object names to pack offsets.
+pub struct PackIndex {
+    names: Vec<ShaHash>,
+    offsets: Vec<u64>,
+}
+
+impl PackIndex {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(path)?;
+        let u32_at = |pos: usize| -> anyhow::Result<u32> {
+            let bytes = data
+                .get(pos..pos + 4)
+                .ok_or(anyhow::anyhow!("truncated pack index: {}", path.display()))?;
+            Ok(u32::from_be_bytes(bytes.try_into()?))
+        };
+
+        if data.get(0..4) != Some(IDX_MAGIC) || u32_at(4)? != 2 {
+            anyhow::bail!("unsupported pack index version: {}", path.display());
+        }
+
+        let fanout_start = 8;
+        let count = u32_at(fanout_start + 255 * 4)? as usize;
+        let names_start = fanout_start + 256 * 4;
+        let offsets_start = names_start + count * 20 + count * 4;
+        let large_offsets_start = offsets_start + count * 4;
+        // the count must be backed by the file before it is used to reserve memory
+        if data.len() < large_offsets_start + 40 {
+            anyhow::bail!("truncated pack index: {}", path.display());
+        }
+
+        let mut names = Vec::with_capacity(count);
+        for i in 0..count {
+            let pos = names_start + i * 20;
+            let name = data
+                .get(pos..pos + 20)
+                .ok_or(anyhow::anyhow!("truncated pack index: {}", path.display()))?;
+            names.push(name.try_into()?);
+        }
+
+        let mut offsets = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = u32_at(offsets_start + i * 4)?;
+            if offset & 0x8000_0000 != 0 {
+                let pos = large_offsets_start + (offset & 0x7fff_ffff) as usize * 8;
+                let large = data
+                    .get(pos..pos + 8)
+                    .ok_or(anyhow::anyhow!("truncated pack index: {}", path.display()))?;
+                offsets.push(u64::from_be_bytes(large.try_into()?));
+            } else {
+                offsets.push(offset as u64);
+            }
+        }
+
+        Ok(Self { names, offsets })
+    }
+
+    pub fn lookup(&self, hash: &ShaHash) -> Option<u64> {
+        self.names
+            .binary_search(hash)
+            .ok()
+            .map(|index| self.offsets[index])
+    }
+
+    /// Object names starting with a hex prefix.
+    pub fn find_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a ShaHash> {
+        let mut lowest = ShaHash::default();
+        let padded = format!("{prefix:0<40}");
+        // an invalid prefix starts the search at the end so that nothing matches
+        let start = match hex::decode_to_slice(padded, &mut lowest) {
+            Ok(()) => self.names.partition_point(|name| *name < lowest),
+            Err(_) => self.names.len(),
+        };
+        self.names[start..]
+            .iter()
+            .take_while(move |name| hex::encode(name).starts_with(prefix))
+    }
+}
+
+pub struct Pack {
+    pub path: PathBuf,
+    pub index: PackIndex,
+    file: File,
+}
+
+impl Pack {
+    /// Opens a pack based on the path of its `.idx` file.
+    pub fn open(idx_path: &Path) -> anyhow::Result<Self> {
+        let index = PackIndex::read(idx_path)?;
+        let path = idx_path.with_extension("pack");
+        let mut file = File::open(&path)?;
+
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        if &header[0..4] != PACK_MAGIC || u32::from_be_bytes(header[4..8].try_into()?) != 2 {
+            anyhow::bail!("unsupported pack file: {}", path.display());
+        }
+
+        Ok(Self { path, index, file })
+    }
+
+    /// Reads a single entry without resolving deltas.
+    pub fn read_entry(&self, offset: u64) -> anyhow::Result<PackEntry> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+
+        let ObjectSizeType { size, object_type } = ObjectSizeType::try_parse(&mut reader)?;
+        let kind = match object_type {
+            6 => EntryKind::OfsDelta {
+                base_offset: parse_base_offset(&mut reader, offset)?,
+            },
+            7 => {
+                let mut base = ShaHash::default();
+                reader.read_exact(&mut base)?;
+                EntryKind::RefDelta { base }
+            }
+            _ => EntryKind::Base(object_kind_from_pack_type(object_type)?),
+        };
+
+        let mut data = Vec::with_capacity(size.0.min(MAX_PREALLOCATION));
+        ZlibDecoder::new(reader).read_to_end(&mut data)?;
+        if data.len() != size.0 {
+            anyhow::bail!(
+                "Pack entry size {}: does not match the actual content: {}",
+                size.0,
+                data.len()
+            );
+        }
+
+        Ok(PackEntry { kind, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_size_and_type() {
+        // blob of 300 bytes: type 3, size 0b1_0010_1100
+        let parsed = ObjectSizeType::try_parse(&mut &[0b1011_1100, 0b0001_0010][..]).unwrap();
+        assert_eq!(parsed.object_type, 3);
+        assert_eq!(parsed.size.0, 300);
+        assert!(ObjectSize::try_parse(&mut &[0xff; 11][..]).is_err());
+    }
+
+    #[test]
+    fn delta_offsets() {
+        assert_eq!(parse_delta_offset(&mut &[0x7f][..]).unwrap(), 127);
+        // each continuation byte adds one before shifting
+        assert_eq!(parse_delta_offset(&mut &[0x80, 0x00][..]).unwrap(), 128);
+        assert_eq!(parse_delta_offset(&mut &[0x81, 0x7f][..]).unwrap(), 383);
+        assert!(parse_delta_offset(&mut &[0xff; 11][..]).is_err());
+        assert!(parse_delta_offset(&mut &[0x80][..]).is_err());
+
+        assert_eq!(parse_base_offset(&mut &[0x10][..], 100).unwrap(), 84);
+        assert!(parse_base_offset(&mut &[0x00][..], 100).is_err());
+        assert!(parse_base_offset(&mut &[0x64][..], 100).is_err());
+    }
+
+    #[test]
+    fn apply_copy_and_insert() {
+        let base = b"hello world";
+        // sizes 11 and 12, copy "hello " (offset 0, size 6), insert "there!"
+        let delta = b"\x0b\x0c\x90\x06\x06there!";
+        assert_eq!(apply_delta(base, delta).unwrap(), b"hello there!");
+
+        // copy past the end of the base
+        assert!(apply_delta(base, b"\x0b\x06\x91\x08\x06").is_err());
+        // wrong base size
+        assert!(apply_delta(base, b"\x0a\x01\x01!").is_err());
+        // a huge result size is rejected instead of being allocated
+        let mut huge = b"\x0b".to_vec();
+        huge.extend([0xff; 8]);
+        huge.extend(b"\x7f\x91\x00\x01");
+        assert!(apply_delta(base, &huge).is_err());
+        // reserved instruction 0
+        assert!(apply_delta(base, b"\x0b\x01\x00").is_err());
+    }
+}
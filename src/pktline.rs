@@ -0,0 +1,305 @@
+use std::io::{self, Read, Write};
+
+/// Largest packet, including the four length digits.
+const MAX_PACKET_LEN: usize = 65520;
+const LENGTH_LEN: usize = 4;
+
+/// A packet of the pkt-line format used by the git protocols.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Data(Vec<u8>),
+    /// `0000`, ends a list of packets.
+    Flush,
+    /// `0001`, separates sections of a protocol v2 message.
+    Delim,
+    /// `0002`, ends a protocol v2 response.
+    ResponseEnd,
+}
+
+impl Packet {
+    /// The payload of a data packet with its trailing newline removed.
+    pub fn as_text(&self) -> Option<&[u8]> {
+        match self {
+            Packet::Data(data) => Some(data.strip_suffix(b"\n").unwrap_or(data)),
+            _ => None,
+        }
+    }
+}
+
+/// Reads packets from a stream without consuming anything past the last packet
+/// read, so the rest of the stream (like a pack) can be read from `into_inner`.
+pub struct PktReader<R> {
+    inner: R,
+}
+
+impl<R: Read> PktReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Reads the next packet, `None` if the stream ends before it.
+    pub fn read_packet(&mut self) -> anyhow::Result<Option<Packet>> {
+        let mut length = [0u8; LENGTH_LEN];
+        let mut read = 0;
+        while read < LENGTH_LEN {
+            match self.inner.read(&mut length[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => anyhow::bail!("unexpected end of stream in a pkt-line length"),
+                n => read += n,
+            }
+        }
+
+        // from_str_radix alone would accept a sign like `+00a`
+        let length = std::str::from_utf8(&length)
+            .ok()
+            .filter(|hex| hex.bytes().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+            .ok_or(anyhow::anyhow!(
+                "invalid pkt-line length: {}",
+                String::from_utf8_lossy(&length)
+            ))?;
+        match length {
+            0 => return Ok(Some(Packet::Flush)),
+            1 => return Ok(Some(Packet::Delim)),
+            2 => return Ok(Some(Packet::ResponseEnd)),
+            3 => anyhow::bail!("invalid pkt-line length: 0003"),
+            _ if length > MAX_PACKET_LEN => {
+                anyhow::bail!("pkt-line of {length} bytes is too long")
+            }
+            _ => {}
+        }
+
+        let mut data = vec![0; length - LENGTH_LEN];
+        self.inner
+            .read_exact(&mut data)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    anyhow::anyhow!("unexpected end of stream in a pkt-line")
+                }
+                _ => err.into(),
+            })?;
+        Ok(Some(Packet::Data(data)))
+    }
+
+    /// Reads the payloads of the data packets up to the next flush packet.
+    pub fn read_until_flush(&mut self) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut payloads = Vec::new();
+        loop {
+            match self.read_packet()? {
+                Some(Packet::Data(data)) => payloads.push(data),
+                Some(Packet::Flush) => return Ok(payloads),
+                Some(packet) => anyhow::bail!("unexpected {packet:?} packet before a flush"),
+                None => anyhow::bail!("unexpected end of stream, expected a flush packet"),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// Writes packets to a stream.
+pub struct PktWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> PktWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    pub fn write_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let length = data.len() + LENGTH_LEN;
+        if length > MAX_PACKET_LEN {
+            anyhow::bail!("pkt-line payload of {} bytes is too long", data.len());
+        }
+        write!(self.inner, "{length:04x}")?;
+        self.inner.write_all(data)?;
+        Ok(())
+    }
+
+    /// Writes a text packet, which ends with a newline.
+    pub fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.write_data(format!("{line}\n").as_bytes())
+    }
+
+    pub fn write_packet(&mut self, packet: &Packet) -> anyhow::Result<()> {
+        match packet {
+            Packet::Data(data) => self.write_data(data),
+            Packet::Flush => Ok(self.inner.write_all(b"0000")?),
+            Packet::Delim => Ok(self.inner.write_all(b"0001")?),
+            Packet::ResponseEnd => Ok(self.inner.write_all(b"0002")?),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// Demultiplexes the side-band channels of a response: channel 1 is read as data,
+/// channel 2 progress is shown on stderr and channel 3 ends the stream with an error.
+/// A flush packet ends the data.
+pub struct SideBandReader<R> {
+    packets: PktReader<R>,
+    /// The last channel 1 packet, including its channel byte.
+    packet: Vec<u8>,
+    position: usize,
+    done: bool,
+    /// Whether the next progress output starts a line and needs the `remote: ` prefix.
+    line_start: bool,
+}
+
+impl<R: Read> SideBandReader<R> {
+    pub fn new(packets: PktReader<R>) -> Self {
+        Self {
+            packets,
+            packet: Vec::new(),
+            position: 0,
+            done: false,
+            line_start: true,
+        }
+    }
+
+    fn progress(&mut self, message: &[u8]) -> io::Result<()> {
+        let mut stderr = io::stderr().lock();
+        for segment in message.split_inclusive(|&c| c == b'\r' || c == b'\n') {
+            if self.line_start {
+                stderr.write_all(b"remote: ")?;
+            }
+            stderr.write_all(segment)?;
+            self.line_start = segment.ends_with(b"\r") || segment.ends_with(b"\n");
+        }
+        stderr.flush()
+    }
+}
+
+impl<R: Read> Read for SideBandReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.packet.len() {
+            if self.done {
+                return Ok(0);
+            }
+            match self.packets.read_packet().map_err(io::Error::other)? {
+                Some(Packet::Data(data)) => match data.first() {
+                    Some(1) => {
+                        self.packet = data;
+                        self.position = 1;
+                    }
+                    Some(2) => self.progress(&data[1..])?,
+                    Some(3) => {
+                        return Err(io::Error::other(format!(
+                            "remote error: {}",
+                            String::from_utf8_lossy(&data[1..]).trim_end()
+                        )))
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid side-band channel in packet {data:?}"),
+                        ))
+                    }
+                },
+                Some(Packet::Flush) | None => self.done = true,
+                Some(packet) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected {packet:?} packet in side-band data"),
+                    ))
+                }
+            }
+        }
+
+        let n = buf.len().min(self.packet.len() - self.position);
+        buf[..n].copy_from_slice(&self.packet[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(data: &[u8]) -> anyhow::Result<Vec<Packet>> {
+        let mut reader = PktReader::new(data);
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet()? {
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+
+    #[test]
+    fn write_and_read_packets() {
+        let mut writer = PktWriter::new(Vec::new());
+        writer.write_line("want abc").unwrap();
+        writer.write_packet(&Packet::Delim).unwrap();
+        writer.write_data(b"").unwrap();
+        writer.write_packet(&Packet::Flush).unwrap();
+        writer.write_packet(&Packet::ResponseEnd).unwrap();
+        let data = writer.into_inner();
+        assert_eq!(data, b"000dwant abc\n0001000400000002");
+
+        let packets = read_all(&data).unwrap();
+        assert_eq!(packets[0].as_text(), Some(&b"want abc"[..]));
+        assert_eq!(
+            packets[1..],
+            [
+                Packet::Delim,
+                Packet::Data(Vec::new()),
+                Packet::Flush,
+                Packet::ResponseEnd
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_packets() {
+        for data in [
+            &b"0003"[..],
+            b"zzzz",
+            b"00",
+            b"0009abc",
+            b"fff0",
+            b"+00a",
+            b"+00aabcdef",
+        ] {
+            assert!(read_all(data).is_err(), "{data:?}");
+        }
+        let mut writer = PktWriter::new(Vec::new());
+        assert!(writer.write_data(&[0; MAX_PACKET_LEN]).is_err());
+    }
+
+    #[test]
+    fn read_until_flush_leaves_the_rest() {
+        let mut reader = PktReader::new(&b"0006a\n0006b\n0000PACK"[..]);
+        assert_eq!(reader.read_until_flush().unwrap(), [b"a\n", b"b\n"]);
+        assert_eq!(reader.into_inner(), b"PACK");
+        assert!(PktReader::new(&b"0006a\n"[..]).read_until_flush().is_err());
+    }
+
+    #[test]
+    fn side_band() {
+        let mut writer = PktWriter::new(Vec::new());
+        writer.write_data(b"\x01PA").unwrap();
+        writer.write_data(b"\x02progress\r").unwrap();
+        writer.write_data(b"\x01CK").unwrap();
+        writer.write_packet(&Packet::Flush).unwrap();
+        let data = writer.into_inner();
+        let mut content = Vec::new();
+        SideBandReader::new(PktReader::new(&data[..]))
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"PACK");
+
+        let mut writer = PktWriter::new(Vec::new());
+        writer.write_data(b"\x03broken").unwrap();
+        let data = writer.into_inner();
+        let err = SideBandReader::new(PktReader::new(&data[..]))
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "remote error: broken");
+    }
+}
@@ -0,0 +1,354 @@
+use crate::ident::{format_date, DateFormat};
+use crate::object::{CommitObject, ShaHash, Signature};
+
+const ABBREV: usize = 7;
+
+/// How `log` shows each commit, as selected with `--format`/`--pretty`.
+pub enum Format {
+    /// `abbrev` shortens the object name as `--oneline` does.
+    Oneline {
+        abbrev: bool,
+    },
+    Short,
+    Medium,
+    Full,
+    Fuller,
+    /// A `%` placeholder string, `terminator` tells whether a newline follows every
+    /// commit (`tformat:`) or only separates them (`format:`).
+    Custom {
+        format: String,
+        terminator: bool,
+    },
+}
+
+impl Format {
+    pub fn parse(format: &str) -> anyhow::Result<Self> {
+        Ok(match format {
+            "oneline" => Format::Oneline { abbrev: false },
+            "short" => Format::Short,
+            "medium" => Format::Medium,
+            "full" => Format::Full,
+            "fuller" => Format::Fuller,
+            _ => {
+                if let Some(format) = format.strip_prefix("format:") {
+                    Format::Custom {
+                        format: format.to_owned(),
+                        terminator: false,
+                    }
+                } else if let Some(format) = format.strip_prefix("tformat:") {
+                    Format::Custom {
+                        format: format.to_owned(),
+                        terminator: true,
+                    }
+                } else if format.contains('%') {
+                    Format::Custom {
+                        format: format.to_owned(),
+                        terminator: true,
+                    }
+                } else {
+                    anyhow::bail!("invalid --pretty format: {format}");
+                }
+            }
+        })
+    }
+
+    /// Text written between two commits.
+    pub fn separator(&self) -> &'static str {
+        match self {
+            Format::Oneline { .. } => "",
+            Format::Custom { terminator, .. } if *terminator => "",
+            _ => "\n",
+        }
+    }
+
+    /// Text written after every commit.
+    pub fn terminator(&self) -> &'static str {
+        match self {
+            Format::Custom { terminator, .. } if !*terminator => "",
+            _ => "\n",
+        }
+    }
+
+    /// Formats a commit without its separator and terminator.
+    pub fn format(&self, hash: &ShaHash, commit: &CommitObject) -> String {
+        let hex = hex::encode(hash);
+        let message = String::from_utf8_lossy(&commit.message);
+        let (subject, _) = split_message(&message);
+        let mut out = String::new();
+
+        match self {
+            Format::Oneline { abbrev: true } => return format!("{} {subject}", &hex[..ABBREV]),
+            Format::Oneline { abbrev: false } => return format!("{hex} {subject}"),
+            Format::Custom { format, .. } => return expand(format, &hex, commit),
+            _ => {}
+        }
+
+        out.push_str(&format!("commit {hex}\n"));
+        if commit.parents.len() > 1 {
+            let parents = commit
+                .parents
+                .iter()
+                .map(|p| hex::encode(p)[..ABBREV].to_owned())
+                .collect::<Vec<_>>();
+            out.push_str(&format!("Merge: {}\n", parents.join(" ")));
+        }
+
+        let person = |s: &Signature| format!("{} <{}>", s.name, s.email);
+        let date = |s: &Signature| format_date(s.timestamp, &s.timezone, DateFormat::Default);
+        match self {
+            Format::Short => out.push_str(&format!("Author: {}\n", person(&commit.author))),
+            Format::Medium => out.push_str(&format!(
+                "Author: {}\nDate:   {}\n",
+                person(&commit.author),
+                date(&commit.author)
+            )),
+            Format::Full => out.push_str(&format!(
+                "Author: {}\nCommit: {}\n",
+                person(&commit.author),
+                person(&commit.committer)
+            )),
+            _ => out.push_str(&format!(
+                "Author:     {}\nAuthorDate: {}\nCommit:     {}\nCommitDate: {}\n",
+                person(&commit.author),
+                date(&commit.author),
+                person(&commit.committer),
+                date(&commit.committer)
+            )),
+        }
+
+        // the short format shows only the subject
+        let message = match self {
+            Format::Short => subject,
+            _ => message.trim_end().to_owned(),
+        };
+        out.push('\n');
+        let lines = message.lines().map(|line| format!("    {line}"));
+        out.push_str(&lines.collect::<Vec<_>>().join("\n"));
+        out
+    }
+}
+
+/// Splits a message into its subject (the first paragraph on one line) and body.
+fn split_message(message: &str) -> (String, String) {
+    let message = message.trim_start_matches('\n');
+    let (subject, body) = message.split_once("\n\n").unwrap_or((message, ""));
+    let subject = subject.lines().map(str::trim).collect::<Vec<_>>().join(" ");
+    (subject, body.trim_start_matches('\n').to_owned())
+}
+
+/// Expands the `%` placeholders of a custom format.
+fn expand(format: &str, hex: &str, commit: &CommitObject) -> String {
+    let message = String::from_utf8_lossy(&commit.message);
+    let (subject, body) = split_message(&message);
+    let mut out = String::new();
+    let mut rest = format;
+
+    while let Some(position) = rest.find('%') {
+        out.push_str(&rest[..position]);
+        rest = &rest[position + 1..];
+
+        let mut chars = rest.chars();
+        let placeholder = match chars.next() {
+            Some('a' | 'c') => {
+                let signature = match rest.as_bytes()[0] {
+                    b'a' => &commit.author,
+                    _ => &commit.committer,
+                };
+                let value = chars.next().and_then(|c| signature_field(signature, c));
+                value.map(|value| (value, 2))
+            }
+            Some('x') => rest
+                .get(1..3)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .map(|byte| ((byte as char).to_string(), 3)),
+            Some(c) => {
+                let value = match c {
+                    'H' => Some(hex.to_owned()),
+                    'h' => Some(hex[..ABBREV].to_owned()),
+                    'T' => Some(hex::encode(commit.tree)),
+                    't' => Some(hex::encode(commit.tree)[..ABBREV].to_owned()),
+                    'P' => Some(join_hashes(&commit.parents, None)),
+                    'p' => Some(join_hashes(&commit.parents, Some(ABBREV))),
+                    's' => Some(subject.clone()),
+                    'b' => Some(body.clone()),
+                    'B' => Some(message.to_string()),
+                    'n' => Some("\n".to_owned()),
+                    '%' => Some("%".to_owned()),
+                    _ => None,
+                };
+                value.map(|value| (value, 1))
+            }
+            None => None,
+        };
+
+        match placeholder {
+            Some((value, consumed)) => {
+                out.push_str(&value);
+                rest = &rest[consumed..];
+            }
+            // unknown placeholders are shown as they are
+            None => out.push('%'),
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+fn signature_field(signature: &Signature, field: char) -> Option<String> {
+    let date = |format| format_date(signature.timestamp, &signature.timezone, format);
+    Some(match field {
+        'n' => signature.name.clone(),
+        'e' => signature.email.clone(),
+        'd' => date(DateFormat::Default),
+        'D' => date(DateFormat::Rfc2822),
+        't' => signature.timestamp.to_string(),
+        'i' => date(DateFormat::Iso),
+        'I' => date(DateFormat::IsoStrict),
+        _ => return None,
+    })
+}
+
+fn join_hashes(hashes: &[ShaHash], abbrev: Option<usize>) -> String {
+    hashes
+        .iter()
+        .map(|hash| {
+            let hex = hex::encode(hash);
+            match abbrev {
+                Some(len) => hex[..len].to_owned(),
+                None => hex,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(name: &str, timestamp: i64) -> Signature {
+        Signature {
+            name: name.to_owned(),
+            email: format!("{}@example.com", name.to_lowercase()),
+            timestamp,
+            timezone: "+0000".to_owned(),
+        }
+    }
+
+    fn commit(message: &str) -> CommitObject {
+        CommitObject::new(
+            [0x11; 20],
+            vec![[0x22; 20], [0x33; 20]],
+            signature("Author", 1000000004),
+            signature("Committer", 1000000005),
+            message.to_owned(),
+        )
+    }
+
+    fn expand_format(format: &str, message: &str) -> String {
+        let Format::Custom { format, .. } = Format::parse(&format!("format:{format}")).unwrap()
+        else {
+            unreachable!("format: gives a custom format");
+        };
+        expand(&format, &"ab".repeat(20), &commit(message))
+    }
+
+    #[test]
+    fn hash_placeholders() {
+        let message = "subject\n";
+        assert_eq!(expand_format("%H", message), "ab".repeat(20));
+        assert_eq!(expand_format("%h", message), "abababa");
+        assert_eq!(
+            expand_format("%T %t", message),
+            "11".repeat(20) + " 1111111"
+        );
+        assert_eq!(
+            expand_format("%P", message),
+            "22".repeat(20) + " " + &"33".repeat(20)
+        );
+        assert_eq!(expand_format("%p", message), "2222222 3333333");
+    }
+
+    #[test]
+    fn message_placeholders() {
+        let message = "first\nline\n\n\nbody\n\nmore\n";
+        assert_eq!(expand_format("%s", message), "first line");
+        assert_eq!(expand_format("%b", message), "body\n\nmore\n");
+        assert_eq!(expand_format("%B", message), message);
+        assert_eq!(expand_format("[%s][%b]", "only\n"), "[only][]");
+    }
+
+    #[test]
+    fn signature_placeholders() {
+        let message = "subject\n";
+        assert_eq!(
+            expand_format("%an <%ae> %cn <%ce>", message),
+            "Author <author@example.com> Committer <committer@example.com>"
+        );
+        assert_eq!(expand_format("%at %ct", message), "1000000004 1000000005");
+        assert_eq!(
+            expand_format("%ad|%aD", message),
+            "Sun Sep 9 01:46:44 2001 +0000|Sun, 9 Sep 2001 01:46:44 +0000"
+        );
+        assert_eq!(
+            expand_format("%ci|%cI", message),
+            "2001-09-09 01:46:45 +0000|2001-09-09T01:46:45+00:00"
+        );
+    }
+
+    #[test]
+    fn literal_placeholders() {
+        let message = "subject\n";
+        assert_eq!(expand_format("a%nb", message), "a\nb");
+        assert_eq!(expand_format("100%%", message), "100%");
+        assert_eq!(expand_format("%x41%x7a", message), "Az");
+        // unknown and incomplete placeholders are kept as they are
+        assert_eq!(expand_format("%q|%a|%ax|%x4|%", message), "%q|%a|%ax|%x4|%");
+        assert_eq!(expand_format("%%s", message), "%s");
+    }
+
+    #[test]
+    fn parse_formats() {
+        assert!(matches!(
+            Format::parse("oneline").unwrap(),
+            Format::Oneline { abbrev: false }
+        ));
+        assert!(matches!(Format::parse("fuller").unwrap(), Format::Fuller));
+        assert!(matches!(
+            Format::parse("%h").unwrap(),
+            Format::Custom {
+                terminator: true,
+                ..
+            }
+        ));
+        let format = Format::parse("format:%h").unwrap();
+        assert_eq!((format.separator(), format.terminator()), ("\n", ""));
+        let format = Format::parse("tformat:%h").unwrap();
+        assert_eq!((format.separator(), format.terminator()), ("", "\n"));
+        assert!(Format::parse("unknown").is_err());
+    }
+
+    #[test]
+    fn builtin_formats() {
+        let hash = [0xab; 20];
+        let commit = commit("subject\n\nbody\n");
+        let hex = "ab".repeat(20);
+        assert_eq!(
+            Format::Oneline { abbrev: true }.format(&hash, &commit),
+            "abababa subject"
+        );
+        assert_eq!(
+            Format::Short.format(&hash, &commit),
+            format!(
+                "commit {hex}\nMerge: 2222222 3333333\nAuthor: Author <author@example.com>\n\n    subject"
+            )
+        );
+        assert_eq!(
+            Format::Medium.format(&hash, &commit),
+            format!(
+                "commit {hex}\nMerge: 2222222 3333333\nAuthor: Author <author@example.com>\nDate:   Sun Sep 9 01:46:44 2001 +0000\n\n    subject\n    \n    body"
+            )
+        );
+    }
+}
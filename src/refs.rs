@@ -0,0 +1,452 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::lockfile::LockFile;
+use crate::object::{parse_hash, ShaHash};
+
+/// The all-zero hash, used as an expected old value to require that a ref does not exist.
+pub const NULL_HASH: ShaHash = [0; 20];
+
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// The content of a ref: an object name or the name of another ref.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefValue {
+    Direct(ShaHash),
+    Symbolic(String),
+}
+
+/// A `packed-refs` line with the object the ref peels to (from the `^` line following it).
+#[derive(Debug, Clone)]
+pub struct PackedRef {
+    pub name: String,
+    pub hash: ShaHash,
+    pub peeled: Option<ShaHash>,
+}
+
+/// Refs stored as loose files under the git directory and in `packed-refs`.
+pub struct RefStore {
+    git_dir: PathBuf,
+}
+
+impl RefStore {
+    pub fn new(git_dir: &Path) -> Self {
+        Self {
+            git_dir: git_dir.to_owned(),
+        }
+    }
+
+    fn loose_path(&self, name: &str) -> PathBuf {
+        self.git_dir.join(name)
+    }
+
+    fn read_loose(&self, name: &str) -> anyhow::Result<Option<RefValue>> {
+        // names like `../config` would read files outside of the ref storage
+        check_ref_name(name)?;
+        let path = self.loose_path(name);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&path)?;
+        let content = content.trim_end();
+        match content.strip_prefix("ref:") {
+            Some(target) => Ok(Some(RefValue::Symbolic(target.trim_start().to_owned()))),
+            None => Ok(Some(RefValue::Direct(
+                parse_hash(content)
+                    .map_err(|_| anyhow::anyhow!("invalid ref {name}: {content}"))?,
+            ))),
+        }
+    }
+
+    /// Reads a ref without following symbolic refs, loose refs take precedence over packed ones.
+    pub fn read(&self, name: &str) -> anyhow::Result<Option<RefValue>> {
+        if let Some(value) = self.read_loose(name)? {
+            return Ok(Some(value));
+        }
+        Ok(self
+            .packed_refs()?
+            .into_iter()
+            .find(|r| r.name == name)
+            .map(|r| RefValue::Direct(r.hash)))
+    }
+
+    pub fn packed_refs(&self) -> anyhow::Result<Vec<PackedRef>> {
+        Ok(self.read_packed_refs()?.1)
+    }
+
+    /// Parses `packed-refs` into its `# pack-refs with:` header line, if any, and the refs.
+    fn read_packed_refs(&self) -> anyhow::Result<(Option<String>, Vec<PackedRef>)> {
+        let path = self.git_dir.join("packed-refs");
+        if !path.exists() {
+            return Ok((None, Vec::new()));
+        }
+
+        let content = fs::read_to_string(path)?;
+        let header = content
+            .lines()
+            .next()
+            .filter(|line| line.starts_with('#'))
+            .map(str::to_owned);
+        let mut refs: Vec<PackedRef> = Vec::new();
+        for line in content.lines() {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            if let Some(peeled) = line.strip_prefix('^') {
+                let last = refs
+                    .last_mut()
+                    .ok_or(anyhow::anyhow!("peeled line without a ref in packed-refs"))?;
+                last.peeled = Some(parse_hash(peeled)?);
+                continue;
+            }
+
+            let (hash, name) = line
+                .split_once(' ')
+                .ok_or(anyhow::anyhow!("invalid packed-refs line: {line}"))?;
+            refs.push(PackedRef {
+                name: name.to_owned(),
+                hash: parse_hash(hash)?,
+                peeled: None,
+            });
+        }
+
+        Ok((header, refs))
+    }
+
+    /// Follows symbolic refs, returns the name of the last ref in the chain and its
+    /// value, which is `None` for an unborn branch.
+    pub fn resolve(&self, name: &str) -> anyhow::Result<(String, Option<ShaHash>)> {
+        let mut name = name.to_owned();
+        for _ in 0..=MAX_SYMREF_DEPTH {
+            match self.read(&name)? {
+                Some(RefValue::Symbolic(target)) => name = target,
+                Some(RefValue::Direct(hash)) => return Ok((name, Some(hash))),
+                None => return Ok((name, None)),
+            }
+        }
+        anyhow::bail!("symbolic ref nesting too deep at {name}")
+    }
+
+    /// All refs below `refs/` resolved to an object, sorted by name.
+    pub fn list(&self) -> anyhow::Result<Vec<(String, ShaHash)>> {
+        let mut names = Vec::new();
+        collect_loose(&self.git_dir, "refs", &mut names)?;
+        for packed in self.packed_refs()? {
+            names.push(packed.name);
+        }
+        names.sort();
+        names.dedup();
+
+        let mut refs = Vec::new();
+        for name in names {
+            // dangling symbolic refs are skipped
+            if let (_, Some(hash)) = self.resolve(&name)? {
+                refs.push((name, hash));
+            }
+        }
+        Ok(refs)
+    }
+
+    /// The peeled value recorded in `packed-refs`, if any.
+    pub fn packed_peeled(&self, name: &str) -> anyhow::Result<Option<ShaHash>> {
+        if self.read_loose(name)?.is_some() {
+            return Ok(None);
+        }
+        Ok(self
+            .packed_refs()?
+            .into_iter()
+            .find(|r| r.name == name)
+            .and_then(|r| r.peeled))
+    }
+
+    /// Takes the lock of a ref and checks its current value against `expected`,
+    /// `NULL_HASH` requires the ref to not exist.
+    fn lock(&self, name: &str, expected: Option<ShaHash>) -> anyhow::Result<LockFile> {
+        check_ref_name(name)?;
+        let lock = LockFile::acquire(&self.loose_path(name))
+            .map_err(|err| anyhow::anyhow!("cannot lock ref '{name}': {err}"))?;
+
+        let current = match self.read(name)? {
+            Some(RefValue::Direct(hash)) => Some(hash),
+            _ => None,
+        };
+        match (expected, current) {
+            (None, _) => {}
+            (Some(NULL_HASH), Some(_)) => {
+                anyhow::bail!("cannot lock ref '{name}': reference already exists")
+            }
+            (Some(NULL_HASH), None) => {}
+            (Some(expected), None) => anyhow::bail!(
+                "cannot lock ref '{name}': unable to resolve reference '{name}', expected {}",
+                hex::encode(expected)
+            ),
+            (Some(expected), Some(current)) if expected != current => anyhow::bail!(
+                "cannot lock ref '{name}': is at {} but expected {}",
+                hex::encode(current),
+                hex::encode(expected)
+            ),
+            _ => {}
+        }
+
+        Ok(lock)
+    }
+
+    /// Points a ref to an object, `deref` updates the ref a symbolic ref points to
+    /// instead of replacing the symbolic ref itself.
+    pub fn update(
+        &self,
+        name: &str,
+        new: &ShaHash,
+        expected: Option<ShaHash>,
+        deref: bool,
+    ) -> anyhow::Result<()> {
+        let name = if deref {
+            self.resolve(name)?.0
+        } else {
+            name.to_owned()
+        };
+        let mut lock = self.lock(&name, expected)?;
+        lock.write_all(format!("{}\n", hex::encode(new)).as_bytes())?;
+        lock.commit()
+    }
+
+    /// Deletes a ref from both the loose and the packed storage.
+    pub fn delete(&self, name: &str, expected: Option<ShaHash>, deref: bool) -> anyhow::Result<()> {
+        let name = if deref {
+            self.resolve(name)?.0
+        } else {
+            name.to_owned()
+        };
+        // the lock is held while the ref is removed
+        let lock = self.lock(&name, expected)?;
+
+        let (header, packed) = self.read_packed_refs()?;
+        if packed.iter().any(|r| r.name == name) {
+            // the header declares the traits of the file, which removing a ref keeps
+            let mut content = header.map(|h| h + "\n").unwrap_or_default();
+            for packed in packed.iter().filter(|r| r.name != name) {
+                content.push_str(&format!("{} {}\n", hex::encode(packed.hash), packed.name));
+                if let Some(peeled) = packed.peeled {
+                    content.push_str(&format!("^{}\n", hex::encode(peeled)));
+                }
+            }
+            let mut packed_lock = LockFile::acquire(&self.git_dir.join("packed-refs"))?;
+            packed_lock.write_all(content.as_bytes())?;
+            packed_lock.commit()?;
+        }
+
+        let path = self.loose_path(&name);
+        if path.is_file() {
+            fs::remove_file(&path)?;
+            drop(lock);
+            // prune the directories left empty below refs/, now that the lock file is gone
+            let refs_dir = self.git_dir.join("refs");
+            let mut dir = path.parent();
+            while let Some(current) = dir.filter(|d| d.starts_with(&refs_dir) && *d != refs_dir) {
+                if fs::remove_dir(current).is_err() {
+                    break;
+                }
+                dir = current.parent();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes `name` a symbolic ref pointing to `target`.
+    pub fn set_symbolic(&self, name: &str, target: &str) -> anyhow::Result<()> {
+        check_ref_name(target)?;
+        let mut lock = self.lock(name, None)?;
+        lock.write_all(format!("ref: {target}\n").as_bytes())?;
+        lock.commit()
+    }
+}
+
+fn collect_loose(git_dir: &Path, prefix: &str, names: &mut Vec<String>) -> anyhow::Result<()> {
+    let dir = git_dir.join(prefix);
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(file_name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        let name = format!("{prefix}/{file_name}");
+        if entry.file_type()?.is_dir() {
+            collect_loose(git_dir, &name, names)?;
+        } else if check_ref_name(&name).is_ok() {
+            // lock files and other stray files are not refs
+            names.push(name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates a ref name with the rules of `git check-ref-format`, names outside of
+/// `refs/` must be upper-case like `HEAD` or `ORIG_HEAD`.
+pub fn check_ref_name(name: &str) -> anyhow::Result<()> {
+    let valid_component = |component: &str| {
+        !component.is_empty() && !component.starts_with('.') && !component.ends_with(".lock")
+    };
+    let valid = !name.is_empty()
+        && name != "@"
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        && name.split('/').all(valid_component)
+        && (name.starts_with("refs/") || name.chars().all(|c| c.is_ascii_uppercase() || c == '_'));
+
+    if !valid {
+        anyhow::bail!("invalid ref name: {name}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const A: ShaHash = [0xaa; 20];
+    const B: ShaHash = [0xbb; 20];
+    const C: ShaHash = [0xcc; 20];
+
+    fn store(dir: &TempDir, packed_refs: &str) -> RefStore {
+        fs::create_dir_all(dir.path().join("refs/heads")).unwrap();
+        fs::write(dir.path().join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        if !packed_refs.is_empty() {
+            fs::write(dir.path().join("packed-refs"), packed_refs).unwrap();
+        }
+        RefStore::new(dir.path())
+    }
+
+    fn packed_line(hash: ShaHash, name: &str) -> String {
+        format!("{} {name}\n", hex::encode(hash))
+    }
+
+    #[test]
+    fn loose_refs_shadow_packed_ones() {
+        let dir = TempDir::new();
+        let packed = packed_line(A, "refs/heads/main")
+            + &packed_line(B, "refs/tags/v1")
+            + &format!("^{}\n", hex::encode(C));
+        let refs = store(&dir, &packed);
+
+        assert_eq!(
+            refs.resolve("HEAD").unwrap(),
+            ("refs/heads/main".into(), Some(A))
+        );
+        assert_eq!(refs.packed_peeled("refs/tags/v1").unwrap(), Some(C));
+        refs.update("HEAD", &B, Some(A), true).unwrap();
+        assert_eq!(
+            refs.read("refs/heads/main").unwrap(),
+            Some(RefValue::Direct(B))
+        );
+        assert_eq!(
+            refs.read("HEAD").unwrap(),
+            Some(RefValue::Symbolic("refs/heads/main".into()))
+        );
+
+        assert_eq!(
+            refs.list().unwrap(),
+            [("refs/heads/main".into(), B), ("refs/tags/v1".into(), B)]
+        );
+        assert!(refs.read("refs/heads/missing").unwrap().is_none());
+        assert_eq!(refs.resolve("refs/heads/missing").unwrap().1, None);
+    }
+
+    #[test]
+    fn update_checks_expected_value() {
+        let dir = TempDir::new();
+        let refs = store(&dir, "");
+
+        // HEAD points to an unborn branch
+        assert_eq!(
+            refs.resolve("HEAD").unwrap(),
+            ("refs/heads/main".into(), None)
+        );
+        assert!(refs.update("HEAD", &A, Some(B), true).is_err());
+        refs.update("HEAD", &A, Some(NULL_HASH), true).unwrap();
+        assert!(refs
+            .update("refs/heads/main", &B, Some(NULL_HASH), false)
+            .is_err());
+        assert!(refs.update("refs/heads/main", &B, Some(C), false).is_err());
+        refs.update("refs/heads/main", &B, Some(A), false).unwrap();
+        assert_eq!(refs.resolve("HEAD").unwrap().1, Some(B));
+
+        // without deref the symbolic ref itself is replaced
+        refs.update("HEAD", &C, None, false).unwrap();
+        assert_eq!(refs.read("HEAD").unwrap(), Some(RefValue::Direct(C)));
+        assert!(!dir.path().join("HEAD.lock").exists());
+    }
+
+    #[test]
+    fn delete_loose_and_packed() {
+        let dir = TempDir::new();
+        let header = "# pack-refs with: peeled sorted \n";
+        let packed = packed_line(A, "refs/heads/gone") + &packed_line(B, "refs/tags/v1");
+        let refs = store(&dir, &(header.to_owned() + &packed));
+        refs.update("refs/heads/gone", &C, None, false).unwrap();
+        refs.update("refs/heads/topic/x", &C, None, false).unwrap();
+
+        assert!(refs.delete("refs/heads/gone", Some(A), false).is_err());
+        refs.delete("refs/heads/gone", Some(C), false).unwrap();
+        assert!(refs.read("refs/heads/gone").unwrap().is_none());
+        let content = fs::read_to_string(dir.path().join("packed-refs")).unwrap();
+        assert_eq!(content, header.to_owned() + &packed_line(B, "refs/tags/v1"));
+
+        // empty directories are pruned, refs/ itself stays
+        refs.delete("refs/heads/topic/x", None, false).unwrap();
+        assert!(!dir.path().join("refs/heads/topic").exists());
+        assert!(dir.path().join("refs").exists());
+    }
+
+    #[test]
+    fn delete_keeps_missing_header() {
+        let dir = TempDir::new();
+        let packed = packed_line(A, "refs/heads/a") + &packed_line(B, "refs/heads/b");
+        let refs = store(&dir, &packed);
+        refs.delete("refs/heads/a", None, false).unwrap();
+        let content = fs::read_to_string(dir.path().join("packed-refs")).unwrap();
+        assert_eq!(content, packed_line(B, "refs/heads/b"));
+    }
+
+    #[test]
+    fn invalid_names() {
+        let dir = TempDir::new();
+        let refs = store(&dir, "");
+        fs::write(dir.path().join("config"), hex::encode(A)).unwrap();
+        fs::write(dir.path().join("refs/heads/bad name"), hex::encode(A)).unwrap();
+
+        for name in [
+            "../x",
+            "refs/../config",
+            "config",
+            "refs/heads/a..b",
+            "refs/heads/x.lock",
+        ] {
+            assert!(refs.read(name).is_err(), "{name}");
+            assert!(refs.update(name, &A, None, false).is_err(), "{name}");
+        }
+        fs::write(dir.path().join("HEAD"), "ref: ../config\n").unwrap();
+        assert!(refs.resolve("HEAD").is_err());
+        assert!(refs.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn symbolic_ref_loops() {
+        let dir = TempDir::new();
+        let refs = store(&dir, "");
+        refs.set_symbolic("refs/heads/a", "refs/heads/b").unwrap();
+        refs.set_symbolic("refs/heads/b", "refs/heads/a").unwrap();
+        assert!(refs.resolve("HEAD").is_ok());
+        assert!(refs.resolve("refs/heads/a").is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::Config;
+use crate::odb::ObjectDatabase;
+use crate::refs::RefStore;
+
+/// A git repository: the git directory, its optional working tree, the object database and refs.
+pub struct Repository {
+    pub git_dir: PathBuf,
+    /// `None` for bare repositories.
+    pub work_tree: Option<PathBuf>,
+    pub odb: ObjectDatabase,
+    pub refs: RefStore,
+    pub config: Config,
+}
+
+impl Repository {
+    /// Creates a new repository in `path/.git` and opens it.
+    pub fn init(path: &Path) -> anyhow::Result<Self> {
+        let git_dir = path.join(".git");
+        fs::create_dir(&git_dir)?;
+        fs::create_dir(git_dir.join("objects"))?;
+        fs::create_dir(git_dir.join("refs"))?;
+        File::create_new(git_dir.join("HEAD"))?.write_all(b"ref: refs/heads/main\n")?;
+
+        Self::open(git_dir, Some(path.to_owned()))
+    }
+
+    pub fn open(git_dir: PathBuf, work_tree: Option<PathBuf>) -> anyhow::Result<Self> {
+        let git_dir = fs::canonicalize(&git_dir)?;
+        let work_tree = work_tree.map(fs::canonicalize).transpose()?;
+        let odb = ObjectDatabase::open(&git_dir.join("objects"))?;
+        let refs = RefStore::new(&git_dir);
+        let config = Config::load(Some(&git_dir))?;
+        Ok(Self {
+            git_dir,
+            work_tree,
+            odb,
+            refs,
+            config,
+        })
+    }
+
+    /// Finds the repository for the current directory. `GIT_DIR` and `GIT_WORK_TREE`
+    /// take precedence, otherwise parent directories are searched for `.git`
+    /// (a directory or a `gitdir:` file) or for a bare repository.
+    pub fn discover() -> anyhow::Result<Self> {
+        Self::discover_from(
+            env::current_dir()?,
+            env::var_os("GIT_DIR"),
+            env::var_os("GIT_WORK_TREE"),
+        )
+    }
+
+    fn discover_from(
+        cwd: PathBuf,
+        env_git_dir: Option<OsString>,
+        env_work_tree: Option<OsString>,
+    ) -> anyhow::Result<Self> {
+        let env_work_tree = env_work_tree.map(|p| cwd.join(p));
+
+        if let Some(git_dir) = env_git_dir {
+            let git_dir = resolve_gitfile(&cwd.join(git_dir))?;
+            let work_tree = env_work_tree.or(Some(cwd));
+            return Self::open(git_dir, work_tree);
+        }
+
+        let mut dir = Some(cwd.as_path());
+        while let Some(current) = dir {
+            let dot_git = current.join(".git");
+            if dot_git.exists() {
+                let work_tree = env_work_tree.unwrap_or(current.to_owned());
+                return Self::open(resolve_gitfile(&dot_git)?, Some(work_tree));
+            }
+            if is_git_dir(current) {
+                return Self::open(current.to_owned(), env_work_tree);
+            }
+            dir = current.parent();
+        }
+
+        anyhow::bail!("not a git repository (or any of the parent directories): .git")
+    }
+
+    /// Opens the repository whose work tree is `path`, `None` if `path` has no `.git`.
+    pub fn open_work_tree(path: &Path) -> anyhow::Result<Option<Self>> {
+        let dot_git = path.join(".git");
+        if !dot_git.exists() {
+            return Ok(None);
+        }
+        Self::open(resolve_gitfile(&dot_git)?, Some(path.to_owned())).map(Some)
+    }
+
+    pub fn work_tree(&self) -> anyhow::Result<&Path> {
+        self.work_tree
+            .as_deref()
+            .ok_or(anyhow::anyhow!("this operation must be run in a work tree"))
+    }
+
+    pub fn index_file(&self) -> PathBuf {
+        self.git_dir.join("index")
+    }
+
+    /// Location of a worktree file given its path relative to the worktree root.
+    pub fn worktree_path(&self, path: &[u8]) -> anyhow::Result<PathBuf> {
+        Ok(self.work_tree()?.join(OsStr::from_bytes(path)))
+    }
+
+    /// Converts a user-provided path (relative to the current directory) into the
+    /// worktree-relative byte form used by the index.
+    pub fn relative_path(&self, path: &Path) -> anyhow::Result<Vec<u8>> {
+        let work_tree = self.work_tree()?;
+        let mut absolute = PathBuf::new();
+        for component in env::current_dir()?.join(path).components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    absolute.pop();
+                }
+                _ => absolute.push(component),
+            }
+        }
+
+        let relative = absolute
+            .strip_prefix(work_tree)
+            .map_err(|_| anyhow::anyhow!("'{}' is outside repository", path.display()))?;
+        let mut result = Vec::new();
+        for component in relative.components() {
+            if !result.is_empty() {
+                result.push(b'/');
+            }
+            result.extend(component.as_os_str().as_bytes());
+        }
+        Ok(result)
+    }
+}
+
+/// A directory is a git directory if it has `HEAD`, `objects` and `refs`.
+fn is_git_dir(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir()
+}
+
+/// Follows a `gitdir: <path>` file used by worktrees and submodules.
+fn resolve_gitfile(path: &Path) -> anyhow::Result<PathBuf> {
+    if !path.is_file() {
+        return Ok(path.to_owned());
+    }
+
+    let content = fs::read_to_string(path)?;
+    let target = content
+        .trim_end()
+        .strip_prefix("gitdir: ")
+        .ok_or(anyhow::anyhow!(
+            "invalid gitfile format: {}",
+            path.display()
+        ))?;
+    let base = path.parent().unwrap_or(Path::new("."));
+    Ok(base.join(target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn discover(cwd: &Path) -> anyhow::Result<Repository> {
+        Repository::discover_from(cwd.to_owned(), None, None)
+    }
+
+    #[test]
+    fn discover_parent_directories() {
+        let dir = TempDir::new();
+        let repo = Repository::init(dir.path()).unwrap();
+        let nested = dir.path().join("a/b");
+        fs::create_dir_all(&nested).unwrap();
+
+        let found = discover(&nested).unwrap();
+        assert_eq!(found.git_dir, repo.git_dir);
+        assert_eq!(found.work_tree, repo.work_tree);
+
+        // inside the git directory itself there is no work tree
+        let found = discover(&repo.git_dir.join("refs")).unwrap();
+        assert_eq!(found.git_dir, repo.git_dir);
+        assert_eq!(found.work_tree, None);
+
+        let outside = TempDir::new();
+        assert!(discover(outside.path()).is_err());
+    }
+
+    #[test]
+    fn discover_gitfile_and_environment() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.path().join("main")).unwrap();
+        let repo = Repository::init(&dir.path().join("main")).unwrap();
+        let linked = dir.path().join("linked");
+        fs::create_dir(&linked).unwrap();
+        fs::write(linked.join(".git"), "gitdir: ../main/.git\n").unwrap();
+
+        let found = discover(&linked).unwrap();
+        assert_eq!(found.git_dir, repo.git_dir);
+        assert_eq!(
+            found.work_tree.as_deref(),
+            Some(fs::canonicalize(&linked).unwrap().as_path())
+        );
+
+        fs::write(linked.join(".git"), "garbage\n").unwrap();
+        assert!(discover(&linked).is_err());
+
+        // GIT_DIR skips the search, the work tree defaults to the current directory
+        let found = Repository::discover_from(
+            linked.clone(),
+            Some("../main/.git".into()),
+            Some("..".into()),
+        )
+        .unwrap();
+        assert_eq!(found.git_dir, repo.git_dir);
+        assert_eq!(
+            found.work_tree.as_deref(),
+            Some(fs::canonicalize(dir.path()).unwrap().as_path())
+        );
+        let found =
+            Repository::discover_from(linked.clone(), Some("../main/.git".into()), None).unwrap();
+        assert_eq!(
+            found.work_tree.as_deref(),
+            Some(fs::canonicalize(&linked).unwrap().as_path())
+        );
+    }
+
+    #[test]
+    fn relative_paths() {
+        let dir = TempDir::new();
+        let repo = Repository::init(dir.path()).unwrap();
+        let work_tree = repo.work_tree().unwrap().to_owned();
+
+        assert_eq!(repo.relative_path(&work_tree).unwrap(), b"");
+        assert_eq!(repo.relative_path(&work_tree.join("a/b")).unwrap(), b"a/b");
+        assert_eq!(
+            repo.relative_path(&work_tree.join("a/./c/../b")).unwrap(),
+            b"a/b"
+        );
+        assert_eq!(repo.relative_path(&work_tree.join("a/..")).unwrap(), b"");
+        assert!(repo.relative_path(&work_tree.join("..")).is_err());
+        assert!(repo.relative_path(&work_tree.join("../x")).is_err());
+    }
+}
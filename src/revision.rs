@@ -0,0 +1,395 @@
+use crate::dircache::Index;
+use crate::object::{parse_hash, Object, ObjectKind, ShaHash};
+use crate::odb::ObjectDatabase;
+use crate::refs::check_ref_name;
+use crate::repository::Repository;
+use crate::revwalk::merge_bases;
+
+/// Shortest abbreviated object name that is looked up.
+const MIN_ABBREV: usize = 4;
+
+/// Where short ref names are searched, in order.
+const REF_RULES: [&str; 6] = [
+    "%s",
+    "refs/%s",
+    "refs/tags/%s",
+    "refs/heads/%s",
+    "refs/remotes/%s",
+    "refs/remotes/%s/HEAD",
+];
+
+/// A commit selected by a revision argument, or one whose history is excluded
+/// (`^A`, the left side of `A..B` and the merge bases of `A...B`).
+#[derive(Debug, Clone, Copy)]
+pub enum RevisionArg {
+    Include(ShaHash),
+    Exclude(ShaHash),
+}
+
+fn unknown_revision(spec: &str) -> anyhow::Error {
+    anyhow::anyhow!("ambiguous argument '{spec}': unknown revision or path not in the working tree")
+}
+
+/// Expands a revision argument that may be a range, in the order `git rev-parse` prints it.
+pub fn parse_arg(repo: &Repository, arg: &str) -> anyhow::Result<Vec<RevisionArg>> {
+    if let Some(excluded) = arg.strip_prefix('^') {
+        return Ok(vec![RevisionArg::Exclude(resolve(repo, excluded)?)]);
+    }
+
+    let side = |spec: &str| match spec {
+        "" => resolve(repo, "HEAD"),
+        spec => resolve(repo, spec),
+    };
+    match split_range(arg) {
+        Some((start, end, true)) => {
+            let (start, end) = (side(start)?, side(end)?);
+            let mut args = vec![RevisionArg::Include(end), RevisionArg::Include(start)];
+            let start = peel(&repo.odb, &start, Some(ObjectKind::Commit))?;
+            let end = peel(&repo.odb, &end, Some(ObjectKind::Commit))?;
+            for base in merge_bases(&repo.odb, &start, &end)? {
+                args.push(RevisionArg::Exclude(base));
+            }
+            Ok(args)
+        }
+        Some((start, end, false)) => Ok(vec![
+            RevisionArg::Include(side(end)?),
+            RevisionArg::Exclude(side(start)?),
+        ]),
+        None => Ok(vec![RevisionArg::Include(resolve(repo, arg)?)]),
+    }
+}
+
+/// Splits `A..B` and `A...B` (the `bool`) ranges. Dots in the path of a
+/// `<rev>:<path>` argument or inside `{...}` do not make a range.
+fn split_range(arg: &str) -> Option<(&str, &str, bool)> {
+    let revision = &arg[..find_outside_braces(arg, ":").unwrap_or(arg.len())];
+    let dots = find_outside_braces(revision, "..")?;
+    let symmetric = revision[dots..].starts_with("...");
+    let end = dots + if symmetric { 3 } else { 2 };
+    Some((&arg[..dots], &arg[end..], symmetric))
+}
+
+/// Resolves a revision and peels it to the given type.
+pub fn resolve_kind(repo: &Repository, spec: &str, kind: ObjectKind) -> anyhow::Result<ShaHash> {
+    peel(&repo.odb, &resolve(repo, spec)?, Some(kind))
+}
+
+/// Resolves a single revision: an object name (possibly abbreviated), a ref name,
+/// `@{upstream}`, followed by `~N`, `^N` and `^{type}` suffixes, or `<rev>:<path>`.
+pub fn resolve(repo: &Repository, spec: &str) -> anyhow::Result<ShaHash> {
+    if let Some(colon) = find_outside_braces(spec, ":") {
+        return resolve_path(repo, &spec[..colon], &spec[colon + 1..]);
+    }
+
+    let base_end = find_outside_braces(spec, "~")
+        .into_iter()
+        .chain(find_outside_braces(spec, "^"))
+        .min()
+        .unwrap_or(spec.len());
+    let mut hash = resolve_base(repo, &spec[..base_end])?;
+
+    let mut rest = &spec[base_end..];
+    while let Some(&operator) = rest.as_bytes().first() {
+        rest = &rest[1..];
+        if operator == b'^' && rest.starts_with('{') {
+            let end = rest.find('}').ok_or_else(|| unknown_revision(spec))?;
+            hash = peel_to_type(&repo.odb, &hash, &rest[1..end])?;
+            rest = &rest[end + 1..];
+            continue;
+        }
+
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let count: usize = match digits {
+            0 => 1,
+            _ => rest[..digits].parse()?,
+        };
+        rest = &rest[digits..];
+
+        hash = peel(&repo.odb, &hash, Some(ObjectKind::Commit))?;
+        let parent = |hash: &ShaHash, n: usize| -> anyhow::Result<ShaHash> {
+            let Object::Commit(commit) = repo.odb.read(hash)? else {
+                unreachable!("peeled to a commit");
+            };
+            commit
+                .parents
+                .get(n)
+                .copied()
+                .ok_or_else(|| unknown_revision(spec))
+        };
+        match (operator, count) {
+            (b'~', _) => {
+                for _ in 0..count {
+                    hash = parent(&hash, 0)?;
+                }
+            }
+            (_, 0) => {}
+            (_, n) => hash = parent(&hash, n - 1)?,
+        }
+    }
+
+    Ok(hash)
+}
+
+/// Position of the first occurrence of `pattern` that is not inside `{...}`.
+fn find_outside_braces(spec: &str, pattern: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, b) in spec.bytes().enumerate() {
+        match b {
+            b'{' => depth += 1,
+            b'}' => depth -= 1,
+            // bytes are compared since `i` is not always a char boundary
+            _ if depth == 0 && spec.as_bytes()[i..].starts_with(pattern.as_bytes()) => {
+                return Some(i)
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn resolve_base(repo: &Repository, base: &str) -> anyhow::Result<ShaHash> {
+    if base.ends_with('}') {
+        if let Some(at) = base.find("@{") {
+            let name = full_ref_name(repo, base)?.ok_or_else(|| unknown_revision(base))?;
+            return repo
+                .refs
+                .resolve(&name)?
+                .1
+                .ok_or_else(|| unknown_revision(&base[at..]));
+        }
+    }
+
+    let base = if base == "@" { "HEAD" } else { base };
+    if base.len() == 40 {
+        if let Ok(hash) = parse_hash(base) {
+            return Ok(hash);
+        }
+    }
+    if let Some((_, hash)) = dwim_ref(repo, base)? {
+        return Ok(hash);
+    }
+
+    if base.len() >= MIN_ABBREV && base.bytes().all(|b| b.is_ascii_hexdigit()) {
+        match repo.odb.find_prefix(base)?.as_slice() {
+            [hash] => return Ok(*hash),
+            [] => {}
+            _ => anyhow::bail!("short object ID {base} is ambiguous"),
+        }
+    }
+
+    Err(unknown_revision(base))
+}
+
+/// Finds the ref a short name refers to using the ref search rules.
+pub fn dwim_ref(repo: &Repository, name: &str) -> anyhow::Result<Option<(String, ShaHash)>> {
+    if name.is_empty() {
+        return Ok(None);
+    }
+    for rule in REF_RULES {
+        let candidate = rule.replace("%s", name);
+        if check_ref_name(&candidate).is_err() {
+            continue;
+        }
+        if let (_, Some(hash)) = repo.refs.resolve(&candidate)? {
+            return Ok(Some((candidate, hash)));
+        }
+    }
+    Ok(None)
+}
+
+/// The full name of the ref a revision names, `None` for revisions that are not refs.
+/// Symbolic refs are followed so that `HEAD` gives the current branch.
+pub fn full_ref_name(repo: &Repository, spec: &str) -> anyhow::Result<Option<String>> {
+    if let Some(branch) = spec
+        .strip_suffix('}')
+        .and_then(|s| s.split_once("@{"))
+        .and_then(|(branch, suffix)| {
+            matches!(suffix.to_lowercase().as_str(), "u" | "upstream").then_some(branch)
+        })
+    {
+        return upstream(repo, branch).map(Some);
+    }
+
+    let spec = if spec == "@" { "HEAD" } else { spec };
+    Ok(match dwim_ref(repo, spec)? {
+        Some((name, _)) => Some(repo.refs.resolve(&name)?.0),
+        None => None,
+    })
+}
+
+/// The remote-tracking ref configured as the upstream of a branch (the current one if empty).
+fn upstream(repo: &Repository, branch: &str) -> anyhow::Result<String> {
+    let branch = match branch {
+        "" | "HEAD" | "@" => {
+            let (head, _) = repo.refs.resolve("HEAD")?;
+            head.strip_prefix("refs/heads/")
+                .ok_or(anyhow::anyhow!("HEAD does not point to a branch"))?
+                .to_owned()
+        }
+        branch => branch
+            .strip_prefix("refs/heads/")
+            .unwrap_or(branch)
+            .to_owned(),
+    };
+
+    let config = &repo.config;
+    let (Some(remote), Some(merge)) = (
+        config.get(&format!("branch.{branch}.remote")),
+        config.get(&format!("branch.{branch}.merge")),
+    ) else {
+        anyhow::bail!("no upstream configured for branch '{branch}'");
+    };
+    if remote == "." {
+        return Ok(merge.to_owned());
+    }
+
+    config
+        .get_all(&format!("remote.{remote}.fetch"))
+        .into_iter()
+        .filter_map(|entry| map_refspec(entry.value.as_deref()?, merge))
+        .next()
+        .ok_or(anyhow::anyhow!(
+            "upstream branch '{merge}' not stored as a remote-tracking branch"
+        ))
+}
+
+/// Maps a ref through the source side of a fetch refspec like `+refs/heads/*:refs/remotes/origin/*`.
+fn map_refspec(refspec: &str, name: &str) -> Option<String> {
+    let (source, destination) = refspec.trim_start_matches('+').split_once(':')?;
+    match (source.strip_suffix('*'), destination.strip_suffix('*')) {
+        (Some(source), Some(destination)) => name
+            .strip_prefix(source)
+            .map(|rest| format!("{destination}{rest}")),
+        _ => (source == name).then(|| destination.to_owned()),
+    }
+}
+
+/// Looks up `<rev>:<path>` in a tree, or `:[<stage>:]<path>` in the index.
+fn resolve_path(repo: &Repository, revision: &str, path: &str) -> anyhow::Result<ShaHash> {
+    if revision.is_empty() {
+        let (stage, path) = match path.split_once(':') {
+            Some((stage @ ("0" | "1" | "2" | "3"), path)) => (stage.parse::<u16>()?, path),
+            _ => (0, path),
+        };
+        let index = Index::load(&repo.index_file())?;
+        return index
+            .entries
+            .iter()
+            .find(|e| e.path == path.as_bytes() && e.stage() == stage)
+            .map(|e| e.hash)
+            .ok_or(anyhow::anyhow!("path '{path}' is not in the index"));
+    }
+
+    let mut hash = resolve_kind(repo, revision, ObjectKind::Tree)?;
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        let Object::Tree(tree) = repo.odb.read(&hash)? else {
+            anyhow::bail!("path '{path}' does not exist in '{revision}'");
+        };
+        hash = tree
+            .items
+            .iter()
+            .find(|item| item.name == component.as_bytes())
+            .map(|item| item.hash)
+            .ok_or(anyhow::anyhow!(
+                "path '{path}' does not exist in '{revision}'"
+            ))?;
+    }
+    Ok(hash)
+}
+
+/// Handles `^{type}`: an empty type peels tags, `object` only requires the object to exist.
+fn peel_to_type(db: &ObjectDatabase, hash: &ShaHash, kind: &str) -> anyhow::Result<ShaHash> {
+    match kind {
+        "" => peel(db, hash, None),
+        "object" => {
+            db.open_stream(hash)?;
+            Ok(*hash)
+        }
+        kind => peel(db, hash, Some(ObjectKind::parse(kind.as_bytes())?)),
+    }
+}
+
+/// Follows tags (and commits to their tree) until an object of the given type,
+/// or until an object that is not a tag if no type is given.
+pub fn peel(
+    db: &ObjectDatabase,
+    hash: &ShaHash,
+    kind: Option<ObjectKind>,
+) -> anyhow::Result<ShaHash> {
+    let mut current = *hash;
+    loop {
+        let current_kind = db.open_stream(&current)?.0;
+        if Some(current_kind) == kind {
+            return Ok(current);
+        }
+        current = match (current_kind, kind) {
+            (ObjectKind::Tag, _) => {
+                let Object::Tag(tag) = db.read(&current)? else {
+                    unreachable!("object kind was checked");
+                };
+                tag.object
+            }
+            (ObjectKind::Commit, Some(ObjectKind::Tree)) => {
+                let Object::Commit(commit) = db.read(&current)? else {
+                    unreachable!("object kind was checked");
+                };
+                commit.tree
+            }
+            (_, None) => return Ok(current),
+            (current_kind, Some(kind)) => anyhow::bail!(
+                "object {} is a {current_kind}, not a {kind}",
+                hex::encode(current)
+            ),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(split_range("a..b"), Some(("a", "b", false)));
+        assert_eq!(split_range("a...b"), Some(("a", "b", true)));
+        assert_eq!(split_range("..b"), Some(("", "b", false)));
+        assert_eq!(split_range("a..."), Some(("a", "", true)));
+        assert_eq!(split_range("v1.0..v2.0"), Some(("v1.0", "v2.0", false)));
+        assert_eq!(split_range("a..b:dir"), Some(("a", "b:dir", false)));
+        assert_eq!(split_range("HEAD:dir/a..b"), None);
+        assert_eq!(split_range(":0:a..b"), None);
+        assert_eq!(split_range("HEAD^{/fix..x}"), None);
+        assert_eq!(split_range("HEAD"), None);
+    }
+
+    #[test]
+    fn braces() {
+        assert_eq!(find_outside_braces("main@{u}~2", "~"), Some(8));
+        assert_eq!(find_outside_braces("a^{/x:y}:path", ":"), Some(8));
+        assert_eq!(find_outside_braces("a^{tree}", "{"), None);
+        assert_eq!(find_outside_braces("HEAD", "^"), None);
+        assert_eq!(find_outside_braces("café~2", "~"), Some(5));
+        assert_eq!(find_outside_braces("é^{/ü:x}:ö", ":"), Some(10));
+        assert_eq!(find_outside_braces("café", "~"), None);
+        assert_eq!(split_range("café..thé"), Some(("café", "thé", false)));
+    }
+
+    #[test]
+    fn refspecs() {
+        let refspec = "+refs/heads/*:refs/remotes/origin/*";
+        assert_eq!(
+            map_refspec(refspec, "refs/heads/topic/a").as_deref(),
+            Some("refs/remotes/origin/topic/a")
+        );
+        assert_eq!(map_refspec(refspec, "refs/tags/v1"), None);
+        assert_eq!(
+            map_refspec(
+                "refs/heads/main:refs/remotes/origin/main",
+                "refs/heads/main"
+            )
+            .as_deref(),
+            Some("refs/remotes/origin/main")
+        );
+        assert_eq!(map_refspec("refs/heads/main", "refs/heads/main"), None);
+    }
+}
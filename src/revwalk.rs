@@ -0,0 +1,384 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::object::{CommitObject, Object, ShaHash};
+use crate::odb::ObjectDatabase;
+
+#[derive(Default, Clone, Copy)]
+pub struct WalkOptions {
+    /// Never show a parent before all of its children.
+    pub topo_order: bool,
+    /// Follow only the first parent of merge commits.
+    pub first_parent: bool,
+    pub max_count: Option<usize>,
+}
+
+/// Iterates over the commits reachable from a set of tips but not from the excluded
+/// commits, newest committer date first.
+pub struct RevWalk<'a> {
+    db: &'a ObjectDatabase,
+    options: WalkOptions,
+    /// Commits ordered by committer date, ties are broken by insertion order.
+    queue: BinaryHeap<(i64, Reverse<usize>, ShaHash)>,
+    inserted: usize,
+    seen: HashSet<ShaHash>,
+    /// With `topo_order` the whole history is sorted upfront.
+    sorted: Option<Vec<ShaHash>>,
+    commits: HashMap<ShaHash, CommitObject>,
+    emitted: usize,
+}
+
+impl<'a> RevWalk<'a> {
+    pub fn new(
+        db: &'a ObjectDatabase,
+        tips: &[ShaHash],
+        excluded: &[ShaHash],
+        options: WalkOptions,
+    ) -> anyhow::Result<Self> {
+        // excluded history is treated as already seen so that it is never queued
+        let seen = match excluded.is_empty() {
+            true => HashSet::new(),
+            false => ancestors(db, excluded, false)?,
+        };
+        let tips = tips
+            .iter()
+            .filter(|tip| !seen.contains(*tip))
+            .copied()
+            .collect::<Vec<_>>();
+        let mut walk = Self {
+            db,
+            options,
+            queue: BinaryHeap::new(),
+            inserted: 0,
+            seen,
+            sorted: None,
+            commits: HashMap::new(),
+            emitted: 0,
+        };
+        for tip in &tips {
+            walk.push(tip)?;
+        }
+        if options.topo_order {
+            walk.sort_topologically(&tips)?;
+        }
+        Ok(walk)
+    }
+
+    fn load(&mut self, hash: &ShaHash) -> anyhow::Result<&CommitObject> {
+        if !self.commits.contains_key(hash) {
+            let Object::Commit(commit) = self.db.read(hash)? else {
+                anyhow::bail!("object {} is not a commit", hex::encode(hash));
+            };
+            self.commits.insert(*hash, commit);
+        }
+        Ok(&self.commits[hash])
+    }
+
+    fn push(&mut self, hash: &ShaHash) -> anyhow::Result<()> {
+        if !self.seen.insert(*hash) {
+            return Ok(());
+        }
+        let timestamp = self.load(hash)?.committer.timestamp;
+        self.queue.push((timestamp, Reverse(self.inserted), *hash));
+        self.inserted += 1;
+        Ok(())
+    }
+
+    fn parents(&mut self, hash: &ShaHash) -> anyhow::Result<Vec<ShaHash>> {
+        let first_parent = self.options.first_parent;
+        let commit = self.load(hash)?;
+        Ok(match first_parent {
+            true => commit.parents.iter().take(1).copied().collect(),
+            false => commit.parents.clone(),
+        })
+    }
+
+    /// Pops the next commit by date and queues its parents.
+    fn next_by_date(&mut self) -> anyhow::Result<Option<ShaHash>> {
+        let Some((_, _, hash)) = self.queue.pop() else {
+            return Ok(None);
+        };
+        for parent in self.parents(&hash)? {
+            self.push(&parent)?;
+        }
+        Ok(Some(hash))
+    }
+
+    /// Orders the history so that children come before their parents, following one
+    /// line of history as far as possible before switching to another.
+    fn sort_topologically(&mut self, tips: &[ShaHash]) -> anyhow::Result<()> {
+        let mut order = Vec::new();
+        while let Some(hash) = self.next_by_date()? {
+            order.push(hash);
+        }
+
+        // parents in the excluded history are not part of the order
+        let mut indegree: HashMap<ShaHash, usize> = order.iter().map(|h| (*h, 0)).collect();
+        for hash in &order {
+            for parent in self.parents(hash)? {
+                if let Some(count) = indegree.get_mut(&parent) {
+                    *count += 1;
+                }
+            }
+        }
+
+        // tips are popped in the order they were given
+        let mut stack: Vec<ShaHash> = Vec::new();
+        for tip in tips.iter().rev() {
+            if indegree.get(tip) == Some(&0) && !stack.contains(tip) {
+                stack.push(*tip);
+            }
+        }
+        let mut sorted = Vec::new();
+        while let Some(hash) = stack.pop() {
+            for parent in self.parents(&hash)? {
+                let Some(count) = indegree.get_mut(&parent) else {
+                    continue;
+                };
+                *count -= 1;
+                if *count == 0 {
+                    stack.push(parent);
+                }
+            }
+            sorted.push(hash);
+        }
+
+        sorted.reverse();
+        self.sorted = Some(sorted);
+        Ok(())
+    }
+
+    /// Returns the next commit of the walk.
+    pub fn next_commit(&mut self) -> anyhow::Result<Option<(ShaHash, &CommitObject)>> {
+        if self
+            .options
+            .max_count
+            .is_some_and(|max| self.emitted >= max)
+        {
+            return Ok(None);
+        }
+
+        let hash = match &mut self.sorted {
+            Some(sorted) => sorted.pop(),
+            None => self.next_by_date()?,
+        };
+        let Some(hash) = hash else {
+            return Ok(None);
+        };
+        self.emitted += 1;
+        Ok(Some((hash, self.load(&hash)?)))
+    }
+}
+
+/// All commits reachable from `tips`, including the tips.
+pub fn ancestors(
+    db: &ObjectDatabase,
+    tips: &[ShaHash],
+    first_parent: bool,
+) -> anyhow::Result<HashSet<ShaHash>> {
+    let mut seen = HashSet::new();
+    let mut pending = tips.to_vec();
+    while let Some(hash) = pending.pop() {
+        if !seen.insert(hash) {
+            continue;
+        }
+        let Object::Commit(commit) = db.read(&hash)? else {
+            anyhow::bail!("object {} is not a commit", hex::encode(hash));
+        };
+        let parents = match first_parent {
+            true => &commit.parents[..commit.parents.len().min(1)],
+            false => &commit.parents[..],
+        };
+        pending.extend(parents);
+    }
+    Ok(seen)
+}
+
+/// The best common ancestors of two commits: common ancestors that are not
+/// ancestors of another common ancestor.
+pub fn merge_bases(db: &ObjectDatabase, a: &ShaHash, b: &ShaHash) -> anyhow::Result<Vec<ShaHash>> {
+    let from_a = ancestors(db, &[*a], false)?;
+    let from_b = ancestors(db, &[*b], false)?;
+    let common = from_a
+        .intersection(&from_b)
+        .copied()
+        .collect::<HashSet<_>>();
+
+    let mut redundant = HashSet::new();
+    for hash in &common {
+        let Object::Commit(commit) = db.read(hash)? else {
+            unreachable!("ancestors are commits");
+        };
+        if !redundant.contains(hash) {
+            redundant.extend(ancestors(db, &commit.parents, false)?);
+        }
+    }
+
+    let mut bases = common.difference(&redundant).copied().collect::<Vec<_>>();
+    bases.sort();
+    Ok(bases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{ObjectKind, Signature};
+    use crate::testutil::TempDir;
+
+    fn commit(db: &ObjectDatabase, parents: &[ShaHash], timestamp: i64) -> ShaHash {
+        let signature = Signature {
+            name: "A U Thor".to_owned(),
+            email: "author@example.com".to_owned(),
+            timestamp,
+            timezone: "+0000".to_owned(),
+        };
+        let commit = CommitObject::new(
+            [0; 20],
+            parents.to_vec(),
+            signature.clone(),
+            signature,
+            format!("{timestamp}\n"),
+        );
+        let commit = Object::Commit(commit);
+        db.write(ObjectKind::Commit, &commit.serialize()).unwrap()
+    }
+
+    /// A merge of two branches whose commit dates interleave:
+    /// `root - a - b - merge` and `root - c - merge`.
+    struct History {
+        root: ShaHash,
+        a: ShaHash,
+        b: ShaHash,
+        c: ShaHash,
+        merge: ShaHash,
+    }
+
+    fn history(db: &ObjectDatabase) -> History {
+        let root = commit(db, &[], 1);
+        let a = commit(db, &[root], 2);
+        let c = commit(db, &[root], 3);
+        let b = commit(db, &[a], 4);
+        let merge = commit(db, &[b, c], 5);
+        History {
+            root,
+            a,
+            b,
+            c,
+            merge,
+        }
+    }
+
+    fn walk(
+        db: &ObjectDatabase,
+        tips: &[ShaHash],
+        excluded: &[ShaHash],
+        options: WalkOptions,
+    ) -> Vec<ShaHash> {
+        let mut walk = RevWalk::new(db, tips, excluded, options).unwrap();
+        let mut commits = Vec::new();
+        while let Some((hash, _)) = walk.next_commit().unwrap() {
+            commits.push(hash);
+        }
+        commits
+    }
+
+    fn open_db(dir: &TempDir) -> ObjectDatabase {
+        std::fs::create_dir(dir.path().join("objects")).unwrap();
+        ObjectDatabase::open(&dir.path().join("objects")).unwrap()
+    }
+
+    #[test]
+    fn date_order() {
+        let dir = TempDir::new();
+        let db = open_db(&dir);
+        let History {
+            root,
+            a,
+            b,
+            c,
+            merge,
+        } = history(&db);
+
+        let options = WalkOptions::default();
+        assert_eq!(walk(&db, &[merge], &[], options), [merge, b, c, a, root]);
+        // tips already reached from another tip are shown once
+        assert_eq!(walk(&db, &[a, b], &[], options), [b, a, root]);
+
+        let options = WalkOptions {
+            first_parent: true,
+            ..Default::default()
+        };
+        assert_eq!(walk(&db, &[merge], &[], options), [merge, b, a, root]);
+    }
+
+    #[test]
+    fn topological_order() {
+        let dir = TempDir::new();
+        let db = open_db(&dir);
+        let History {
+            root,
+            a,
+            b,
+            c,
+            merge,
+        } = history(&db);
+
+        let options = WalkOptions {
+            topo_order: true,
+            ..Default::default()
+        };
+        // one line of history is finished before the other one is shown
+        assert_eq!(walk(&db, &[merge], &[], options), [merge, c, b, a, root]);
+        assert_eq!(walk(&db, &[c, b], &[], options), [c, b, a, root]);
+    }
+
+    #[test]
+    fn max_count() {
+        let dir = TempDir::new();
+        let db = open_db(&dir);
+        let History { b, c, merge, .. } = history(&db);
+
+        for topo_order in [false, true] {
+            let options = WalkOptions {
+                topo_order,
+                max_count: Some(2),
+                ..Default::default()
+            };
+            let expected = if topo_order { [merge, c] } else { [merge, b] };
+            assert_eq!(walk(&db, &[merge], &[], options), expected);
+        }
+        let options = WalkOptions {
+            max_count: Some(0),
+            ..Default::default()
+        };
+        assert!(walk(&db, &[merge], &[], options).is_empty());
+    }
+
+    #[test]
+    fn excluded_history() {
+        let dir = TempDir::new();
+        let db = open_db(&dir);
+        let History {
+            root,
+            a,
+            b,
+            c,
+            merge,
+        } = history(&db);
+
+        for topo_order in [false, true] {
+            let options = WalkOptions {
+                topo_order,
+                ..Default::default()
+            };
+            // `c..merge`
+            assert_eq!(walk(&db, &[merge], &[c], options), [merge, b, a]);
+            // `merge ^b`
+            assert_eq!(walk(&db, &[merge], &[b], options), [merge, c]);
+            assert!(walk(&db, &[a], &[merge], options).is_empty());
+            assert_eq!(walk(&db, &[b, c], &[a], options).len(), 2);
+        }
+        assert_eq!(merge_bases(&db, &b, &c).unwrap(), [root]);
+        assert_eq!(merge_bases(&db, &merge, &a).unwrap(), [a]);
+    }
+}
@@ -1,452 +1,2689 @@
-use anyhow::Context;
-use chrono::Local;
 use flate2::bufread::ZlibDecoder;
-use flate2::write::ZlibEncoder;
-use flate2::Compression;
 use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
 use std::fs::File;
+use std::fs::{Metadata, Permissions};
 use std::io::{self, BufRead, Read};
-use std::io::{BufReader, BufWriter, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::io::{BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::str;
-use std::{env, fs};
 
-use crate::object::{BlobObject, TreeObject};
+use crate::config::{self, Config, Edit, Scope};
+use crate::dircache::{is_parent_path, Index, IndexEntry};
+use crate::ident::{self, Role};
+use crate::ignore::IgnoreRules;
+use crate::object::{
+    compute_hash, parse_hash, CommitObject, Object, ObjectKind, ShaHash, TreeItem, TreeObject,
+};
+use crate::odb::ObjectDatabase;
+use crate::pack::{
+    apply_delta, object_kind_from_pack_type, parse_base_offset, CountingReader, ObjectSizeType,
+};
+use crate::pktline::{Packet, PktReader, PktWriter, SideBandReader};
+use crate::pretty::Format;
+use crate::refs::{RefValue, NULL_HASH};
+use crate::repository::Repository;
+use crate::revision::{self, RevisionArg};
+use crate::revwalk::{RevWalk, WalkOptions};
+use itertools::Itertools;
 
-const TEMPORARY: &str = "temporary";
+pub fn init() -> anyhow::Result<()> {
+    Repository::init(Path::new("."))?;
 
-fn object_path_from_hash(hash: &str) -> String {
-    format!(".git/objects/{}/{}", &hash[0..2], &hash[2..])
+    Ok(())
 }
 
-pub fn init() -> anyhow::Result<()> {
-    fs::create_dir(".git")?;
-    fs::create_dir(".git/objects")?;
-    fs::create_dir(".git/refs")?;
-    File::create_new(".git/HEAD")?.write_all(b"ref: refs/heads/main\n")?;
+/// What `cat-file` shows about an object.
+#[derive(Debug, Clone, Copy)]
+pub enum CatFileMode {
+    Type,
+    Size,
+    /// Only checks that the object exists.
+    Exists,
+    /// Content formatted according to the object type.
+    Pretty,
+    /// Raw content of the object peeled to the given type.
+    Typed(ObjectKind),
+}
+
+/// Returns `false` if the object does not exist with `CatFileMode::Exists`.
+pub fn cat_file(repo: &Repository, spec: &str, mode: CatFileMode) -> anyhow::Result<bool> {
+    let mut hash = match revision::resolve(repo, spec) {
+        Ok(hash) => hash,
+        Err(_) if matches!(mode, CatFileMode::Exists) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    if let CatFileMode::Typed(kind) = mode {
+        hash = revision::peel(&repo.odb, &hash, Some(kind))?;
+    }
+    let (kind, size, mut reader) = match repo.odb.open_stream(&hash) {
+        Ok(object) => object,
+        Err(_) if matches!(mode, CatFileMode::Exists) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    let mut stdout = io::stdout().lock();
+    match (mode, kind) {
+        (CatFileMode::Type, _) => writeln!(stdout, "{kind}")?,
+        (CatFileMode::Size, _) => writeln!(stdout, "{size}")?,
+        (CatFileMode::Exists, _) => {}
+        (CatFileMode::Pretty, ObjectKind::Tree) => {
+            let Object::Tree(tree) = repo.odb.read(&hash)? else {
+                unreachable!("object kind was checked");
+            };
+            for item in &tree.items {
+                write!(
+                    stdout,
+                    "{:06o} {} {}\t",
+                    item.mode,
+                    item.kind(),
+                    hex::encode(item.hash)
+                )?;
+                stdout.write_all(&item.name)?;
+                writeln!(stdout)?;
+            }
+        }
+        _ => {
+            // stream the content as blobs can be arbitrarily large
+            let written = io::copy(&mut reader, &mut stdout)?;
+            if written != size as u64 {
+                anyhow::bail!(
+                    "Object content size {size}: does not match the actual content: {written}"
+                );
+            }
+        }
+    }
+    stdout.flush()?;
+
+    Ok(true)
+}
+
+/// The default output format of `cat-file --batch` and `--batch-check`.
+const BATCH_FORMAT: &str = "%(objectname) %(objecttype) %(objectsize)";
+
+/// How `cat-file` handles the object names read from stdin.
+#[derive(Debug, Clone, Copy)]
+pub enum CatFileBatch {
+    /// Only the information line of every object.
+    Check,
+    /// The information line followed by the content.
+    Contents,
+    /// `info` and `contents` commands choose between the two per object.
+    Command,
+}
+
+/// Prints the objects named on stdin with a single object database.
+pub fn cat_file_batch(
+    repo: &Repository,
+    batch: CatFileBatch,
+    format: Option<&str>,
+    buffer: bool,
+) -> anyhow::Result<()> {
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    write_batch(repo, batch, format, buffer, io::stdin().lock(), &mut out)
+}
+
+/// Answers the requests read from `input`, `out` is flushed after each one unless `buffer`.
+fn write_batch(
+    repo: &Repository,
+    batch: CatFileBatch,
+    format: Option<&str>,
+    buffer: bool,
+    input: impl BufRead,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let format = format.unwrap_or(BATCH_FORMAT);
+    // check the format before reading any input
+    expand_batch_format(format, &NULL_HASH, ObjectKind::Blob, 0, "")?;
+    // with %(rest) only the first word of a line names the object
+    let split_rest = format.contains("%(rest)");
+
+    for line in input.lines() {
+        let line = line?;
+        let (contents, spec) = match batch {
+            CatFileBatch::Check => (false, line.as_str()),
+            CatFileBatch::Contents => (true, line.as_str()),
+            CatFileBatch::Command => {
+                let (command, argument) = line.split_once(' ').unwrap_or((&line, ""));
+                match command {
+                    "" => anyhow::bail!("empty command in input"),
+                    "flush" if !buffer => anyhow::bail!("flush is only for --buffer mode"),
+                    "flush" if !argument.is_empty() => anyhow::bail!("flush takes no arguments"),
+                    "flush" => {
+                        out.flush()?;
+                        continue;
+                    }
+                    "info" | "contents" if argument.is_empty() => {
+                        anyhow::bail!("{command} requires arguments")
+                    }
+                    "info" => (false, argument),
+                    "contents" => (true, argument),
+                    _ => anyhow::bail!("unknown command: '{line}'"),
+                }
+            }
+        };
+        let (spec, rest) = match split_rest {
+            true => spec
+                .split_once(char::is_whitespace)
+                .map(|(spec, rest)| (spec, rest.trim_start()))
+                .unwrap_or((spec, "")),
+            false => (spec, ""),
+        };
+
+        let object =
+            revision::resolve(repo, spec).and_then(|hash| Ok((hash, repo.odb.open_stream(&hash)?)));
+        match object {
+            Ok((hash, (kind, size, mut reader))) => {
+                writeln!(
+                    out,
+                    "{}",
+                    expand_batch_format(format, &hash, kind, size, rest)?
+                )?;
+                if contents {
+                    // the size was already written, a corrupt object must not go unnoticed
+                    let copied = io::copy(&mut reader, out)?;
+                    if copied != size as u64 {
+                        anyhow::bail!(
+                            "object {} has {copied} bytes but its header says {size}",
+                            hex::encode(hash)
+                        );
+                    }
+                    writeln!(out)?;
+                }
+            }
+            Err(_) => writeln!(out, "{spec} missing")?,
+        }
+        if !buffer {
+            out.flush()?;
+        }
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Expands the `%(atom)` placeholders of a batch format.
+fn expand_batch_format(
+    format: &str,
+    hash: &ShaHash,
+    kind: ObjectKind,
+    size: usize,
+    rest: &str,
+) -> anyhow::Result<String> {
+    let mut out = String::new();
+    let mut remaining = format;
+    while let Some(start) = remaining.find("%(") {
+        out.push_str(&remaining[..start]);
+        let end = remaining[start..]
+            .find(')')
+            .map(|end| start + end)
+            .ok_or(anyhow::anyhow!(
+                "unterminated format element: {}",
+                &remaining[start..]
+            ))?;
+        match &remaining[start + 2..end] {
+            "objectname" => out.push_str(&hex::encode(hash)),
+            "objecttype" => out.push_str(kind.as_str()),
+            "objectsize" => out.push_str(&size.to_string()),
+            "rest" => out.push_str(rest),
+            atom => anyhow::bail!("unknown format element: {atom}"),
+        }
+        remaining = &remaining[end + 1..];
+    }
+    out.push_str(remaining);
+
+    Ok(out)
+}
+
+pub struct LsTreeOptions {
+    pub recursive: bool,
+    pub show_trees: bool,
+    pub trees_only: bool,
+    pub long: bool,
+    pub null_terminated: bool,
+    pub name_only: bool,
+    pub abbrev: Option<usize>,
+}
+
+pub fn ls_tree(
+    repo: &Repository,
+    spec: &str,
+    paths: &[String],
+    options: &LsTreeOptions,
+) -> anyhow::Result<()> {
+    let tree = revision::resolve_kind(repo, spec, ObjectKind::Tree)?;
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    list_tree(repo, &tree, &[], paths, options, &mut stdout)?;
+    stdout.flush()?;
 
     Ok(())
 }
 
-pub fn cat_file(hash: &str) -> anyhow::Result<()> {
-    let object = File::open(object_path_from_hash(hash))
-        .with_context(|| anyhow::anyhow!("cannot open hash object file: {hash}"))?;
-    let mut bufreader = BufReader::new(ZlibDecoder::new(BufReader::new(object)));
-    let blob = BlobObject::read(&mut bufreader)?;
-    print!("{}", blob.content);
+fn list_tree(
+    repo: &Repository,
+    hash: &ShaHash,
+    base: &[u8],
+    paths: &[String],
+    options: &LsTreeOptions,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let Object::Tree(tree) = repo.odb.read(hash)? else {
+        anyhow::bail!("object {} is not a tree", hex::encode(hash));
+    };
+
+    for item in &tree.items {
+        let path = [base, &item.name].concat();
+        let dir = [&path[..], b"/"].concat();
+        let is_tree = item.kind() == ObjectKind::Tree;
+        // a path is listed if it is inside a pathspec, trees leading to a pathspec are walked
+        let interesting = paths.is_empty()
+            || paths.iter().map(String::as_bytes).any(|spec| {
+                let spec_dir = match spec.ends_with(b"/") {
+                    true => spec.to_vec(),
+                    false => [spec, b"/"].concat(),
+                };
+                path == spec || path.starts_with(&spec_dir) || (is_tree && spec.starts_with(&dir))
+            });
+        if !interesting {
+            continue;
+        }
+
+        let descend = is_tree
+            && (options.recursive || paths.iter().any(|spec| spec.as_bytes().starts_with(&dir)));
+        // -d with -r shows the trees it recurses into
+        let show_trees = options.show_trees || (options.trees_only && options.recursive);
+        let show = match descend {
+            true => show_trees,
+            false => is_tree || !options.trees_only,
+        };
+        if show {
+            write_tree_entry(repo, item, &path, options, out)?;
+        }
+        if descend {
+            list_tree(repo, &item.hash, &dir, paths, options, out)?;
+        }
+    }
 
     Ok(())
 }
 
-pub fn ls_tree(hash: &str) -> anyhow::Result<()> {
-    let object = File::open(object_path_from_hash(hash))
-        .with_context(|| anyhow::anyhow!("cannot open hash object file: {hash}"))?;
-    let mut bufreader = BufReader::new(ZlibDecoder::new(BufReader::new(object)));
-    let tree = TreeObject::read(&mut bufreader)?;
-    for entry in tree.items {
-        println!("{}", entry.name);
+fn write_tree_entry(
+    repo: &Repository,
+    item: &TreeItem,
+    path: &[u8],
+    options: &LsTreeOptions,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let terminator = if options.null_terminated {
+        b"\0"
+    } else {
+        b"\n"
+    };
+    if !options.name_only {
+        let hash = match options.abbrev {
+            Some(len) => repo.odb.abbreviate(&item.hash, len)?,
+            None => hex::encode(item.hash),
+        };
+        write!(out, "{:06o} {} {hash}", item.mode, item.kind())?;
+        if options.long {
+            let size = match item.kind() {
+                ObjectKind::Blob => repo.odb.open_stream(&item.hash)?.1.to_string(),
+                _ => "-".to_owned(),
+            };
+            write!(out, " {size:>7}")?;
+        }
+        write!(out, "\t")?;
     }
+    out.write_all(path)?;
+    out.write_all(terminator)?;
 
     Ok(())
 }
 
-pub fn hash_object(path: &PathBuf, write: bool) -> anyhow::Result<String> {
+pub fn ls_files(repo: &Repository, stage: bool) -> anyhow::Result<()> {
+    let index = Index::load(&repo.index_file())?;
+    let mut stdout = io::stdout().lock();
+    for entry in index.entries {
+        if stage {
+            write!(
+                stdout,
+                "{:06o} {} {}\t",
+                entry.mode,
+                hex::encode(entry.hash),
+                entry.stage()
+            )?;
+        }
+        stdout.write_all(&entry.path)?;
+        stdout.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+/// Hashes a file, the blob is stored only if a repository is provided.
+pub fn hash_object(repo: Option<&Repository>, path: &Path) -> anyhow::Result<String> {
+    Ok(hex::encode(hash_file(repo.map(|r| &r.odb), path)?))
+}
+
+/// Computes the blob hash of a file and stores it if a database is provided.
+fn hash_file(db: Option<&ObjectDatabase>, path: &Path) -> anyhow::Result<ShaHash> {
     let file = File::open(path)?;
-    let metadata = file.metadata()?;
+    let size = file.metadata()?.len();
     let mut reader = BufReader::new(file);
 
+    if let Some(db) = db {
+        return db.write_stream(ObjectKind::Blob, size, &mut reader);
+    }
+
     let mut hasher = Sha1::new();
-    let header = format!("blob {}\0", metadata.len());
+    let header = format!("blob {size}\0");
     hasher.update(&header);
     io::copy(&mut reader, &mut hasher)?;
 
-    let hash = hex::encode(hasher.finalize()).to_string();
-    if write {
-        let mut blob_file: File = File::open(path)?;
-        let blob_object_path = PathBuf::from(object_path_from_hash(&hash));
-        if let Some(folder) = blob_object_path.parent() {
-            if !folder.exists() {
-                fs::create_dir(folder)?;
+    Ok(hasher.finalize().into())
+}
+
+fn matches_pathspec(spec: &[u8], path: &[u8]) -> bool {
+    spec == path || is_parent_path(spec, path)
+}
+
+/// Creates an index entry for a worktree file, the blob is stored if `write` is set.
+fn worktree_entry(repo: &Repository, write: bool, name: &[u8]) -> anyhow::Result<IndexEntry> {
+    let db = if write { Some(&repo.odb) } else { None };
+    let path = repo.worktree_path(name)?;
+    let path = path.as_path();
+    let metadata = fs::symlink_metadata(path)?;
+    let (mode, hash) = if metadata.is_symlink() {
+        let target = fs::read_link(path)?;
+        let target = target.as_os_str().as_bytes();
+        let hash = match db {
+            Some(db) => db.write(ObjectKind::Blob, target)?,
+            None => compute_hash(ObjectKind::Blob, target),
+        };
+        (0o120000, hash)
+    } else if metadata.is_file() {
+        (worktree_mode(&metadata), hash_file(db, path)?)
+    } else {
+        anyhow::bail!("unsupported file type: {}", path.display());
+    };
+
+    Ok(IndexEntry::from_metadata(
+        name.to_vec(),
+        mode,
+        hash,
+        &metadata,
+    ))
+}
+
+/// Lists all files (anything but directories) below `dir`, the `.git` directory and
+/// the ignored paths, unless `ignore` is `None`, are skipped.
+fn walk_worktree(
+    dir: &Path,
+    prefix: &[u8],
+    mut ignore: Option<&mut IgnoreRules>,
+    files: &mut Vec<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, io::Error>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let filename = entry.file_name();
+        if filename == ".git" {
+            continue;
+        }
+
+        let mut name = prefix.to_vec();
+        if !name.is_empty() {
+            name.push(b'/');
+        }
+        name.extend(filename.as_bytes());
+
+        let is_dir = entry.file_type()?.is_dir();
+        if let Some(ignore) = ignore.as_deref_mut() {
+            if ignore.is_ignored(&name, is_dir)? {
+                continue;
+            }
+        }
+        if is_dir {
+            walk_worktree(&entry.path(), &name, ignore.as_deref_mut(), files)?;
+        } else {
+            files.push(name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Stages the given paths. `all` is `Some(true)` for `-A`, which stages the whole
+/// tree when no paths are given, and `Some(false)` for `--ignore-removal`, which keeps
+/// the entries of files missing from the worktree.
+pub fn add(
+    repo: &Repository,
+    paths: &[PathBuf],
+    update: bool,
+    all: Option<bool>,
+    force: bool,
+) -> anyhow::Result<()> {
+    let specs: Vec<Vec<u8>> = if paths.is_empty() {
+        if !update && all != Some(true) {
+            anyhow::bail!("Nothing specified, nothing added.");
+        }
+        vec![Vec::new()]
+    } else {
+        paths
+            .iter()
+            .map(|p| repo.relative_path(p))
+            .collect::<anyhow::Result<Vec<_>>>()?
+    };
+
+    let index_file = repo.index_file();
+    let mut index = Index::load(&index_file)?;
+    let index_mtime = file_mtime(&index_file);
+    let mut ignore = IgnoreRules::load(repo)?;
+    let mut ignored = Vec::new();
+    for spec in specs {
+        let mut matched = false;
+
+        // refresh tracked files, the ones gone from the worktree are removed
+        // unless removals are ignored
+        let tracked = index
+            .entries
+            .iter()
+            .filter(|e| matches_pathspec(&spec, &e.path))
+            .map(|e| e.path.clone())
+            .dedup()
+            .collect_vec();
+        for path in tracked {
+            matched = true;
+            match fs::symlink_metadata(repo.worktree_path(&path)?) {
+                Ok(metadata) if !metadata.is_dir() => {
+                    // unchanged files keep their entry instead of being hashed again
+                    let unchanged = index.find(&path).is_some_and(|entry| {
+                        entry.stage() == 0
+                            && entry.mode == worktree_mode(&metadata)
+                            && entry.is_stat_clean(&metadata)
+                            && !is_racy(entry, index_mtime)
+                    });
+                    if !unchanged {
+                        index.add(worktree_entry(repo, true, &path)?);
+                    }
+                }
+                _ if all == Some(false) => {}
+                _ => {
+                    index.remove(&path);
+                }
+            }
+        }
+
+        if !update {
+            let path = repo.worktree_path(&spec)?;
+            let mut files = Vec::new();
+            if let Ok(metadata) = fs::symlink_metadata(&path) {
+                let is_dir = metadata.is_dir();
+                // explicitly named paths that are ignored are reported instead of added
+                if !force && !matched && !spec.is_empty() && ignore.is_ignored(&spec, is_dir)? {
+                    ignored.push(spec.clone());
+                    matched = true;
+                } else if is_dir {
+                    let filter = (!force).then_some(&mut ignore);
+                    walk_worktree(&path, &spec, filter, &mut files)?;
+                } else {
+                    files.push(spec.clone());
+                }
+            }
+            for file in files {
+                matched = true;
+                if index.find(&file).is_none() {
+                    index.add(worktree_entry(repo, true, &file)?);
+                }
+            }
+        }
+
+        if !matched && !paths.is_empty() {
+            anyhow::bail!(
+                "pathspec '{}' did not match any files",
+                String::from_utf8_lossy(&spec)
+            );
+        }
+    }
+
+    index.write(&index_file)?;
+    if !ignored.is_empty() {
+        let paths = ignored
+            .iter()
+            .map(|p| String::from_utf8_lossy(p))
+            .join("\n");
+        anyhow::bail!(
+            "The following paths are ignored by one of your .gitignore files:\n{paths}\nhint: Use -f if you really want to add them."
+        );
+    }
+
+    Ok(())
+}
+
+pub fn rm(
+    repo: &Repository,
+    paths: &[PathBuf],
+    cached: bool,
+    recursive: bool,
+    force: bool,
+) -> anyhow::Result<()> {
+    let index_file = repo.index_file();
+    let mut index = Index::load(&index_file)?;
+    let (_, head) = repo.refs.resolve("HEAD")?;
+    let head_entries = head_entries(repo, head.as_ref())?;
+
+    let mut removed = Vec::new();
+    for path in paths {
+        let spec = repo.relative_path(path)?;
+        let matching = index
+            .entries
+            .iter()
+            .filter(|e| matches_pathspec(&spec, &e.path))
+            .collect_vec();
+        if matching.is_empty() {
+            anyhow::bail!("pathspec '{}' did not match any files", path.display());
+        }
+        if !recursive && matching.iter().any(|e| e.path != spec) {
+            anyhow::bail!("not removing '{}' recursively without -r", path.display());
+        }
+
+        for entry in matching {
+            // files already gone from the worktree have nothing left to lose
+            if !force && fs::symlink_metadata(repo.worktree_path(&entry.path)?).is_ok() {
+                let staged = head_entries.get(&entry.path) != Some(&(entry.mode, entry.hash));
+                let modified = worktree_entry(repo, false, &entry.path)?.hash != entry.hash;
+                let name = String::from_utf8_lossy(&entry.path);
+                match (staged, modified) {
+                    (true, true) => anyhow::bail!(
+                        "'{name}' has staged content different from both the file and the HEAD (use -f to force removal)"
+                    ),
+                    (true, false) if !cached => anyhow::bail!(
+                        "'{name}' has changes staged in the index (use --cached to keep the file, or -f to force removal)"
+                    ),
+                    (false, true) if !cached => anyhow::bail!(
+                        "'{name}' has local modifications (use --cached to keep the file, or -f to force removal)"
+                    ),
+                    _ => {}
+                }
+            }
+            removed.push(entry.path.clone());
+        }
+    }
+
+    for path in removed.into_iter().dedup() {
+        index.remove(&path);
+        if !cached {
+            let file = repo.worktree_path(&path)?;
+            match fs::remove_file(&file) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            // drop directories that became empty
+            let work_tree = repo.work_tree()?;
+            let mut dir = file.parent();
+            while let Some(parent) = dir.filter(|d| *d != work_tree) {
+                if fs::remove_dir(parent).is_err() {
+                    break;
+                }
+                dir = parent.parent();
             }
         }
-        if blob_object_path.exists() {
-            return Ok(hash);
+        println!("rm '{}'", String::from_utf8_lossy(&path));
+    }
+
+    index.write(&index_file)
+}
+
+/// Collects all blobs (and gitlinks) of a tree keyed by their full path.
+fn flatten_tree(
+    db: &ObjectDatabase,
+    tree: &ShaHash,
+    prefix: &[u8],
+    entries: &mut BTreeMap<Vec<u8>, (u32, ShaHash)>,
+) -> anyhow::Result<()> {
+    let Object::Tree(tree) = db.read(tree)? else {
+        anyhow::bail!("object {} is not a tree", hex::encode(tree));
+    };
+
+    for item in tree.items {
+        let mut name = prefix.to_vec();
+        if !name.is_empty() {
+            name.push(b'/');
         }
-        let object_file = BufWriter::new(File::create(blob_object_path)?);
-        let mut encoder = ZlibEncoder::new(object_file, Compression::fast());
+        name.extend(&item.name);
 
-        encoder.write_all(header.as_bytes())?;
-        io::copy(&mut blob_file, &mut encoder)?;
+        if item.kind() == ObjectKind::Tree {
+            flatten_tree(db, &item.hash, &name, entries)?;
+        } else {
+            entries.insert(name, (item.mode, item.hash));
+        }
     }
 
-    Ok(hash)
+    Ok(())
 }
 
-fn write_dir_hash(path: &Path) -> anyhow::Result<String> {
-    let mut entries = fs::read_dir(path)?
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, io::Error>>()?;
-    entries.sort();
+const MODE_TYPE_MASK: u32 = 0o170000;
+
+/// Change code of a path between two states (HEAD, index or worktree) as used by `status -s`.
+fn change_code(old: Option<(u32, ShaHash)>, new: Option<(u32, ShaHash)>) -> u8 {
+    match (old, new) {
+        (None, None) => b' ',
+        (None, Some(_)) => b'A',
+        (Some(_), None) => b'D',
+        (Some((old_mode, _)), Some((new_mode, _)))
+            if old_mode & MODE_TYPE_MASK != new_mode & MODE_TYPE_MASK =>
+        {
+            b'T'
+        }
+        (Some(old), Some(new)) if old != new => b'M',
+        _ => b' ',
+    }
+}
 
-    let mut content = Vec::new();
+struct FileStatus {
+    path: Vec<u8>,
+    head: Option<(u32, ShaHash)>,
+    index: Option<(u32, ShaHash)>,
+    /// Mode and hash of the worktree file, the hash is the index one for unmodified files.
+    worktree: Option<(u32, ShaHash)>,
+}
+
+impl FileStatus {
+    fn staged(&self) -> u8 {
+        change_code(self.head, self.index)
+    }
+
+    fn unstaged(&self) -> u8 {
+        match self.index {
+            Some(_) => change_code(self.index, self.worktree),
+            None => b' ',
+        }
+    }
+}
+
+struct Conflict {
+    path: Vec<u8>,
+    stages: [Option<(u32, ShaHash)>; 3],
+    worktree_mode: u32,
+}
+
+impl Conflict {
+    fn code(&self) -> &'static str {
+        match self.stages.map(|s| s.is_some()) {
+            [true, true, true] => "UU",
+            [false, true, true] => "AA",
+            [true, true, false] => "UD",
+            [true, false, true] => "DU",
+            [false, true, false] => "AU",
+            [false, false, true] => "UA",
+            _ => "DD",
+        }
+    }
+}
+
+fn file_mtime(path: &Path) -> Option<(i64, i64)> {
+    fs::metadata(path).ok().map(|m| (m.mtime(), m.mtime_nsec()))
+}
+
+/// Entries modified in the same second as the index was written cannot be trusted
+/// to be unchanged when their stat data match.
+fn is_racy(entry: &IndexEntry, index_mtime: Option<(i64, i64)>) -> bool {
+    index_mtime.is_some_and(|mtime| (entry.mtime_sec as i64, entry.mtime_nsec as i64) >= mtime)
+}
+
+fn worktree_mode(metadata: &Metadata) -> u32 {
+    if metadata.is_symlink() {
+        0o120000
+    } else if metadata.permissions().mode() & 0o100 != 0 {
+        0o100755
+    } else {
+        0o100644
+    }
+}
+
+/// Lists untracked files that are not ignored, directories without any tracked file
+/// are reported as `dir/`.
+fn untracked_files(
+    index: &Index,
+    ignore: &mut IgnoreRules,
+    dir: &Path,
+    prefix: &[u8],
+    untracked: &mut Vec<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, io::Error>>()?;
+    entries.sort_by_key(|e| e.file_name());
     for entry in entries {
-        let filename = entry
-            .file_name()
-            .ok_or(anyhow::anyhow!("missing filename"))?
-            .to_str()
-            .ok_or(anyhow::anyhow!("cannot get string"))?;
-        if filename.starts_with('.') {
+        let filename = entry.file_name();
+        if filename == ".git" {
             continue;
         }
 
-        let hash: String = if entry.is_file() {
-            hash_object(&entry, true)
-        } else {
-            write_dir_hash(&entry)
-        }?;
-        let hash = hex::decode(hash)?;
+        let mut name = prefix.to_vec();
+        if !name.is_empty() {
+            name.push(b'/');
+        }
+        name.extend(filename.as_bytes());
+
+        let is_dir = entry.file_type()?.is_dir();
+        if is_dir {
+            // a tracked directory is a submodule, untracked files below an ignored
+            // directory are ignored as well
+            if index.find(&name).is_some() || ignore.is_ignored(&name, true)? {
+                continue;
+            }
+            if index.contains_dir(&name) {
+                untracked_files(index, ignore, &entry.path(), &name, untracked)?;
+            } else {
+                let mut files = Vec::new();
+                walk_worktree(&entry.path(), &name, Some(ignore), &mut files)?;
+                if !files.is_empty() {
+                    name.push(b'/');
+                    untracked.push(name);
+                }
+            }
+        } else if index.find(&name).is_none() && !ignore.is_ignored(&name, false)? {
+            untracked.push(name);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum StatusFormat {
+    Long,
+    Short,
+    PorcelainV2,
+}
+
+/// Blobs and gitlinks of the tree of the commit `HEAD` points to, empty on an unborn branch.
+fn head_entries(
+    repo: &Repository,
+    head: Option<&ShaHash>,
+) -> anyhow::Result<BTreeMap<Vec<u8>, (u32, ShaHash)>> {
+    let mut entries = BTreeMap::new();
+    if let Some(head) = head {
+        let Object::Commit(commit) = repo.odb.read(head)? else {
+            anyhow::bail!("HEAD {} is not a commit", hex::encode(head));
+        };
+        flatten_tree(&repo.odb, &commit.tree, &[], &mut entries)?;
+    }
+    Ok(entries)
+}
 
-        let mode = if entry.is_file() {
-            if entry.metadata()?.permissions().mode() & 0o011 != 0 {
-                "100755"
+/// Differences between HEAD, the index and the worktree.
+struct WorktreeStatus {
+    /// Changed paths, staged or not, sorted by path.
+    files: Vec<FileStatus>,
+    conflicts: Vec<Conflict>,
+    untracked: Vec<Vec<u8>>,
+}
+
+fn collect_status(repo: &Repository, head: Option<&ShaHash>) -> anyhow::Result<WorktreeStatus> {
+    let index_file = repo.index_file();
+    let index = Index::load(&index_file)?;
+    let index_mtime = file_mtime(&index_file);
+    let head_entries = head_entries(repo, head)?;
+
+    let mut files = Vec::new();
+    let mut conflicts: Vec<Conflict> = Vec::new();
+    for entry in &index.entries {
+        // submodules are directories, for other entries a directory means the file is gone
+        let metadata = fs::symlink_metadata(repo.worktree_path(&entry.path)?)
+            .ok()
+            .filter(|m| !m.is_dir() || entry.mode == 0o160000);
+
+        if entry.stage() != 0 {
+            if conflicts.last().map(|c| &c.path) != Some(&entry.path) {
+                conflicts.push(Conflict {
+                    path: entry.path.clone(),
+                    stages: [None; 3],
+                    worktree_mode: metadata.as_ref().map_or(0, worktree_mode),
+                });
+            }
+            let conflict = conflicts.last_mut().unwrap();
+            conflict.stages[entry.stage() as usize - 1] = Some((entry.mode, entry.hash));
+            continue;
+        }
+
+        let worktree = match metadata {
+            None => None,
+            Some(metadata) => {
+                let mode = worktree_mode(&metadata);
+                if entry.mode == 0o160000
+                    || (mode == entry.mode
+                        && entry.is_stat_clean(&metadata)
+                        && !is_racy(entry, index_mtime))
+                {
+                    Some((entry.mode, entry.hash))
+                } else {
+                    Some((mode, worktree_entry(repo, false, &entry.path)?.hash))
+                }
+            }
+        };
+        files.push(FileStatus {
+            path: entry.path.clone(),
+            head: head_entries.get(&entry.path).copied(),
+            index: Some((entry.mode, entry.hash)),
+            worktree,
+        });
+    }
+    for (path, head) in &head_entries {
+        if index.find(path).is_none() {
+            files.push(FileStatus {
+                path: path.clone(),
+                head: Some(*head),
+                index: None,
+                worktree: None,
+            });
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files.retain(|f| f.staged() != b' ' || f.unstaged() != b' ');
+
+    let mut untracked = Vec::new();
+    let mut ignore = IgnoreRules::load(repo)?;
+    untracked_files(&index, &mut ignore, repo.work_tree()?, &[], &mut untracked)?;
+
+    Ok(WorktreeStatus {
+        files,
+        conflicts,
+        untracked,
+    })
+}
+
+pub fn status(repo: &Repository, format: StatusFormat) -> anyhow::Result<()> {
+    let (head_ref, head) = repo.refs.resolve("HEAD")?;
+    let branch = (head_ref != "HEAD").then_some(head_ref);
+    let WorktreeStatus {
+        files,
+        conflicts,
+        untracked,
+    } = collect_status(repo, head.as_ref())?;
+
+    let mut stdout = io::stdout().lock();
+    match format {
+        StatusFormat::Short => {
+            for conflict in &conflicts {
+                write!(stdout, "{} ", conflict.code())?;
+                stdout.write_all(&conflict.path)?;
+                writeln!(stdout)?;
+            }
+            for file in &files {
+                stdout.write_all(&[file.staged(), file.unstaged(), b' '])?;
+                stdout.write_all(&file.path)?;
+                writeln!(stdout)?;
+            }
+            for path in &untracked {
+                stdout.write_all(b"?? ")?;
+                stdout.write_all(path)?;
+                writeln!(stdout)?;
+            }
+        }
+        StatusFormat::PorcelainV2 => {
+            let mode = |m: Option<(u32, ShaHash)>| format!("{:06o}", m.map_or(0, |m| m.0));
+            let hash = |m: Option<(u32, ShaHash)>| hex::encode(m.map_or([0; 20], |m| m.1));
+            for file in &files {
+                let code =
+                    [file.staged(), file.unstaged()].map(|c| if c == b' ' { b'.' } else { c });
+                write!(
+                    stdout,
+                    "1 {} N... {} {} {} {} {} ",
+                    str::from_utf8(&code)?,
+                    mode(file.head),
+                    mode(file.index),
+                    mode(file.worktree),
+                    hash(file.head),
+                    hash(file.index),
+                )?;
+                stdout.write_all(&file.path)?;
+                writeln!(stdout)?;
+            }
+            for conflict in &conflicts {
+                let [base, ours, theirs] = conflict.stages;
+                write!(
+                    stdout,
+                    "u {} N... {} {} {} {:06o} {} {} {} ",
+                    conflict.code(),
+                    mode(base),
+                    mode(ours),
+                    mode(theirs),
+                    conflict.worktree_mode,
+                    hash(base),
+                    hash(ours),
+                    hash(theirs),
+                )?;
+                stdout.write_all(&conflict.path)?;
+                writeln!(stdout)?;
+            }
+            for path in &untracked {
+                stdout.write_all(b"? ")?;
+                stdout.write_all(path)?;
+                writeln!(stdout)?;
+            }
+        }
+        StatusFormat::Long => {
+            match (&branch, &head) {
+                (Some(branch), _) => writeln!(
+                    stdout,
+                    "On branch {}",
+                    branch.strip_prefix("refs/heads/").unwrap_or(branch)
+                )?,
+                (None, Some(head)) => {
+                    writeln!(stdout, "HEAD detached at {}", &hex::encode(head)[..7])?
+                }
+                (None, None) => {}
+            }
+            if head.is_none() {
+                writeln!(stdout, "\nNo commits yet\n")?;
+            }
+
+            let describe = |code: u8| match code {
+                b'A' => "new file:   ",
+                b'D' => "deleted:    ",
+                b'T' => "typechange: ",
+                _ => "modified:   ",
+            };
+            let staged = files.iter().filter(|f| f.staged() != b' ').collect_vec();
+            if !staged.is_empty() {
+                writeln!(stdout, "Changes to be committed:")?;
+                for file in staged {
+                    write!(stdout, "\t{}", describe(file.staged()))?;
+                    stdout.write_all(&file.path)?;
+                    writeln!(stdout)?;
+                }
+                writeln!(stdout)?;
+            }
+            if !conflicts.is_empty() {
+                writeln!(stdout, "Unmerged paths:")?;
+                for conflict in &conflicts {
+                    write!(stdout, "\t{}:  ", conflict.code())?;
+                    stdout.write_all(&conflict.path)?;
+                    writeln!(stdout)?;
+                }
+                writeln!(stdout)?;
+            }
+            let unstaged = files.iter().filter(|f| f.unstaged() != b' ').collect_vec();
+            if !unstaged.is_empty() {
+                writeln!(stdout, "Changes not staged for commit:")?;
+                for file in unstaged {
+                    write!(stdout, "\t{}", describe(file.unstaged()))?;
+                    stdout.write_all(&file.path)?;
+                    writeln!(stdout)?;
+                }
+                writeln!(stdout)?;
+            }
+            if !untracked.is_empty() {
+                writeln!(stdout, "Untracked files:")?;
+                for path in &untracked {
+                    stdout.write_all(b"\t")?;
+                    stdout.write_all(path)?;
+                    writeln!(stdout)?;
+                }
+                writeln!(stdout)?;
+            }
+            if files.is_empty() && conflicts.is_empty() && untracked.is_empty() {
+                writeln!(stdout, "nothing to commit, working tree clean")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the tree of a worktree directory, `None` if it has no files that are not ignored.
+/// Nested repositories are recorded as gitlinks to their checked out commit.
+fn write_dir_hash(
+    db: &ObjectDatabase,
+    ignore: &mut IgnoreRules,
+    path: &Path,
+    prefix: &[u8],
+) -> anyhow::Result<Option<ShaHash>> {
+    let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, io::Error>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    let mut items = Vec::new();
+    for entry in entries {
+        let filename = entry.file_name();
+        if filename == ".git" {
+            continue;
+        }
+        let mut name = prefix.to_vec();
+        if !name.is_empty() {
+            name.push(b'/');
+        }
+        name.extend(filename.as_bytes());
+
+        // symlinks are not followed
+        let path = entry.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        if ignore.is_ignored(&name, metadata.is_dir())? {
+            continue;
+        }
+
+        let (mode, hash) = if metadata.is_symlink() {
+            let target = fs::read_link(&path)?;
+            (
+                0o120000,
+                db.write(ObjectKind::Blob, target.as_os_str().as_bytes())?,
+            )
+        } else if metadata.is_file() {
+            (worktree_mode(&metadata), hash_file(Some(db), &path)?)
+        } else if metadata.is_dir() {
+            if let Some(nested) = Repository::open_work_tree(&path)? {
+                let (_, head) = nested.refs.resolve("HEAD")?;
+                let head = head.ok_or(anyhow::anyhow!(
+                    "'{}' does not have a commit checked out",
+                    path.display()
+                ))?;
+                (0o160000, head)
             } else {
-                "100644"
+                // git does not track empty directories
+                match write_dir_hash(db, ignore, &path, &name)? {
+                    Some(hash) => (0o40000, hash),
+                    None => continue,
+                }
             }
-        } else if entry.is_dir() {
-            "40000"
         } else {
-            todo!("unknown entry type")
+            // sockets, fifos and devices cannot be stored
+            continue;
         };
 
-        content.extend(mode.as_bytes());
-        content.extend(b" ");
-        content.extend(filename.as_bytes());
-        content.extend(b"\0");
-        content.extend(&hash);
-        assert_eq!(hash.len(), 20);
+        items.push(TreeItem {
+            mode,
+            name: filename.as_bytes().to_vec(),
+            hash,
+        });
     }
 
-    let mut hasher = Sha1::new();
-    let header = format!("tree {}\0", content.len());
-    hasher.update(&header);
-    hasher.update(&content);
+    if items.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(db.write_tree(&TreeObject { items })?))
+}
 
-    let hash = hex::encode(hasher.finalize()).to_string();
+pub fn write_tree(repo: &Repository) -> anyhow::Result<String> {
+    let mut ignore = IgnoreRules::load(repo)?;
+    let hash = match write_dir_hash(&repo.odb, &mut ignore, repo.work_tree()?, &[])? {
+        Some(hash) => hash,
+        None => repo.odb.write_tree(&TreeObject { items: Vec::new() })?,
+    };
+    Ok(hex::encode(hash))
+}
 
-    let tree_object_path = PathBuf::from(object_path_from_hash(&hash));
-    if let Some(folder) = tree_object_path.parent() {
-        if !folder.exists() {
-            fs::create_dir(folder)?;
+/// Builds a commit message from `-m` paragraphs and `-F` files, stdin is read if neither is given.
+fn read_message(messages: &[String], files: &[PathBuf]) -> anyhow::Result<String> {
+    let mut message = String::new();
+    for paragraph in messages {
+        if !message.is_empty() {
+            message.push('\n');
         }
+        message.push_str(paragraph);
+        message.push('\n');
     }
 
-    let tree_file = BufWriter::new(File::create(&tree_object_path)?);
-    let mut encoder = ZlibEncoder::new(tree_file, Compression::fast());
-    encoder.write_all(header.as_bytes())?;
-    encoder.write_all(&content)?;
-    // TODO: check return values from write
+    let stdin = [PathBuf::from("-")];
+    let files = if messages.is_empty() && files.is_empty() {
+        &stdin[..]
+    } else {
+        files
+    };
+    for file in files {
+        if !message.is_empty() {
+            message.push('\n');
+        }
+        if file.as_os_str() == "-" {
+            io::stdin().read_to_string(&mut message)?;
+        } else {
+            message.push_str(&fs::read_to_string(file).map_err(|err| {
+                anyhow::anyhow!("could not read log file '{}': {err}", file.display())
+            })?);
+        }
+    }
 
-    Ok(hash)
+    Ok(message)
 }
 
-pub fn write_tree() -> anyhow::Result<String> {
-    let cwd = Path::new(".");
-    write_dir_hash(cwd)
+/// Resolves a revision and checks that it names an object of the expected type.
+fn expect_object(repo: &Repository, spec: &str, expected: ObjectKind) -> anyhow::Result<ShaHash> {
+    let hash = revision::resolve(repo, spec)?;
+    match repo.odb.open_stream(&hash) {
+        Ok((kind, _, _)) if kind == expected => Ok(hash),
+        _ => anyhow::bail!("{spec} is not a valid '{expected}' object"),
+    }
 }
 
-pub fn commit_tree(tree: &str, parent: &str, message: &str) -> anyhow::Result<String> {
-    let mut content = String::new();
-    content.push_str(&format!("tree {tree}\n"));
-    content.push_str(&format!("parent {parent}\n"));
+pub fn commit_tree(
+    repo: &Repository,
+    tree: &str,
+    parents: &[String],
+    messages: &[String],
+    files: &[PathBuf],
+) -> anyhow::Result<String> {
+    let tree = expect_object(repo, tree, ObjectKind::Tree)?;
+    let mut parent_hashes = Vec::new();
+    for parent in parents {
+        let hash = expect_object(repo, parent, ObjectKind::Commit)?;
+        if parent_hashes.contains(&hash) {
+            eprintln!("error: duplicate parent {parent} ignored");
+        } else {
+            parent_hashes.push(hash);
+        }
+    }
+    let message = read_message(messages, files)?;
+
+    let author = ident::signature(&repo.config, Role::Author)?;
+    let committer = ident::signature(&repo.config, Role::Committer)?;
+
+    let commit = Object::Commit(CommitObject::new(
+        tree,
+        parent_hashes,
+        author,
+        committer,
+        message,
+    ));
+    let hash = repo.odb.write(commit.kind(), &commit.serialize())?;
+    Ok(hex::encode(hash))
+}
 
-    let now = Local::now();
-    let tz = now.offset().to_string().replace(':', "");
-    let author_line = format!(
-        "Martin Liska <martin.liska@hey.com> {} {tz}",
-        now.timestamp()
-    );
-    content.push_str(&format!("author {}\n", &author_line));
-    content.push_str(&format!("commiter {}\n\n", &author_line));
-    content.push_str(message);
-    content.push('\n');
+/// Writes the trees for index entries below `prefix`, which must be sorted by path.
+fn write_index_tree(
+    db: &ObjectDatabase,
+    entries: &[IndexEntry],
+    prefix: &[u8],
+) -> anyhow::Result<ShaHash> {
+    let mut items = Vec::new();
+    let mut i = 0;
+    while i < entries.len() {
+        let relative = &entries[i].path[prefix.len()..];
+        let (item, mode, hash) = match relative.iter().position(|&c| c == b'/') {
+            Some(slash) => {
+                let dir = &entries[i].path[..prefix.len() + slash + 1];
+                let count = entries[i..]
+                    .iter()
+                    .take_while(|e| e.path.starts_with(dir))
+                    .count();
+                let hash = write_index_tree(db, &entries[i..i + count], dir)?;
+                i += count;
+                (&relative[..slash], 0o40000, hash)
+            }
+            None => {
+                i += 1;
+                (relative, entries[i - 1].mode, entries[i - 1].hash)
+            }
+        };
+        items.push(TreeItem {
+            mode,
+            name: item.to_vec(),
+            hash,
+        });
+    }
 
-    let mut hasher = Sha1::new();
-    let header = format!("commit {}\0", content.len());
-    hasher.update(&header);
-    hasher.update(&content);
+    db.write_tree(&TreeObject { items })
+}
 
-    let hash = hex::encode(hasher.finalize()).to_string();
+/// Cleans up a commit message like `git commit --cleanup=whitespace`: trailing whitespace
+/// and leading and trailing blank lines are removed, consecutive blank lines are collapsed.
+fn cleanup_message(message: &str) -> String {
+    let mut cleaned = String::new();
+    let mut blank = false;
+    for line in message.lines().map(str::trim_end) {
+        if line.is_empty() {
+            blank = !cleaned.is_empty();
+            continue;
+        }
+        if blank {
+            cleaned.push('\n');
+            blank = false;
+        }
+        cleaned.push_str(line);
+        cleaned.push('\n');
+    }
+    cleaned
+}
 
-    let tree_object_path = PathBuf::from(object_path_from_hash(&hash));
-    if let Some(folder) = tree_object_path.parent() {
-        if !folder.exists() {
-            fs::create_dir(folder)?;
+pub fn commit(
+    repo: &Repository,
+    messages: &[String],
+    files: &[PathBuf],
+    amend: bool,
+    allow_empty: bool,
+) -> anyhow::Result<()> {
+    let index = Index::load(&repo.index_file())?;
+    if index.entries.iter().any(|e| e.stage() != 0) {
+        anyhow::bail!("Committing is not possible because you have unmerged files.");
+    }
+    let tree = write_index_tree(&repo.odb, &index.entries, &[])?;
+
+    let (head_ref, head) = repo.refs.resolve("HEAD")?;
+    let head_commit = match &head {
+        Some(head) => match repo.odb.read(head)? {
+            Object::Commit(commit) => Some(commit),
+            _ => anyhow::bail!("HEAD {} is not a commit", hex::encode(head)),
+        },
+        None => None,
+    };
+
+    let (parents, author, previous_message) = if amend {
+        let commit = head_commit.ok_or(anyhow::anyhow!("You have nothing to amend."))?;
+        let message = String::from_utf8_lossy(&commit.message).into_owned();
+        (commit.parents, commit.author, Some(message))
+    } else {
+        if !allow_empty {
+            let unchanged = match &head_commit {
+                Some(commit) => commit.tree == tree,
+                None => index.entries.is_empty(),
+            };
+            if unchanged {
+                anyhow::bail!("nothing to commit");
+            }
+        }
+        let author = ident::signature(&repo.config, Role::Author)?;
+        (head.into_iter().collect(), author, None)
+    };
+
+    let message = match previous_message {
+        Some(message) if messages.is_empty() && files.is_empty() => message,
+        _ if messages.is_empty() && files.is_empty() => {
+            anyhow::bail!("no commit message given, use -m or -F")
         }
+        _ => cleanup_message(&read_message(messages, files)?),
+    };
+    if message.trim().is_empty() {
+        anyhow::bail!("Aborting commit due to empty commit message.");
     }
 
-    let commit_file = BufWriter::new(File::create(&tree_object_path)?);
-    let mut encoder = ZlibEncoder::new(commit_file, Compression::fast());
-    encoder.write_all(header.as_bytes())?;
-    encoder.write_all(content.as_bytes())?;
-    // TODO: check return values from write
+    let root = if parents.is_empty() {
+        " (root-commit)"
+    } else {
+        ""
+    };
+    let subject = message.lines().next().unwrap_or_default().to_owned();
+    let commit = Object::Commit(CommitObject::new(
+        tree,
+        parents,
+        author,
+        ident::signature(&repo.config, Role::Committer)?,
+        message,
+    ));
+    let hash = repo.odb.write(commit.kind(), &commit.serialize())?;
+    // the expected old value guards against concurrent updates of the branch
+    repo.refs
+        .update("HEAD", &hash, Some(head.unwrap_or(NULL_HASH)), true)?;
+
+    let branch = head_ref
+        .strip_prefix("refs/heads/")
+        .unwrap_or("detached HEAD");
+    println!("[{branch}{root} {}] {subject}", &hex::encode(hash)[..7]);
 
-    Ok(hash)
+    Ok(())
 }
 
-struct ObjectSize(usize);
+/// Resolves a value given to `update-ref`, an empty value is the null hash.
+fn parse_ref_value(repo: &Repository, value: &str) -> anyhow::Result<ShaHash> {
+    if value.is_empty() {
+        return Ok(NULL_HASH);
+    }
+    revision::resolve(repo, value).map_err(|_| anyhow::anyhow!("{value}: not a valid SHA1"))
+}
 
-impl ObjectSize {
-    fn try_parse(reader: &mut dyn Read) -> anyhow::Result<ObjectSize> {
-        let mut size = 0usize;
-        let mut bitcount = 0usize;
+pub fn update_ref(
+    repo: &Repository,
+    name: &str,
+    new_value: Option<&str>,
+    old_value: Option<&str>,
+    delete: bool,
+    no_deref: bool,
+) -> anyhow::Result<()> {
+    let old_value = old_value
+        .map(|value| parse_ref_value(repo, value))
+        .transpose()?;
+    if delete {
+        return repo.refs.delete(name, old_value, !no_deref);
+    }
 
-        loop {
-            let mut v = [0u8; 1];
-            reader.read_exact(&mut v)?;
-            let tmp = (v[0] & 0b0111_1111) as usize;
-            size |= tmp << bitcount;
-            bitcount += 7;
+    let new_value = new_value.ok_or(anyhow::anyhow!("missing new value for {name}"))?;
+    let new_value = parse_ref_value(repo, new_value)?;
+    if new_value == NULL_HASH {
+        return repo.refs.delete(name, old_value, !no_deref);
+    }
+    if !repo.odb.contains(&new_value) {
+        anyhow::bail!(
+            "trying to write ref '{name}' with nonexistent object {}",
+            hex::encode(new_value)
+        );
+    }
+    repo.refs.update(name, &new_value, old_value, !no_deref)
+}
 
-            if v[0] >> 7 == 0 {
-                break;
-            }
+pub fn symbolic_ref(
+    repo: &Repository,
+    name: &str,
+    target: Option<&str>,
+    delete: bool,
+    quiet: bool,
+    short: bool,
+) -> anyhow::Result<()> {
+    if delete {
+        if name == "HEAD" {
+            anyhow::bail!("deleting '{name}' is not allowed");
         }
+        let Some(RefValue::Symbolic(_)) = repo.refs.read(name)? else {
+            anyhow::bail!("Cannot delete {name}, not a symbolic ref");
+        };
+        return repo.refs.delete(name, None, false);
+    }
 
-        Ok(ObjectSize(size))
+    if let Some(target) = target {
+        if name == "HEAD" && !target.starts_with("refs/") {
+            anyhow::bail!("Refusing to point HEAD outside of refs/");
+        }
+        return repo.refs.set_symbolic(name, target);
     }
+
+    match repo.refs.read(name)? {
+        Some(RefValue::Symbolic(target)) if short => println!("{}", shorten_ref(&target)),
+        Some(RefValue::Symbolic(target)) => println!("{target}"),
+        // -q only silences the error for refs that are not symbolic
+        _ if quiet => {}
+        _ => anyhow::bail!("ref {name} is not a symbolic ref"),
+    }
+    Ok(())
 }
 
-struct ObjectSizeType {
-    size: ObjectSize,
-    object_type: u8,
+/// Strips the `refs/heads/`, `refs/tags/` or `refs/remotes/` prefix of a ref name.
+fn shorten_ref(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
 }
 
-impl ObjectSizeType {
-    fn try_parse(reader: &mut dyn Read) -> anyhow::Result<ObjectSizeType> {
-        let mut size = ObjectSize::try_parse(reader)?;
-        let object_type = ((size.0 >> 4) & 0b111) as u8;
+pub struct ShowRefOptions {
+    pub head: bool,
+    pub heads: bool,
+    pub tags: bool,
+    pub dereference: bool,
+    /// Print only the (abbreviated) object names.
+    pub hash: Option<usize>,
+    pub verify: bool,
+    pub quiet: bool,
+}
 
-        // we need to preserve lowest 4 bits before we remove bits 5,6 and 7 by shifting
-        let lower = size.0 & 0b1111;
-        size.0 >>= 7;
-        size.0 <<= 4;
-        size.0 += lower;
+pub fn show_ref(
+    repo: &Repository,
+    patterns: &[String],
+    options: &ShowRefOptions,
+) -> anyhow::Result<bool> {
+    let mut refs = Vec::new();
+    if options.verify {
+        for pattern in patterns {
+            let hash = match pattern.as_str() {
+                "HEAD" => repo.refs.resolve(pattern)?.1,
+                _ if pattern.starts_with("refs/") => repo.refs.resolve(pattern)?.1,
+                _ => None,
+            };
+            let Some(hash) = hash else {
+                if !options.quiet {
+                    eprintln!("fatal: '{pattern}' - not a valid ref");
+                }
+                return Ok(false);
+            };
+            refs.push((pattern.clone(), hash));
+        }
+    } else {
+        if options.head {
+            if let (_, Some(hash)) = repo.refs.resolve("HEAD")? {
+                refs.push(("HEAD".to_owned(), hash));
+            }
+        }
+        let filtered = options.heads || options.tags;
+        for (name, hash) in repo.refs.list()? {
+            let selected = !filtered
+                || (options.heads && name.starts_with("refs/heads/"))
+                || (options.tags && name.starts_with("refs/tags/"));
+            let matches = patterns.is_empty()
+                || patterns
+                    .iter()
+                    .any(|p| name == *p || name.ends_with(&format!("/{p}")));
+            if selected && matches {
+                refs.push((name, hash));
+            }
+        }
+    }
 
-        Ok(ObjectSizeType { size, object_type })
+    if options.quiet {
+        return Ok(!refs.is_empty());
     }
-}
 
-#[derive(Debug)]
-enum CopyCommand {
-    FromReference { offset: usize, size: usize },
-    Direct { data: Vec<u8> },
-}
+    let format_hash = |hash: &ShaHash| {
+        let hex = hex::encode(hash);
+        match options.hash {
+            Some(len) => hex[..len.clamp(4, hex.len())].to_owned(),
+            None => hex,
+        }
+    };
+    let mut stdout = io::stdout().lock();
+    for (name, hash) in &refs {
+        match options.hash {
+            Some(_) => writeln!(stdout, "{}", format_hash(hash))?,
+            None => writeln!(stdout, "{} {name}", format_hash(hash))?,
+        }
+        if options.dereference {
+            let peeled = match repo.refs.packed_peeled(name)? {
+                Some(peeled) => peeled,
+                None => revision::peel(&repo.odb, hash, None)?,
+            };
+            if peeled != *hash {
+                writeln!(stdout, "{} {name}^{{}}", format_hash(&peeled))?;
+            }
+        }
+    }
 
-impl CopyCommand {
-    fn try_parse(reader: &mut dyn Read) -> anyhow::Result<CopyCommand> {
-        let mut header = [0u8; 1];
-        reader.read_exact(&mut header)?;
-        let header = header[0];
+    Ok(!refs.is_empty())
+}
 
-        match header >> 7 {
-            0 => {
-                let size = header & 0b0111_1111;
-                let mut data = vec![0u8; size as usize];
-                reader.read_exact(&mut data)?;
-                Ok(CopyCommand::Direct { data })
+pub fn log(
+    repo: &Repository,
+    revisions: &[String],
+    options: WalkOptions,
+    format: &Format,
+) -> anyhow::Result<()> {
+    let mut tips = Vec::new();
+    if revisions.is_empty() {
+        let (head_ref, head) = repo.refs.resolve("HEAD")?;
+        let head = head.ok_or(anyhow::anyhow!(
+            "your current branch '{}' does not have any commits yet",
+            head_ref.strip_prefix("refs/heads/").unwrap_or(&head_ref)
+        ))?;
+        tips.push(head);
+    }
+    let mut excluded = Vec::new();
+    for arg in revisions {
+        for revision in revision::parse_arg(repo, arg)? {
+            match revision {
+                RevisionArg::Include(hash) => tips.push(hash),
+                RevisionArg::Exclude(hash) => excluded.push(hash),
             }
-            1 => {
-                let mut buffer = [0u8; 1];
-                let mut offset = 0;
-                let mut size = 0;
+        }
+    }
+    let peel_commits = |hashes: Vec<ShaHash>| {
+        hashes
+            .iter()
+            .map(|hash| revision::peel(&repo.odb, hash, Some(ObjectKind::Commit)))
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    let (tips, excluded) = (peel_commits(tips)?, peel_commits(excluded)?);
+
+    let mut walk = RevWalk::new(&repo.odb, &tips, &excluded, options)?;
+    let mut stdout = io::stdout().lock();
+    let mut first = true;
+    while let Some((hash, commit)) = walk.next_commit()? {
+        if !first {
+            stdout.write_all(format.separator().as_bytes())?;
+        }
+        first = false;
+        stdout.write_all(format.format(&hash, commit).as_bytes())?;
+        stdout.write_all(format.terminator().as_bytes())?;
+    }
 
-                for i in 0..4 {
-                    if header & (1u8 << i) != 0 {
-                        reader.read_exact(&mut buffer)?;
-                        offset += (buffer[0] as usize) << (8 * i);
-                    }
-                }
+    Ok(())
+}
 
-                for i in 0..3 {
-                    if header & (1u8 << (i + 4)) != 0 {
-                        reader.read_exact(&mut buffer)?;
-                        size += (buffer[0] as usize) << (8 * i);
-                    }
-                }
+pub struct RevParseOptions {
+    pub verify: bool,
+    pub quiet: bool,
+    /// Abbreviate object names to at least this many digits.
+    pub short: Option<usize>,
+    pub abbrev_ref: bool,
+    pub symbolic_full_name: bool,
+    pub git_dir: bool,
+    pub show_toplevel: bool,
+}
+
+/// Prints the object names of revisions, returns false if `--verify --quiet` failed.
+pub fn rev_parse(
+    repo: &Repository,
+    args: &[String],
+    options: &RevParseOptions,
+) -> anyhow::Result<bool> {
+    let mut stdout = io::stdout().lock();
+    if options.git_dir {
+        writeln!(stdout, "{}", repo.git_dir.display())?;
+    }
+    if options.show_toplevel {
+        writeln!(stdout, "{}", repo.work_tree()?.display())?;
+    }
+    let format_hash = |hash: &ShaHash| match options.short {
+        Some(len) => repo.odb.abbreviate(hash, len),
+        None => Ok(hex::encode(hash)),
+    };
+
+    if options.verify {
+        let verified = match args {
+            [arg] => revision::resolve(repo, arg)
+                .ok()
+                .filter(|hash| repo.odb.contains(hash)),
+            _ => None,
+        };
+        return match verified {
+            Some(hash) => {
+                writeln!(stdout, "{}", format_hash(&hash)?)?;
+                Ok(true)
+            }
+            None if options.quiet => Ok(false),
+            None => anyhow::bail!("Needed a single revision"),
+        };
+    }
 
-                if size == 0 {
-                    size = 0x10000;
+    for arg in args {
+        if options.symbolic_full_name || options.abbrev_ref {
+            if let Some(name) = revision::full_ref_name(repo, arg)? {
+                match options.abbrev_ref {
+                    true => writeln!(stdout, "{}", shorten_ref(&name))?,
+                    false => writeln!(stdout, "{name}")?,
                 }
+            }
+            continue;
+        }
 
-                Ok(CopyCommand::FromReference { offset, size })
+        for revision in revision::parse_arg(repo, arg)? {
+            match revision {
+                RevisionArg::Include(hash) => writeln!(stdout, "{}", format_hash(&hash)?)?,
+                RevisionArg::Exclude(hash) => writeln!(stdout, "^{}", format_hash(&hash)?)?,
             }
-            _ => unreachable!(),
         }
     }
 
-    fn size(&self) -> usize {
-        match self {
-            CopyCommand::Direct { data } => data.len(),
-            CopyCommand::FromReference { offset: _, size } => *size,
+    Ok(true)
+}
+
+pub enum ConfigAction {
+    Get,
+    GetAll,
+    List { show_origin: bool, show_scope: bool },
+    Edit(Edit),
+}
+
+/// Config files a `config` command reads and writes: the explicitly selected one,
+/// or all the scopes for reading and the repository config for writing.
+fn config_files(
+    repo: Option<&Repository>,
+    scope: Option<Scope>,
+    file: Option<&Path>,
+) -> anyhow::Result<(Vec<PathBuf>, PathBuf)> {
+    let local = || {
+        repo.map(|r| r.git_dir.join("config"))
+            .ok_or(anyhow::anyhow!("not in a git directory"))
+    };
+    match (scope, file) {
+        (_, Some(file)) => Ok((vec![file.to_owned()], file.to_owned())),
+        (Some(Scope::System), _) => {
+            let path = config::system_config_path().unwrap_or(PathBuf::from("/etc/gitconfig"));
+            Ok((vec![path.clone()], path))
+        }
+        (Some(Scope::Global), _) => {
+            let paths = config::global_config_paths();
+            // the XDG file is written to only if it exists and ~/.gitconfig does not
+            let target = paths
+                .iter()
+                .rev()
+                .find(|p| p.exists())
+                .or(paths.last())
+                .cloned()
+                .ok_or(anyhow::anyhow!("$HOME not set"))?;
+            Ok((paths, target))
+        }
+        _ => {
+            let path = local()?;
+            Ok((vec![path.clone()], path))
         }
     }
 }
 
-fn move_based_on_hash() -> anyhow::Result<String> {
-    let mut hasher = Sha1::new();
-    let mut buffer = [0u8; 1024];
-    let mut f = File::open(TEMPORARY)?;
-    loop {
-        let n = f.read(&mut buffer)?;
-        if n == 0 {
-            break;
+pub fn config(
+    repo: Option<&Repository>,
+    scope: Option<Scope>,
+    file: Option<&Path>,
+    action: ConfigAction,
+    name: Option<&str>,
+    value: Option<&str>,
+) -> anyhow::Result<bool> {
+    let git_dir = repo.map(|r| r.git_dir.as_path());
+    let config = match (scope, file) {
+        (None, None) => Config::load(git_dir)?,
+        _ => {
+            let (paths, _) = config_files(repo, scope, file)?;
+            Config::load_files(&paths, scope.unwrap_or(Scope::File), git_dir)?
+        }
+    };
+
+    match action {
+        ConfigAction::Get | ConfigAction::GetAll => {
+            let name = name.ok_or(anyhow::anyhow!("missing key"))?;
+            let mut entries = config.get_all(name);
+            if let ConfigAction::Get = action {
+                entries = entries.split_off(entries.len().saturating_sub(1));
+            }
+            if entries.is_empty() {
+                return Ok(false);
+            }
+            for entry in entries {
+                println!("{}", entry.value.as_deref().unwrap_or_default());
+            }
+        }
+        ConfigAction::List {
+            show_origin,
+            show_scope,
+        } => {
+            for entry in config.entries() {
+                if show_scope {
+                    print!("{}\t", entry.scope.as_str());
+                }
+                if show_origin {
+                    print!("file:{}\t", entry.file.display());
+                }
+                match &entry.value {
+                    Some(value) => println!("{}={value}", entry.key()),
+                    None => println!("{}", entry.key()),
+                }
+            }
+        }
+        ConfigAction::Edit(edit) => {
+            let name = name.ok_or(anyhow::anyhow!("missing key"))?;
+            let (_, path) = config_files(repo, scope, file)?;
+            config::edit_file(&path, name, value, edit)?;
         }
-        hasher.update(&buffer[..n]);
     }
 
-    let hash: String = hex::encode(hasher.finalize()).to_string();
-    let tree_object_path: PathBuf = object_path_from_hash(&hash).into();
-    if let Some(folder) = tree_object_path.parent() {
-        if !folder.exists() {
-            fs::create_dir(folder)?;
-        }
+    Ok(true)
+}
+
+/// Capabilities requested from the server when it advertises them.
+const CLIENT_CAPABILITIES: [&str; 1] = ["ofs-delta"];
+const REMOTE: &str = "origin";
+
+/// The refs and capabilities a server advertises before a fetch.
+struct RefAdvertisement {
+    refs: Vec<(String, ShaHash)>,
+    capabilities: Vec<String>,
+}
+
+impl RefAdvertisement {
+    /// The value of a `name=value` capability, empty for capabilities without a value.
+    fn capability(&self, name: &str) -> Option<&str> {
+        self.capabilities
+            .iter()
+            .find_map(|capability| match capability.split_once('=') {
+                Some((key, value)) if key == name => Some(value),
+                None if capability == name => Some(""),
+                _ => None,
+            })
     }
 
-    let mut f = File::open(TEMPORARY)?;
-    let mut encoder = ZlibEncoder::new(File::create(tree_object_path)?, Compression::fast());
-    io::copy(&mut f, &mut encoder)?;
+    /// The target of a symbolic ref from its `symref=<name>:<target>` capability.
+    fn symref(&self, name: &str) -> Option<&str> {
+        self.capabilities.iter().find_map(|capability| {
+            let (symref, target) = capability.strip_prefix("symref=")?.split_once(':')?;
+            (symref == name).then_some(target)
+        })
+    }
+
+    fn find(&self, name: &str) -> Option<ShaHash> {
+        self.refs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, hash)| *hash)
+    }
+}
+
+/// Reads the ref advertisement of the smart HTTP protocol.
+fn read_advertisement(url: &str) -> anyhow::Result<RefAdvertisement> {
+    let res = reqwest::blocking::get(format!("{url}/info/refs?service=git-upload-pack"))?
+        .error_for_status()?;
+    parse_advertisement(url, res)
+}
+
+fn parse_advertisement(url: &str, input: impl Read) -> anyhow::Result<RefAdvertisement> {
+    let mut advertisement = PktReader::new(input);
+    let service = advertisement.read_packet()?;
+    if service.as_ref().and_then(Packet::as_text) != Some(b"# service=git-upload-pack") {
+        anyhow::bail!("{url} is not a git repository served with the smart HTTP protocol");
+    }
+    advertisement.read_until_flush()?;
+
+    let mut refs = Vec::new();
+    let mut capabilities = Vec::new();
+    for (i, line) in advertisement.read_until_flush()?.iter().enumerate() {
+        let line = str::from_utf8(line.strip_suffix(b"\n").unwrap_or(line))?;
+        // the capabilities follow the first ref after a NUL
+        let line = match line.split_once('\0') {
+            Some((line, list)) if i == 0 => {
+                capabilities = list.split(' ').map(str::to_owned).collect();
+                line
+            }
+            _ => line,
+        };
+        let (hash, name) = line
+            .split_once(' ')
+            .ok_or(anyhow::anyhow!("invalid ref advertisement: {line}"))?;
+        // peeled tags are fetched with the tags, an empty repository only sends capabilities
+        if name.ends_with("^{}") {
+            continue;
+        }
+        refs.push((name.to_owned(), parse_hash(hash)?));
+    }
 
-    fs::remove_file(TEMPORARY)?;
-    Ok(hash)
+    let advertisement = RefAdvertisement { refs, capabilities };
+    if let Some(format) = advertisement.capability("object-format") {
+        if format != "sha1" {
+            anyhow::bail!("unsupported object format: {format}");
+        }
+    }
+    Ok(advertisement)
 }
 
 pub fn clone(url: &str, path: &Path, verbose: bool) -> anyhow::Result<()> {
-    let body =
-        reqwest::blocking::get(format!("{url}/info/refs?service=git-upload-pack"))?.text()?;
-    let mut lines = body.lines();
-    assert!(lines.next().is_some_and(|l| l.starts_with("001e")));
-    let head: String = lines
-        .next()
-        .unwrap()
-        .strip_prefix("0000")
-        .unwrap()
-        .chars()
-        .skip(4)
-        .take(40)
-        .collect();
-    println!("{head}");
+    let advertisement = read_advertisement(url)?;
+    let head = advertisement.find("HEAD");
+    // without a symref capability, HEAD is guessed from the branches pointing to it
+    let head_branch = match advertisement.symref("HEAD") {
+        Some(target) => Some(target.to_owned()),
+        None => head.and_then(|head| {
+            let branches = advertisement
+                .refs
+                .iter()
+                .filter(|(name, hash)| name.starts_with("refs/heads/") && *hash == head)
+                .map(|(name, _)| name.clone())
+                .collect_vec();
+            branches
+                .iter()
+                .find(|name| *name == "refs/heads/master")
+                .or(branches.first())
+                .cloned()
+        }),
+    };
+
+    let mut seen = HashSet::new();
+    let wants = head
+        .into_iter()
+        .chain(
+            advertisement
+                .refs
+                .iter()
+                .filter(|(name, _)| {
+                    name.starts_with("refs/heads/") || name.starts_with("refs/tags/")
+                })
+                .map(|(_, hash)| *hash),
+        )
+        .filter(|hash| seen.insert(*hash))
+        .collect_vec();
+    if wants.is_empty() {
+        fs::create_dir(path)?;
+        let repo = Repository::init(path)?;
+        configure_remote(&repo, url, head_branch.as_deref())?;
+        if let Some(branch) = &head_branch {
+            repo.refs.set_symbolic("HEAD", branch)?;
+        }
+        eprintln!("warning: You appear to have cloned an empty repository.");
+        return Ok(());
+    }
+
+    let mut capabilities = CLIENT_CAPABILITIES
+        .into_iter()
+        .filter(|capability| advertisement.capability(capability).is_some())
+        .map(str::to_owned)
+        .collect_vec();
+    // pack data is multiplexed with progress messages if the server supports it
+    if advertisement.capability("side-band-64k").is_some() {
+        capabilities.push("side-band-64k".to_owned());
+    } else if advertisement.capability("side-band").is_some() {
+        capabilities.push("side-band".to_owned());
+    }
+    if advertisement.capability("agent").is_some() {
+        capabilities.push(format!(
+            "agent=git-starter-rust/{}",
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+
+    let mut request = PktWriter::new(Vec::new());
+    for (i, want) in wants.iter().enumerate() {
+        let mut line = format!("want {}", hex::encode(want));
+        // capabilities are sent with the first want
+        if i == 0 {
+            line = [line, capabilities.join(" ")]
+                .join(" ")
+                .trim_end()
+                .to_owned();
+        }
+        request.write_line(&line)?;
+    }
+    request.write_packet(&Packet::Flush)?;
+    request.write_line("done")?;
 
     let client = reqwest::blocking::Client::new();
-    let mut res = client
+    let res = client
         .post(format!("{url}/git-upload-pack"))
-        .body(format!("0032want {head}\n00000009done\n"))
-        .send()?;
-
-    println!("{}", res.status());
-    const EXPECTED_PREFIX: &str = "0008NAK\nPACK";
-    let mut prefix = [0u8; EXPECTED_PREFIX.len()];
-    res.read_exact(&mut prefix)?;
-    assert_eq!(&prefix, EXPECTED_PREFIX.as_bytes());
+        .body(request.into_inner())
+        .send()?
+        .error_for_status()?;
+
+    let mut response = PktReader::new(res);
+    match response.read_packet()? {
+        Some(packet) if packet.as_text() == Some(b"NAK") => {}
+        Some(Packet::Data(data)) if data.starts_with(b"ERR ") => anyhow::bail!(
+            "remote error: {}",
+            String::from_utf8_lossy(&data[4..]).trim_end()
+        ),
+        packet => anyhow::bail!("expected NAK from the server, got {packet:?}"),
+    }
+    let side_band = capabilities.iter().any(|c| c.starts_with("side-band"));
+    let mut res: Box<dyn Read> = match side_band {
+        true => Box::new(SideBandReader::new(response)),
+        false => Box::new(response.into_inner()),
+    };
+
+    let mut signature = [0u8; 4];
+    res.read_exact(&mut signature)?;
+    if &signature != b"PACK" {
+        anyhow::bail!("invalid pack signature: {signature:?}");
+    }
     let mut buffer = [0u8; 4];
     res.read_exact(&mut buffer)?;
     let version = u32::from_be_bytes(buffer);
     res.read_exact(&mut buffer)?;
     let objects = u32::from_be_bytes(buffer);
-    println!("version: {version} objects:{objects}");
+    if version != 2 && version != 3 {
+        anyhow::bail!("unsupported pack version: {version}");
+    }
 
     fs::create_dir(path)?;
-    env::set_current_dir(path)?;
-    init()?;
+    let repo = Repository::init(path)?;
 
-    let mut reader = BufReader::new(res);
+    let db = &repo.odb;
+    // pack offsets are relative to the "PACK" signature, the header has been read already
+    let mut reader = CountingReader::new(BufReader::new(res), 12);
+    let mut offsets = HashMap::new();
     for _ in 0..objects {
+        let entry_offset = reader.offset();
         let ObjectSizeType { size, object_type } = ObjectSizeType::try_parse(&mut reader)?;
         let size = size.0;
 
-        match object_type {
+        let (object_type, content) = match object_type {
             1..=4 => {
+                let object_type = object_kind_from_pack_type(object_type)?;
                 let mut zlib_reader = ZlibDecoder::new(reader);
                 let mut content = Vec::new();
                 zlib_reader.read_to_end(&mut content)?;
-                let object_type = match object_type {
-                    1 => "commit",
-                    2 => "tree",
-                    3 => "blob",
-                    _ => todo!(),
-                };
+                if content.len() != size {
+                    anyhow::bail!(
+                        "Pack entry size {size}: does not match the actual content: {}",
+                        content.len()
+                    );
+                }
+                reader = zlib_reader.into_inner();
 
-                let mut f = File::create(TEMPORARY)?;
-                f.write_all(format!("{object_type} {size}\0").as_bytes())?;
-                f.write_all(&content)?;
-                drop(f);
-                assert_eq!(size, content.len());
+                (object_type, content)
+            }
+            6 | 7 => {
+                let base_hash = if object_type == 6 {
+                    let base_offset = parse_base_offset(&mut reader, entry_offset)?;
+                    *offsets.get(&base_offset).ok_or(anyhow::anyhow!(
+                        "OFS_DELTA base at {base_offset} is not a known pack entry"
+                    ))?
+                } else {
+                    let mut base_hash = ShaHash::default();
+                    reader.read_exact(&mut base_hash)?;
+                    base_hash
+                };
 
-                let hash = move_based_on_hash()?;
-                if verbose {
-                    println!("{hash} {object_type} {size}");
+                let mut zlib_reader = ZlibDecoder::new(reader);
+                let mut delta = Vec::new();
+                zlib_reader.read_to_end(&mut delta)?;
+                if delta.len() != size {
+                    anyhow::bail!(
+                        "Pack entry size {size}: does not match the actual delta: {}",
+                        delta.len()
+                    );
                 }
                 reader = zlib_reader.into_inner();
+
+                let (object_type, base) = db.read_raw(&base_hash)?;
+                (object_type, apply_delta(&base, &delta)?)
             }
-            7 => {
-                let mut base_hash = [0u8; 20];
-                reader.read_exact(&mut base_hash)?;
-                let base_hash = hex::encode(base_hash).to_string();
+            _ => anyhow::bail!("invalid pack object type: {object_type}"),
+        };
 
-                let mut f = File::create(TEMPORARY)?;
+        if object_type == ObjectKind::Tree {
+            TreeObject::parse(&content)?.verify_entries()?;
+        }
+        let hash = db.write(object_type, &content)?;
+        offsets.insert(entry_offset, hash);
+        if verbose {
+            println!("{} {object_type} {}", hex::encode(hash), content.len());
+        }
+    }
 
-                // TODO: read sizes
-                let mut zlib_reader = ZlibDecoder::new(reader);
-                let _ = ObjectSize::try_parse(&mut zlib_reader)?.0;
-                let final_size = ObjectSize::try_parse(&mut zlib_reader)?.0;
-                // println!("base_hash: {}, final_size: {final_size}", object_path_from_hash(&base_hash));
-
-                let mut current_size = 0;
-                while current_size != final_size {
-                    let copy_command = CopyCommand::try_parse(&mut zlib_reader)?;
-                    match copy_command {
-                        CopyCommand::FromReference { offset, size } => {
-                            let mut decoder = BufReader::new(ZlibDecoder::new(BufReader::new(
-                                File::open(object_path_from_hash(&base_hash))?,
-                            )));
-                            let mut content = Vec::new();
-                            decoder.read_until(b'\0', &mut content)?;
-                            let object_type =
-                                &content[0..content.iter().position(|&x| x == b' ').unwrap()];
-                            if current_size == 0 {
-                                // write header
-                                f.write_all(object_type)?;
-                                f.write_all(b" ")?;
-                                f.write_all(final_size.to_string().as_bytes())?;
-                                f.write_all(b"\0")?;
-                            }
-                            // TODO
-                            let mut content = Vec::new();
-                            decoder.read_to_end(&mut content)?;
-                            f.write_all(&content[offset..offset + size])?;
-                        }
-                        CopyCommand::Direct { ref data } => {
-                            f.write_all(data)?;
-                        }
-                    }
-                    // println!("  {copy_command:?}");
-                    current_size += copy_command.size();
-                }
+    for (name, hash) in &advertisement.refs {
+        if let Some(branch) = name.strip_prefix("refs/heads/") {
+            let tracking = format!("refs/remotes/{REMOTE}/{branch}");
+            repo.refs.update(&tracking, hash, Some(NULL_HASH), false)?;
+        } else if name.starts_with("refs/tags/") {
+            repo.refs.update(name, hash, Some(NULL_HASH), false)?;
+        }
+    }
+    configure_remote(&repo, url, head_branch.as_deref())?;
+
+    let Some(head) = head else {
+        if let Some(branch) = &head_branch {
+            repo.refs.set_symbolic("HEAD", branch)?;
+        }
+        eprintln!("warning: remote HEAD refers to nonexistent ref, unable to checkout");
+        return Ok(());
+    };
+    checkout(&repo, &head)?;
+    match &head_branch {
+        Some(branch) => {
+            let short = branch.strip_prefix("refs/heads/").unwrap_or(branch);
+            repo.refs.set_symbolic(
+                &format!("refs/remotes/{REMOTE}/HEAD"),
+                &format!("refs/remotes/{REMOTE}/{short}"),
+            )?;
+            repo.refs.set_symbolic("HEAD", branch)?;
+            repo.refs.update(branch, &head, Some(NULL_HASH), false)?;
+        }
+        None => repo.refs.update("HEAD", &head, None, false)?,
+    }
 
-                drop(f);
-                let hash = move_based_on_hash()?;
-                if verbose {
-                    println!("{hash} {object_type} {size}");
+    Ok(())
+}
+
+/// Records the remote a repository was cloned from and the upstream of its branch.
+fn configure_remote(repo: &Repository, url: &str, branch: Option<&str>) -> anyhow::Result<()> {
+    let config_file = repo.git_dir.join("config");
+    let set = |key: &str, value: &str| config::edit_file(&config_file, key, Some(value), Edit::Set);
+    set(&format!("remote.{REMOTE}.url"), url)?;
+    set(
+        &format!("remote.{REMOTE}.fetch"),
+        &format!("+refs/heads/*:refs/remotes/{REMOTE}/*"),
+    )?;
+    if let Some(branch) = branch {
+        let short = branch.strip_prefix("refs/heads/").unwrap_or(branch);
+        set(&format!("branch.{short}.remote"), REMOTE)?;
+        set(&format!("branch.{short}.merge"), branch)?;
+    }
+    Ok(())
+}
+
+/// Materializes the tree of a commit in the worktree and writes the index for it.
+fn checkout(repo: &Repository, commit: &ShaHash) -> anyhow::Result<()> {
+    let Object::Commit(commit) = repo.odb.read(commit)? else {
+        anyhow::bail!("object {} is not a commit", hex::encode(commit));
+    };
+
+    let mut entries = Vec::new();
+    checkout_tree(
+        &repo.odb,
+        &commit.tree,
+        repo.work_tree()?,
+        &[],
+        &mut entries,
+    )?;
+    Index::new(entries).write(&repo.index_file())
+}
+
+fn checkout_tree(
+    db: &ObjectDatabase,
+    tree: &ShaHash,
+    dir: &Path,
+    prefix: &[u8],
+    entries: &mut Vec<IndexEntry>,
+) -> anyhow::Result<()> {
+    let Object::Tree(tree) = db.read(tree)? else {
+        anyhow::bail!("object {} is not a tree", hex::encode(tree));
+    };
+    // entry names become paths, trees from elsewhere must not escape the worktree
+    tree.verify_entries()?;
+
+    for item in tree.items {
+        let path = dir.join(OsStr::from_bytes(&item.name));
+        let mut name = prefix.to_vec();
+        name.extend(&item.name);
+
+        let mut mode = item.mode;
+        match mode & 0o170000 {
+            0o040000 => {
+                fs::create_dir_all(&path)?;
+                name.push(b'/');
+                checkout_tree(db, &item.hash, &path, &name, entries)?;
+                continue;
+            }
+            0o160000 => {
+                // submodule contents are not fetched, leave an empty directory behind
+                fs::create_dir_all(&path)?;
+                entries.push(IndexEntry {
+                    mode,
+                    hash: item.hash,
+                    flags: name.len().min(0xfff) as u16,
+                    path: name,
+                    ..Default::default()
+                });
+                continue;
+            }
+            0o120000 => {
+                let Object::Blob(blob) = db.read(&item.hash)? else {
+                    anyhow::bail!("symlink {} is not a blob", path.display());
+                };
+                symlink(OsStr::from_bytes(&blob.content), &path)?;
+            }
+            // legacy modes like 100664 are regular files too, only the executable bit counts
+            0o100000 => {
+                let (_, _, mut reader) = db.open_stream(&item.hash)?;
+                let mut file = File::create(&path)?;
+                io::copy(&mut reader, &mut file)?;
+                if mode & 0o100 != 0 {
+                    file.set_permissions(Permissions::from_mode(0o755))?;
+                    mode = 0o100755;
+                } else {
+                    mode = 0o100644;
                 }
-                reader = zlib_reader.into_inner();
             }
-            _ => unimplemented!(),
+            _ => anyhow::bail!("unsupported mode {mode:o} of {}", path.display()),
         }
-    }
 
-    // TODO: implement git checkout (extract-tree)
-    Command::new("git").arg("checkout").arg(head).output()?;
+        let metadata = fs::symlink_metadata(&path)?;
+        entries.push(IndexEntry::from_metadata(name, mode, item.hash, &metadata));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Signature;
+    use crate::testutil::TempDir;
+
+    fn raw_tree(entries: &[(&str, &str, ShaHash)]) -> Vec<u8> {
+        let mut content = Vec::new();
+        for (mode, name, hash) in entries {
+            content.extend(format!("{mode} {name}\0").as_bytes());
+            content.extend(hash);
+        }
+        content
+    }
+
+    #[test]
+    fn checkout_regular_file_modes() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.path().join("objects")).unwrap();
+        let db = ObjectDatabase::open(&dir.path().join("objects")).unwrap();
+        let blob = db.write(ObjectKind::Blob, b"content\n").unwrap();
+        let tree = raw_tree(&[
+            ("100600", "private", blob),
+            ("100644", "plain", blob),
+            ("100664", "shared", blob),
+            ("100775", "tool", blob),
+        ]);
+        let tree = db.write(ObjectKind::Tree, &tree).unwrap();
+
+        let work_tree = dir.path().join("work");
+        fs::create_dir(&work_tree).unwrap();
+        let mut entries = Vec::new();
+        checkout_tree(&db, &tree, &work_tree, &[], &mut entries).unwrap();
+
+        let modes = entries.iter().map(|e| e.mode).collect_vec();
+        assert_eq!(modes, [0o100644, 0o100644, 0o100644, 0o100755]);
+        let executable = |name: &str| {
+            let metadata = fs::metadata(work_tree.join(name)).unwrap();
+            metadata.permissions().mode() & 0o100 != 0
+        };
+        assert!(!executable("shared"));
+        assert!(executable("tool"));
+        assert_eq!(fs::read(work_tree.join("private")).unwrap(), b"content\n");
+    }
+
+    #[test]
+    fn checkout_rejects_unsafe_names() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.path().join("objects")).unwrap();
+        let db = ObjectDatabase::open(&dir.path().join("objects")).unwrap();
+        let blob = db.write(ObjectKind::Blob, b"x").unwrap();
+        let work_tree = dir.path().join("work");
+        fs::create_dir(&work_tree).unwrap();
+
+        for name in [".git", ".GIT", ".."] {
+            let tree = raw_tree(&[("100644", name, blob)]);
+            let tree = db.write(ObjectKind::Tree, &tree).unwrap();
+            let result = checkout_tree(&db, &tree, &work_tree, &[], &mut Vec::new());
+            assert!(result.is_err(), "{name}");
+        }
+        let tree = raw_tree(&[("120000", "link", blob), ("20000", "odd", blob)]);
+        let tree = db.write(ObjectKind::Tree, &tree).unwrap();
+        assert!(checkout_tree(&db, &tree, &work_tree, &[], &mut Vec::new()).is_err());
+    }
+
+    fn index_paths(repo: &Repository) -> Vec<String> {
+        let index = Index::load(&repo.index_file()).unwrap();
+        index
+            .entries
+            .iter()
+            .map(|e| String::from_utf8_lossy(&e.path).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn add_all_and_ignore_removal() {
+        let dir = TempDir::new();
+        let repo = Repository::init(dir.path()).unwrap();
+        let work_tree = repo.work_tree().unwrap().to_owned();
+        fs::create_dir(work_tree.join("dir")).unwrap();
+        for name in ["a", "b", "dir/c"] {
+            fs::write(work_tree.join(name), name).unwrap();
+        }
+
+        assert!(add(&repo, &[], false, None, false).is_err());
+        add(&repo, &[], false, Some(true), false).unwrap();
+        assert_eq!(index_paths(&repo), ["a", "b", "dir/c"]);
+
+        fs::remove_file(work_tree.join("a")).unwrap();
+        fs::remove_file(work_tree.join("dir/c")).unwrap();
+        fs::write(work_tree.join("d"), "d").unwrap();
+        add(&repo, &[work_tree.join(".")], false, Some(false), false).unwrap();
+        assert_eq!(index_paths(&repo), ["a", "b", "d", "dir/c"]);
+
+        // removals are staged below the given paths only
+        add(&repo, &[work_tree.join("dir")], false, None, false).unwrap();
+        assert_eq!(index_paths(&repo), ["a", "b", "d"]);
+        add(&repo, &[], false, Some(true), false).unwrap();
+        assert_eq!(index_paths(&repo), ["b", "d"]);
+    }
+
+    #[test]
+    fn add_update_skips_untracked() {
+        let dir = TempDir::new();
+        let repo = Repository::init(dir.path()).unwrap();
+        let work_tree = repo.work_tree().unwrap().to_owned();
+        fs::write(work_tree.join("a"), "a").unwrap();
+        fs::write(work_tree.join("b"), "b").unwrap();
+        add(
+            &repo,
+            &[work_tree.join("a"), work_tree.join("b")],
+            false,
+            None,
+            false,
+        )
+        .unwrap();
+
+        fs::remove_file(work_tree.join("a")).unwrap();
+        fs::write(work_tree.join("b"), "changed").unwrap();
+        fs::write(work_tree.join("c"), "c").unwrap();
+        add(&repo, &[], true, None, false).unwrap();
+        assert_eq!(index_paths(&repo), ["b"]);
+        let index = Index::load(&repo.index_file()).unwrap();
+        assert_eq!(
+            index.find(b"b").unwrap().hash,
+            compute_hash(ObjectKind::Blob, b"changed")
+        );
+    }
+
+    /// Commits the index on top of HEAD.
+    fn commit_index(repo: &Repository) {
+        let index = Index::load(&repo.index_file()).unwrap();
+        let tree = write_index_tree(&repo.odb, &index.entries, &[]).unwrap();
+        let (_, head) = repo.refs.resolve("HEAD").unwrap();
+        let signature = Signature::parse("A U Thor <author@example.com> 0 +0000").unwrap();
+        let commit = Object::Commit(CommitObject::new(
+            tree,
+            head.into_iter().collect(),
+            signature.clone(),
+            signature,
+            "commit\n".to_owned(),
+        ));
+        let hash = repo.odb.write(commit.kind(), &commit.serialize()).unwrap();
+        repo.refs.update("HEAD", &hash, None, true).unwrap();
+    }
+
+    /// A repository with the files `a`, `b`, `c` and `d` committed.
+    fn committed_repo(dir: &TempDir) -> Repository {
+        let repo = Repository::init(dir.path()).unwrap();
+        let work_tree = repo.work_tree().unwrap().to_owned();
+        for name in ["a", "b", "c", "d"] {
+            fs::write(work_tree.join(name), name).unwrap();
+        }
+        add(&repo, &[], false, Some(true), false).unwrap();
+        commit_index(&repo);
+        repo
+    }
+
+    #[test]
+    fn status_classification() {
+        let dir = TempDir::new();
+        let repo = committed_repo(&dir);
+        let work_tree = repo.work_tree().unwrap().to_owned();
+        let stage = |name: &str| add(&repo, &[work_tree.join(name)], false, None, false).unwrap();
+
+        fs::write(work_tree.join("a"), "changed").unwrap();
+        fs::write(work_tree.join("b"), "staged").unwrap();
+        stage("b");
+        fs::remove_file(work_tree.join("c")).unwrap();
+        let mut index = Index::load(&repo.index_file()).unwrap();
+        index.remove(b"d");
+        index.write(&repo.index_file()).unwrap();
+        fs::write(work_tree.join("e"), "new").unwrap();
+        stage("e");
+        fs::write(work_tree.join("f"), "untracked").unwrap();
+        fs::write(work_tree.join("g"), "added").unwrap();
+        stage("g");
+        fs::write(work_tree.join("g"), "modified").unwrap();
+        fs::remove_file(work_tree.join("b")).unwrap();
+        symlink("a", work_tree.join("b")).unwrap();
+
+        let (_, head) = repo.refs.resolve("HEAD").unwrap();
+        let status = collect_status(&repo, head.as_ref()).unwrap();
+        let codes = status
+            .files
+            .iter()
+            .map(|f| {
+                let path = String::from_utf8_lossy(&f.path).into_owned();
+                (
+                    path,
+                    String::from_utf8_lossy(&[f.staged(), f.unstaged()]).into_owned(),
+                )
+            })
+            .collect_vec();
+        let expected = [
+            ("a", " M"),
+            ("b", "MT"),
+            ("c", " D"),
+            ("d", "D "),
+            ("e", "A "),
+            ("g", "AM"),
+        ];
+        assert_eq!(codes, expected.map(|(p, c)| (p.to_owned(), c.to_owned())));
+        assert_eq!(status.untracked, [b"d".to_vec(), b"f".to_vec()]);
+        assert!(status.conflicts.is_empty());
+    }
+
+    #[test]
+    fn status_conflicts() {
+        let dir = TempDir::new();
+        let repo = committed_repo(&dir);
+        let mut index = Index::load(&repo.index_file()).unwrap();
+        let stages = |path: &str, stages: &[u16]| {
+            stages
+                .iter()
+                .map(|&stage| IndexEntry {
+                    mode: 0o100644,
+                    flags: stage << 12,
+                    path: path.as_bytes().to_vec(),
+                    ..Default::default()
+                })
+                .collect_vec()
+        };
+        index.entries.retain(|e| e.path != b"a" && e.path != b"b");
+        index.entries.extend(stages("a", &[1, 2, 3]));
+        index.entries.extend(stages("b", &[2]));
+        index.entries.extend(stages("x", &[1, 3]));
+        let index = Index::new(index.entries);
+        index.write(&repo.index_file()).unwrap();
+
+        let (_, head) = repo.refs.resolve("HEAD").unwrap();
+        let status = collect_status(&repo, head.as_ref()).unwrap();
+        let codes = status
+            .conflicts
+            .iter()
+            .map(|c| (String::from_utf8_lossy(&c.path).into_owned(), c.code()))
+            .collect_vec();
+        let expected = [("a", "UU"), ("b", "AU"), ("x", "DU")];
+        assert_eq!(codes, expected.map(|(p, c)| (p.to_owned(), c)));
+        assert_eq!(status.conflicts[0].worktree_mode, 0o100644);
+        assert_eq!(status.conflicts[2].worktree_mode, 0);
+    }
+
+    #[test]
+    fn rm_keeps_unsaved_content() {
+        let dir = TempDir::new();
+        let repo = committed_repo(&dir);
+        let work_tree = repo.work_tree().unwrap().to_owned();
+        let rm = |name: &str, cached: bool, force: bool| {
+            rm(&repo, &[work_tree.join(name)], cached, false, force)
+        };
+
+        // modified in the worktree only
+        fs::write(work_tree.join("a"), "changed").unwrap();
+        assert!(rm("a", false, false).is_err());
+        rm("a", true, false).unwrap();
+        assert!(work_tree.join("a").exists());
+
+        // modified in the index only
+        fs::write(work_tree.join("b"), "changed").unwrap();
+        add(&repo, &[work_tree.join("b")], false, None, false).unwrap();
+        assert!(rm("b", false, false).is_err());
+        rm("b", true, false).unwrap();
+
+        // different in HEAD, index and worktree
+        fs::write(work_tree.join("c"), "staged").unwrap();
+        add(&repo, &[work_tree.join("c")], false, None, false).unwrap();
+        fs::write(work_tree.join("c"), "changed").unwrap();
+        assert!(rm("c", true, false).is_err());
+        assert!(rm("c", false, false).is_err());
+        rm("c", false, true).unwrap();
+        assert!(!work_tree.join("c").exists());
+
+        rm("d", false, false).unwrap();
+        assert!(!work_tree.join("d").exists());
+        assert!(index_paths(&repo).is_empty());
+    }
+
+    fn batch(repo: &Repository, batch: CatFileBatch, format: Option<&str>, input: &str) -> String {
+        let mut out = Vec::new();
+        write_batch(repo, batch, format, false, input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn batch_output() {
+        let dir = TempDir::new();
+        let repo = Repository::init(dir.path()).unwrap();
+        let blob = repo.odb.write(ObjectKind::Blob, b"hello\n").unwrap();
+        let blob = hex::encode(blob);
+
+        assert_eq!(
+            batch(&repo, CatFileBatch::Check, None, &format!("{blob}\nnope\n")),
+            format!("{blob} blob 6\nnope missing\n")
+        );
+        assert_eq!(
+            batch(&repo, CatFileBatch::Contents, None, &format!("{blob}\n")),
+            format!("{blob} blob 6\nhello\n\n")
+        );
+
+        let format = Some("%(objecttype) %(objectsize) [%(rest)]");
+        assert_eq!(
+            batch(
+                &repo,
+                CatFileBatch::Check,
+                format,
+                &format!("{blob}  some text \n{blob}\n")
+            ),
+            "blob 6 [some text ]\nblob 6 []\n"
+        );
+        let mut out = Vec::new();
+        let result = write_batch(
+            &repo,
+            CatFileBatch::Check,
+            Some("%(x)"),
+            false,
+            &b""[..],
+            &mut out,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn batch_commands() {
+        let dir = TempDir::new();
+        let repo = Repository::init(dir.path()).unwrap();
+        let blob = hex::encode(repo.odb.write(ObjectKind::Blob, b"x").unwrap());
+
+        let input = format!("info {blob}\ncontents {blob}\ninfo nope\n");
+        assert_eq!(
+            batch(&repo, CatFileBatch::Command, None, &input),
+            format!("{blob} blob 1\n{blob} blob 1\nx\nnope missing\n")
+        );
+
+        let mut out = Vec::new();
+        let input = format!("info {blob}\nflush\n");
+        write_batch(
+            &repo,
+            CatFileBatch::Command,
+            None,
+            true,
+            input.as_bytes(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(out, format!("{blob} blob 1\n").as_bytes());
+
+        for input in ["flush\n", "info\n", "\n", "show x\n"] {
+            let result = write_batch(
+                &repo,
+                CatFileBatch::Command,
+                None,
+                false,
+                input.as_bytes(),
+                &mut Vec::new(),
+            );
+            assert!(result.is_err(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn batch_detects_truncated_objects() {
+        use flate2::write::ZlibEncoder;
+        use flate2::Compression;
+
+        let dir = TempDir::new();
+        let repo = Repository::init(dir.path()).unwrap();
+        // a loose object whose header claims more content than it has
+        let name = "0123456789012345678901234567890123456789";
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"blob 10\0hello").unwrap();
+        let object_dir = repo.git_dir.join("objects").join(&name[..2]);
+        fs::create_dir(&object_dir).unwrap();
+        fs::write(object_dir.join(&name[2..]), encoder.finish().unwrap()).unwrap();
+
+        let input = format!("{name}\n");
+        let mut out = Vec::new();
+        write_batch(
+            &repo,
+            CatFileBatch::Check,
+            None,
+            false,
+            input.as_bytes(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(out, format!("{name} blob 10\n").as_bytes());
+        let result = write_batch(
+            &repo,
+            CatFileBatch::Contents,
+            None,
+            false,
+            input.as_bytes(),
+            &mut Vec::new(),
+        );
+        assert!(result.is_err());
+    }
+
+    /// `a`, `dir/b`, `dir/sub/c` and the executable `x`, returns the root tree.
+    fn sample_tree(repo: &Repository) -> ShaHash {
+        let db = &repo.odb;
+        let blob = |content: &str| db.write(ObjectKind::Blob, content.as_bytes()).unwrap();
+        let sub = raw_tree(&[("100644", "c", blob("c\n"))]);
+        let sub = db.write(ObjectKind::Tree, &sub).unwrap();
+        let dir = raw_tree(&[("100644", "b", blob("bb\n")), ("40000", "sub", sub)]);
+        let dir = db.write(ObjectKind::Tree, &dir).unwrap();
+        let root = raw_tree(&[
+            ("100644", "a", blob("a\n")),
+            ("40000", "dir", dir),
+            ("100755", "x", blob("#!/bin/sh\n")),
+        ]);
+        db.write(ObjectKind::Tree, &root).unwrap()
+    }
+
+    fn ls_tree_output(repo: &Repository, paths: &[&str], options: &LsTreeOptions) -> String {
+        let tree = sample_tree(repo);
+        let paths = paths.iter().map(|p| p.to_string()).collect_vec();
+        let mut out = Vec::new();
+        list_tree(repo, &tree, &[], &paths, options, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn ls_tree_options() -> LsTreeOptions {
+        LsTreeOptions {
+            recursive: false,
+            show_trees: false,
+            trees_only: false,
+            long: false,
+            null_terminated: false,
+            name_only: true,
+            abbrev: None,
+        }
+    }
+
+    #[test]
+    fn ls_tree_formatting() {
+        let dir = TempDir::new();
+        let repo = Repository::init(dir.path()).unwrap();
+        let hash = |content: &str| hex::encode(compute_hash(ObjectKind::Blob, content.as_bytes()));
+        let options = LsTreeOptions {
+            name_only: false,
+            ..ls_tree_options()
+        };
+        let output = ls_tree_output(&repo, &["a", "x"], &options);
+        assert_eq!(
+            output,
+            format!(
+                "100644 blob {}\ta\n100755 blob {}\tx\n",
+                hash("a\n"),
+                hash("#!/bin/sh\n")
+            )
+        );
+
+        let options = LsTreeOptions {
+            name_only: false,
+            long: true,
+            abbrev: Some(10),
+            ..ls_tree_options()
+        };
+        let output = ls_tree_output(&repo, &["a", "dir"], &options);
+        let lines = output.lines().collect_vec();
+        assert_eq!(
+            lines[0],
+            format!("100644 blob {}       2\ta", &hash("a\n")[..10])
+        );
+        assert!(lines[1].starts_with("040000 tree "));
+        assert!(lines[1].ends_with("       -\tdir"));
+
+        let options = LsTreeOptions {
+            null_terminated: true,
+            ..ls_tree_options()
+        };
+        assert_eq!(ls_tree_output(&repo, &[], &options), "a\0dir\0x\0");
+    }
+
+    #[test]
+    fn ls_tree_filtering() {
+        let dir = TempDir::new();
+        let repo = Repository::init(dir.path()).unwrap();
+        let names = |paths: &[&str], options: LsTreeOptions| {
+            ls_tree_output(&repo, paths, &options).lines().join(" ")
+        };
+
+        assert_eq!(names(&[], ls_tree_options()), "a dir x");
+        let recursive = || LsTreeOptions {
+            recursive: true,
+            ..ls_tree_options()
+        };
+        assert_eq!(names(&[], recursive()), "a dir/b dir/sub/c x");
+        let show_trees = LsTreeOptions {
+            show_trees: true,
+            ..recursive()
+        };
+        assert_eq!(names(&[], show_trees), "a dir dir/b dir/sub dir/sub/c x");
+        let trees_only = LsTreeOptions {
+            trees_only: true,
+            ..ls_tree_options()
+        };
+        assert_eq!(names(&[], trees_only), "dir");
+        let trees_only = LsTreeOptions {
+            trees_only: true,
+            ..recursive()
+        };
+        assert_eq!(names(&[], trees_only), "dir dir/sub");
+
+        // pathspecs select entries and the trees leading to them
+        assert_eq!(names(&["dir"], ls_tree_options()), "dir");
+        assert_eq!(names(&["dir/"], ls_tree_options()), "dir/b dir/sub");
+        assert_eq!(names(&["dir/sub/c"], ls_tree_options()), "dir/sub/c");
+        assert_eq!(names(&["dir", "x"], recursive()), "dir/b dir/sub/c x");
+        assert_eq!(names(&["di", "nope"], ls_tree_options()), "");
+    }
+
+    fn advertisement(lines: &[&str]) -> Vec<u8> {
+        let mut writer = PktWriter::new(Vec::new());
+        writer.write_line("# service=git-upload-pack").unwrap();
+        writer.write_packet(&Packet::Flush).unwrap();
+        for line in lines {
+            writer.write_data(line.as_bytes()).unwrap();
+        }
+        writer.write_packet(&Packet::Flush).unwrap();
+        writer.into_inner()
+    }
+
+    #[test]
+    fn ref_advertisement() {
+        let (a, b) = ("a".repeat(40), "b".repeat(40));
+        let first = format!(
+            "{a} HEAD\0multi_ack side-band-64k symref=HEAD:refs/heads/main agent=git/2.43.0\n"
+        );
+        let data = advertisement(&[
+            &first,
+            &format!("{a} refs/heads/main\n"),
+            &format!("{b} refs/tags/v1\n"),
+            &format!("{a} refs/tags/v1^{{}}\n"),
+            // the last line may come without a newline
+            &format!("{b} refs/heads/side"),
+        ]);
+        let advertisement = parse_advertisement("url", &data[..]).unwrap();
+
+        let names = advertisement
+            .refs
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .collect_vec();
+        assert_eq!(
+            names,
+            ["HEAD", "refs/heads/main", "refs/tags/v1", "refs/heads/side"]
+        );
+        assert_eq!(advertisement.find("refs/tags/v1"), Some([0xbb; 20]));
+        assert_eq!(advertisement.find("refs/heads/nope"), None);
+        assert_eq!(advertisement.capability("side-band-64k"), Some(""));
+        assert_eq!(advertisement.capability("agent"), Some("git/2.43.0"));
+        assert_eq!(advertisement.capability("side-band"), None);
+        assert_eq!(advertisement.symref("HEAD"), Some("refs/heads/main"));
+        assert_eq!(advertisement.symref("refs/heads/main"), None);
+    }
+
+    #[test]
+    fn empty_repository_advertisement() {
+        let line = format!("{} capabilities^{{}}\0ofs-delta agent=x\n", "0".repeat(40));
+        let advertisement = parse_advertisement("url", &advertisement(&[&line])[..]).unwrap();
+        assert!(advertisement.refs.is_empty());
+        assert_eq!(advertisement.capability("ofs-delta"), Some(""));
+    }
+
+    #[test]
+    fn invalid_advertisements() {
+        let a = "a".repeat(40);
+        let sha256 = format!("{a} HEAD\0object-format=sha256\n");
+        for lines in [
+            vec![format!("{a}\n")],
+            vec![format!("{} HEAD\n", &a[..39])],
+            vec![sha256],
+        ] {
+            let lines = lines.iter().map(String::as_str).collect_vec();
+            assert!(parse_advertisement("url", &advertisement(&lines)[..]).is_err());
+        }
+
+        // a dumb HTTP server sends the refs without the service line
+        let dumb = format!("{a}\trefs/heads/main\n");
+        assert!(parse_advertisement("url", dumb.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+/// Matches `text` against a git glob `pattern`: `*` and `?` do not match `/`,
+/// `**` between slashes matches any number of directories, `[...]` is a character
+/// class (`!` or `^` negates it) and `\` escapes the next character.
+pub fn wildmatch(pattern: &[u8], text: &[u8], ignore_case: bool) -> bool {
+    let mut pi = 0;
+    let mut ti = 0;
+
+    while pi < pattern.len() {
+        match pattern[pi] {
+            b'*' => {
+                let mut end = pi;
+                while pattern.get(end) == Some(&b'*') {
+                    end += 1;
+                }
+                let rest = &pattern[end..];
+
+                let at_start = pi == 0 || pattern[pi - 1] == b'/';
+                let at_end = end == pattern.len() || pattern[end] == b'/';
+                if end - pi >= 2 && at_start && at_end {
+                    if rest.is_empty() {
+                        return true;
+                    }
+                    // "**/" matches zero or more leading directories
+                    let rest = &rest[1..];
+                    if wildmatch(rest, &text[ti..], ignore_case) {
+                        return true;
+                    }
+                    return (ti..text.len())
+                        .any(|k| text[k] == b'/' && wildmatch(rest, &text[k + 1..], ignore_case));
+                }
+
+                for k in ti..=text.len() {
+                    if wildmatch(rest, &text[k..], ignore_case) {
+                        return true;
+                    }
+                    if k < text.len() && text[k] == b'/' {
+                        break;
+                    }
+                }
+                return false;
+            }
+            b'?' => {
+                if ti >= text.len() || text[ti] == b'/' {
+                    return false;
+                }
+                pi += 1;
+                ti += 1;
+            }
+            b'[' => {
+                let Some(&c) = text.get(ti) else {
+                    return false;
+                };
+                match match_class(&pattern[pi + 1..], c, ignore_case) {
+                    Some((true, consumed)) if c != b'/' => {
+                        pi += 1 + consumed;
+                        ti += 1;
+                    }
+                    Some(_) => return false,
+                    // an unterminated class is a literal '['
+                    None => {
+                        if c != b'[' {
+                            return false;
+                        }
+                        pi += 1;
+                        ti += 1;
+                    }
+                }
+            }
+            c => {
+                let (c, advance) = if c == b'\\' && pi + 1 < pattern.len() {
+                    (pattern[pi + 1], 2)
+                } else {
+                    (c, 1)
+                };
+                match text.get(ti) {
+                    Some(t) if eq(c, *t, ignore_case) => {
+                        pi += advance;
+                        ti += 1;
+                    }
+                    _ => return false,
+                }
+            }
+        }
+    }
+
+    ti == text.len()
+}
+
+fn eq(a: u8, b: u8, ignore_case: bool) -> bool {
+    if ignore_case {
+        a.eq_ignore_ascii_case(&b)
+    } else {
+        a == b
+    }
+}
+
+/// Matches a character class (the part after `[`), returns whether `c` matches
+/// and how many pattern bytes the class takes, `None` if it is not terminated.
+fn match_class(class: &[u8], c: u8, ignore_case: bool) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(class.first(), Some(b'!' | b'^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let current = *class.get(i)?;
+        if current == b']' && !first {
+            break;
+        }
+        first = false;
+
+        if current == b'[' && class.get(i + 1) == Some(&b':') {
+            let name_end = class[i + 2..].windows(2).position(|w| w == b":]")? + i + 2;
+            let matches = match &class[i + 2..name_end] {
+                b"alnum" => c.is_ascii_alphanumeric(),
+                b"alpha" => c.is_ascii_alphabetic(),
+                b"digit" => c.is_ascii_digit(),
+                b"lower" => c.is_ascii_lowercase(),
+                b"upper" => c.is_ascii_uppercase(),
+                b"space" => c.is_ascii_whitespace(),
+                b"punct" => c.is_ascii_punctuation(),
+                b"xdigit" => c.is_ascii_hexdigit(),
+                _ => false,
+            };
+            matched |= matches;
+            i = name_end + 2;
+            continue;
+        }
+
+        let (low, next) = if current == b'\\' {
+            (*class.get(i + 1)?, i + 2)
+        } else {
+            (current, i + 1)
+        };
+        if class.get(next) == Some(&b'-') && class.get(next + 1).is_some_and(|&h| h != b']') {
+            let high = class[next + 1];
+            let in_range = |c: u8| low <= c && c <= high;
+            matched |= in_range(c)
+                || (ignore_case
+                    && (in_range(c.to_ascii_lowercase()) || in_range(c.to_ascii_uppercase())));
+            i = next + 2;
+        } else {
+            matched |= eq(low, c, ignore_case);
+            i = next;
+        }
+    }
+
+    Some((matched != negated, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        wildmatch(pattern.as_bytes(), text.as_bytes(), false)
+    }
+
+    #[test]
+    fn literals_and_wildcards() {
+        assert!(matches("foo", "foo"));
+        assert!(!matches("foo", "bar"));
+        assert!(matches("f?o", "foo"));
+        assert!(!matches("f?o", "f/o"));
+        assert!(matches("*.txt", "a.txt"));
+        assert!(matches("*", ""));
+        assert!(!matches("*.txt", "dir/a.txt"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b", "a/b"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+    }
+
+    #[test]
+    fn double_stars() {
+        assert!(matches("**/foo", "foo"));
+        assert!(matches("**/foo", "a/b/foo"));
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+        assert!(!matches("a/**/b", "ab"));
+        assert!(matches("a/**", "a/x/y"));
+        assert!(matches("**", "a/b"));
+        // not between slashes, `**` is a regular `*`
+        assert!(!matches("a**b", "a/b"));
+        assert!(matches("a**b", "axxb"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("[abc]", "b"));
+        assert!(!matches("[abc]", "d"));
+        assert!(matches("[!abc]", "d"));
+        assert!(matches("[^abc]", "d"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[[:digit:]]", "7"));
+        assert!(!matches("[[:upper:]]", "a"));
+        assert!(matches("[\\]]", "]"));
+        assert!(!matches("[/]", "/"));
+        // an unterminated class is a literal
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn ignore_case() {
+        assert!(wildmatch(b"*.TXT", b"a.txt", true));
+        assert!(wildmatch(b"[A-C]", b"b", true));
+        assert!(!wildmatch(b"*.TXT", b"a.txt", false));
+    }
+}